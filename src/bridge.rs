@@ -0,0 +1,500 @@
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::commands::Commands;
+use crate::config::entities::{EntityConfig, TelemetryEntity};
+use crate::config::watcher::{diff_discovery, ConfigWatcher};
+use crate::devices::{self, Devices};
+use crate::diagnostics::{self, DiagnosticsReporter, DIAGNOSTICS_STATE};
+use crate::groups::{self, group_states, group_topic};
+use crate::inputs::{InputsChannel, INPUTS_STATE};
+use crate::irmqtt::encoding::Encoding;
+use crate::irmqtt::publisher::Publisher;
+use crate::pit_plan::PIT_PLAN_STATE;
+use crate::recording::{RecordEntry, VarInfo};
+use crate::rules::{Action, RuleEngine};
+use crate::scheduler::{interval_from_env, Scheduler, Throttle};
+use crate::scripting::{ScriptHost, SCRIPT_STATE};
+use crate::sinks::sink::Sink;
+use crate::sun::{Sun, SUN_STATE};
+use crate::weather::{self, Weather, WEATHER_STATE};
+use crate::{
+    config_discovery_packet, discovery_packet, handle_session_entities, session_discovery_packet,
+    telemetry_topic, var_state, CONNECTED_STATE, SESSION_ENTITIES_STATE, SESSION_STATE,
+    TELEMETRY_STATE, VERSION,
+};
+
+/// Full telemetry map on `TELEMETRY_STATE`.  Defaults to 2 Hz, the original rate of the bridge.
+const TELEMETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Everything the telemetry thread keeps between updates.  Each update from iRacing, or each entry of a recording, is
+/// turned into discovery and state messages for the publisher.
+pub(crate) struct Bridge {
+    entity_config: EntityConfig,
+    config_watcher: Option<ConfigWatcher>,
+    commands: Commands,
+    // Outputs alongside mqtt
+    sinks: Vec<Box<dyn Sink + Send>>,
+    telemetry_throttle: Throttle,
+    // Encodings of the full telemetry map and the session, which HA does not read
+    telemetry_encoding: Encoding,
+    session_encoding: Encoding,
+    // Compact high rate channel for rig lighting and fans
+    inputs: Option<InputsChannel>,
+    script: Option<ScriptHost>,
+
+    // Bridge, car and track devices.  Car and track are renamed once the session is known.
+    devices: Devices,
+    reporter: DiagnosticsReporter,
+    var_headers: HashMap<String, VarInfo>,
+    scheduler: Scheduler,
+    // Groups are scheduled by object id, separate from the vars
+    group_scheduler: Scheduler,
+    rule_engine: RuleEngine,
+    weather: Weather,
+    sun: Sun,
+
+    // Last session and resolved session entities, re-sent so session entities do not expire while iRacing has
+    // nothing new to send.
+    last_session: Option<Value>,
+    session_entities: Option<Map<String, Value>>,
+    session_heartbeat: Throttle,
+
+    // Generic telemetry entities plus those of the player's car profile.  Picked when the session arrives.
+    telemetry_entities: Vec<TelemetryEntity>,
+
+    // `SessionNum` from the telemetry.  Picks the current session for session paths.
+    session_num: Option<i64>,
+
+    // Session discovery packet is only sent once per session.
+    session_discory_sent: bool,
+}
+
+impl Bridge {
+    /// A bridge with nothing enabled through the env, as replayed in tests.
+    pub fn new(entity_config: EntityConfig, commands: Commands) -> Self {
+        Self {
+            rule_engine: RuleEngine::new(&entity_config.rules),
+            session_heartbeat: Throttle::new(entity_config.session_interval().max),
+            entity_config,
+            config_watcher: None,
+            commands,
+            sinks: Vec::new(),
+            telemetry_throttle: Throttle::new(TELEMETRY_INTERVAL),
+            telemetry_encoding: Encoding::Json,
+            session_encoding: Encoding::Json,
+            inputs: None,
+            script: None,
+            devices: Devices::new(VERSION.unwrap_or("unavailable")),
+            reporter: DiagnosticsReporter::new(),
+            var_headers: HashMap::new(),
+            scheduler: Scheduler::default(),
+            group_scheduler: Scheduler::default(),
+            weather: Weather::new(),
+            sun: Sun::new(),
+            last_session: None,
+            session_entities: None,
+            telemetry_entities: Vec::new(),
+            session_num: None,
+            session_discory_sent: false,
+        }
+    }
+
+    pub fn from_env(
+        entity_config: EntityConfig,
+        commands: Commands,
+        sinks: Vec<Box<dyn Sink + Send>>,
+    ) -> Self {
+        Self {
+            config_watcher: ConfigWatcher::from_env(),
+            sinks,
+            telemetry_throttle: Throttle::new(interval_from_env(
+                "TELEMETRY_INTERVAL",
+                TELEMETRY_INTERVAL,
+            )),
+            telemetry_encoding: Encoding::from_env("TELEMETRY_ENCODING"),
+            session_encoding: Encoding::from_env("SESSION_ENCODING"),
+            inputs: InputsChannel::from_env(),
+            script: ScriptHost::from_env(entity_config.default_interval()),
+            ..Self::new(entity_config, commands)
+        }
+    }
+
+    /// Reports `version` for the bridge device instead of the crate version, so replays don't change with a release.
+    #[cfg(test)]
+    pub fn with_version(mut self, version: &str) -> Self {
+        self.devices = Devices::new(version);
+        self
+    }
+
+    /// The bridge device does not depend on iRacing, so its diagnostics can be discovered straight away.
+    pub fn start(&mut self, publisher: &mut impl Publisher) {
+        for entity in diagnostics::discovery_packet(&self.devices.bridge).into_iter() {
+            publisher.publish_discovery(entity);
+        }
+    }
+
+    /// A publish on one of the command topics.
+    pub fn command(&mut self, topic: &str, payload: &[u8]) {
+        if let Err(e) = self.commands.handle(topic, payload) {
+            log::error!("{}", e);
+        }
+    }
+
+    pub fn handle(&mut self, publisher: &mut impl Publisher, entry: RecordEntry, now: Instant) {
+        self.check_config(publisher, now);
+
+        if let Some(report) = self.reporter.report(publisher.diagnostics(), now) {
+            publisher.publish_value(DIAGNOSTICS_STATE, &report);
+        }
+
+        // Telemetry sends fresh values through the scheduler instead.
+        if !matches!(entry, RecordEntry::Telemetry(_)) {
+            for (var, value) in self.scheduler.heartbeat(now) {
+                publisher.publish_value(&telemetry_topic(&var), &var_state(var.clone(), value));
+            }
+            for (object_id, state) in self.group_scheduler.heartbeat(now) {
+                publisher.publish_value(&group_topic(&object_id), &state);
+            }
+            if let Some(outputs) = self
+                .script
+                .as_mut()
+                .and_then(|script| script.heartbeat(now))
+            {
+                publisher.publish_value(SCRIPT_STATE, &outputs);
+            }
+        }
+
+        match entry {
+            RecordEntry::Telemetry(payload) => self.telemetry(publisher, payload, now),
+            RecordEntry::Session(payload) => self.session(publisher, payload, now),
+            RecordEntry::Disconnected => self.disconnected(publisher),
+            RecordEntry::Variables(variables) => self.variables(publisher, variables),
+        }
+
+        // After the entry, so a session that just arrived resets the heartbeat instead of going out twice
+        if let (Some(session), Some(entities)) = (&self.last_session, &self.session_entities) {
+            if self.session_heartbeat.ready(now) {
+                publisher.publish_encoded(SESSION_STATE, session, self.session_encoding);
+                publisher.publish_value(SESSION_ENTITIES_STATE, entities);
+            }
+        }
+    }
+
+    /// The entities file changed.  Only the difference is discovered, against the current devices, var headers and
+    /// session.
+    fn check_config(&mut self, publisher: &mut impl Publisher, now: Instant) {
+        let Some(config) = self
+            .config_watcher
+            .as_mut()
+            .and_then(|watcher| watcher.check(now))
+        else {
+            return;
+        };
+
+        if self.session_discory_sent {
+            let profile = self
+                .last_session
+                .as_ref()
+                .and_then(Value::as_object)
+                .and_then(|session| {
+                    config.profile(devices::car_path(session), devices::car_class(session))
+                });
+            let entities = config.telemetry_entities(profile);
+
+            let (changed, removed) = diff_discovery(
+                config_discovery_packet(
+                    &self.var_headers,
+                    &self.devices,
+                    &self.telemetry_entities,
+                    &self.entity_config,
+                ),
+                config_discovery_packet(&self.var_headers, &self.devices, &entities, &config),
+            );
+            log::info!(
+                "Entities changed: {} discovered, {} removed",
+                changed.len(),
+                removed.len()
+            );
+            for topic in removed.iter() {
+                publisher.remove_discovery(topic);
+            }
+            for entity in changed.into_iter() {
+                publisher.publish_discovery(entity);
+            }
+            self.telemetry_entities = entities;
+
+            if !self.var_headers.is_empty() {
+                schedule_entities(
+                    &mut self.scheduler,
+                    &self.var_headers,
+                    &self.telemetry_entities,
+                    &config,
+                );
+                groups::schedule_groups(&mut self.group_scheduler, &self.var_headers, &config);
+            }
+            if let Some(session) = &self.last_session {
+                let entities = handle_session_entities(session, self.session_num, &config);
+                publisher.publish_value(SESSION_ENTITIES_STATE, &entities);
+                self.session_entities = Some(entities);
+            }
+        }
+
+        // Unchanged rules keep their state, rather than turning off and on again
+        if config.rules != self.entity_config.rules {
+            publish_actions(publisher, &self.rule_engine.reset());
+            self.rule_engine = RuleEngine::new(&config.rules);
+        }
+        self.session_heartbeat = Throttle::new(config.session_interval().max);
+        self.entity_config = config;
+    }
+
+    fn telemetry(
+        &mut self,
+        publisher: &mut impl Publisher,
+        payload: Map<String, Value>,
+        now: Instant,
+    ) {
+        publisher.diagnostics().record_telemetry_tick();
+
+        if let Some(sample) = self
+            .inputs
+            .as_mut()
+            .and_then(|inputs| inputs.update(&payload, now))
+        {
+            publisher.direct_publish(INPUTS_STATE, &sample);
+        }
+
+        if self.telemetry_throttle.ready(now) {
+            publisher.direct_publish(CONNECTED_STATE, "connected".as_bytes());
+            publisher.publish_encoded(TELEMETRY_STATE, &payload, self.telemetry_encoding);
+        }
+
+        for (var, value) in self.scheduler.tick(&payload, now) {
+            publisher.publish_value(&telemetry_topic(&var), &var_state(var.clone(), value));
+        }
+
+        let states = group_states(&self.entity_config.groups, &payload);
+        for (object_id, state) in self.group_scheduler.tick(&states, now) {
+            publisher.publish_value(&group_topic(&object_id), &state);
+        }
+
+        let actions =
+            self.rule_engine
+                .evaluate(&payload, self.last_session.as_ref(), self.session_num, now);
+        publish_actions(publisher, &actions);
+
+        if let Some(state) = self.commands.telemetry(&payload, now) {
+            publisher.publish_value(PIT_PLAN_STATE, &state);
+        }
+
+        if let Some(state) = self.weather.update(&payload, now) {
+            publisher.publish_value(WEATHER_STATE, &state);
+        }
+        if let Some(state) = self.sun.update(&payload, now) {
+            publisher.publish_value(SUN_STATE, &state);
+        }
+
+        if let Some(script) = self.script.as_mut() {
+            // A reloaded script may declare different entities
+            if script.check_reload(now) && self.session_discory_sent {
+                for entity in script.discovery_packet(&self.devices).into_iter() {
+                    publisher.publish_discovery(entity);
+                }
+            }
+            if let Some(outputs) = script.tick(&payload, now) {
+                publisher.publish_value(SCRIPT_STATE, &outputs);
+            }
+        }
+
+        // A new current session changes what the session paths resolve to
+        let current = payload.get("SessionNum").and_then(Value::as_i64);
+        if current.is_some() && current != self.session_num {
+            self.session_num = current;
+            if let Some(session) = &self.last_session {
+                let entities =
+                    handle_session_entities(session, self.session_num, &self.entity_config);
+                publisher.publish_value(SESSION_ENTITIES_STATE, &entities);
+                self.session_entities = Some(entities);
+            }
+        }
+
+        for sink in self.sinks.iter_mut() {
+            sink.telemetry(&payload);
+        }
+    }
+
+    fn session(
+        &mut self,
+        publisher: &mut impl Publisher,
+        payload: Map<String, Value>,
+        now: Instant,
+    ) {
+        publisher.diagnostics().record_session_update();
+        let session_id = payload
+            .get("weekend_info")
+            .and_then(|info| info.get("sub_session_id"))
+            .map(Value::to_string);
+        publisher.session_id(session_id);
+        self.weather.session(&payload);
+        self.commands.session(&payload);
+        self.sun.session(&payload);
+        if let Some(script) = self.script.as_mut() {
+            script.session(&Value::Object(payload.clone()));
+        }
+
+        if !self.session_discory_sent {
+            self.devices.update_from_session(&payload);
+
+            let profile = self
+                .entity_config
+                .profile(devices::car_path(&payload), devices::car_class(&payload));
+            match profile {
+                Some(profile) => log::info!("Using the {} car profile", profile.name),
+                None => log::info!("No car profile matches, using the generic entities"),
+            }
+            self.telemetry_entities = self.entity_config.telemetry_entities(profile);
+
+            let entities = session_discovery_packet(&self.devices, &self.entity_config);
+            for entity in entities.into_iter() {
+                publisher.publish_discovery(entity);
+            }
+            for entity in self.commands.discovery_packet(&self.devices).into_iter() {
+                publisher.publish_discovery(entity);
+            }
+            if let Some(script) = &self.script {
+                for entity in script.discovery_packet(&self.devices).into_iter() {
+                    publisher.publish_discovery(entity);
+                }
+            }
+
+            // Telemetry entities are attached to the car and track devices, so they wait for the session.
+            if !self.var_headers.is_empty() {
+                self.discover_telemetry(publisher);
+            }
+
+            self.session_discory_sent = true;
+            log::trace!("Session Discovery sent");
+        }
+
+        publisher.publish_encoded(SESSION_STATE, &payload, self.session_encoding);
+        for sink in self.sinks.iter_mut() {
+            sink.session(&payload);
+        }
+
+        let session = Value::Object(payload);
+        let entities = handle_session_entities(&session, self.session_num, &self.entity_config);
+        publisher.publish_value(SESSION_ENTITIES_STATE, &entities);
+        self.session_heartbeat.mark(now);
+
+        self.last_session = Some(session);
+        self.session_entities = Some(entities);
+
+        log::trace!("Session Info updated");
+    }
+
+    /// Clears session specific data
+    fn disconnected(&mut self, publisher: &mut impl Publisher) {
+        self.var_headers.clear();
+        self.scheduler.clear();
+        self.group_scheduler.clear();
+        self.telemetry_entities.clear();
+        self.weather.clear();
+        publish_actions(publisher, &self.rule_engine.reset());
+        self.commands.clear();
+        self.sun.clear();
+        if let Some(script) = self.script.as_mut() {
+            script.clear();
+        }
+        self.last_session = None;
+        self.session_entities = None;
+        self.session_num = None;
+        self.session_discory_sent = false;
+        for sink in self.sinks.iter_mut() {
+            sink.disconnected();
+        }
+        publisher.session_id(None);
+
+        publisher.direct_publish(CONNECTED_STATE, "disconnected".as_bytes());
+        log::trace!("Ir-telemetry is not connected");
+    }
+
+    /// Only received when the race session loads.
+    fn variables(&mut self, publisher: &mut impl Publisher, variables: Vec<VarInfo>) {
+        self.var_headers = variables
+            .into_iter()
+            .map(|var| (var.name.clone(), var))
+            .collect();
+        self.weather.variables(&self.var_headers);
+        for sink in self.sinks.iter_mut() {
+            sink.variables(&self.var_headers);
+        }
+
+        // If the session arrived first, the devices are already known and discovery can go out now.
+        if self.session_discory_sent {
+            self.discover_telemetry(publisher);
+        }
+        log::trace!("Updated Variable Headers");
+    }
+
+    /// Discovers and schedules the telemetry entities and groups, and discovers the weather, once both the session and
+    /// var headers are known.
+    fn discover_telemetry(&mut self, publisher: &mut impl Publisher) {
+        let entities = discovery_packet(
+            &self.var_headers,
+            &self.devices,
+            &self.telemetry_entities,
+            &self.entity_config,
+        );
+        for entity in entities.into_iter() {
+            publisher.publish_discovery(entity);
+        }
+        schedule_entities(
+            &mut self.scheduler,
+            &self.var_headers,
+            &self.telemetry_entities,
+            &self.entity_config,
+        );
+
+        let entities =
+            groups::discovery_packet(&self.var_headers, &self.devices, &self.entity_config);
+        for entity in entities.into_iter() {
+            publisher.publish_discovery(entity);
+        }
+        groups::schedule_groups(
+            &mut self.group_scheduler,
+            &self.var_headers,
+            &self.entity_config,
+        );
+
+        for entity in weather::discovery_packet(&self.var_headers, &self.devices.track).into_iter()
+        {
+            publisher.publish_discovery(entity);
+        }
+    }
+}
+
+/// Schedules the vars of the discovered entities.  Vars shared by several entities use the strictest interval.
+fn schedule_entities<V>(
+    scheduler: &mut Scheduler,
+    var_headers: &HashMap<String, V>,
+    entities: &[TelemetryEntity],
+    config: &EntityConfig,
+) {
+    let default_interval = config.default_interval();
+
+    scheduler.clear();
+    for entity in entities.iter() {
+        if var_headers.contains_key(&entity.var) {
+            scheduler.register(&entity.var, entity.interval(default_interval));
+        }
+    }
+}
+
+/// Publishes the actions of rules that have turned on or off.
+fn publish_actions(publisher: &mut impl Publisher, actions: &[Action]) {
+    for action in actions.iter() {
+        publisher.publish(&action.topic, &action.payload(), action.retain);
+    }
+}
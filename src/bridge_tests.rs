@@ -0,0 +1,390 @@
+//! Replays `tests/fixtures/replay.jsonl` through the bridge: vars, session load, telemetry, a disconnect and a
+//! reconnect.  The messages are checked against `tests/golden/replay.txt`, regenerated with `UPDATE_GOLDEN=1 cargo test`,
+//! and the same replay is run against an embedded broker to check what a subscriber receives, down to the last will.
+use rumqttc::{Client, Event, MqttOptions, Packet, QoS, Transport};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+
+use crate::bridge::Bridge;
+use crate::broadcast::LoggingSender;
+use crate::commands::Commands;
+use crate::config::entities::EntityConfig;
+use crate::diagnostics::Diagnostics;
+use crate::irmqtt::client::{MqttConnection, MqttEvent, MAX_PACKET_SIZE};
+use crate::irmqtt::publisher::{MemoryPublisher, Published, Publisher, DISCOVERY_QOS};
+use crate::pit_plan::{PitPlan, PitPlanner};
+use crate::recording::{read_recording, RecordEntry};
+use crate::{CONNECTED_STATE, SESSION_STATE, TELEMETRY_STATE};
+
+const GOLDEN: &str = "tests/golden/replay.txt";
+
+/// Time between replayed entries.  Long enough for every throttle to let the next update through.
+const STEP: Duration = Duration::from_secs(1);
+
+fn entries() -> Vec<RecordEntry> {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/replay.jsonl");
+    read_recording(&path).expect("Replay fixture is valid")
+}
+
+/// Nothing is read from the env and the version is pinned, so the replay only depends on the fixture.
+fn bridge() -> Bridge {
+    Bridge::new(
+        EntityConfig::default_entities(),
+        Commands::new(
+            Box::new(LoggingSender),
+            PitPlanner::new(PitPlan::default(), None),
+        ),
+    )
+    .with_version("golden")
+}
+
+/// The messages each step published, starting with `start`.
+fn replay_steps(start: Instant) -> Vec<(Option<RecordEntry>, Vec<Published>)> {
+    let mut publisher = MemoryPublisher::default();
+    let mut bridge = bridge();
+
+    bridge.start(&mut publisher);
+    let mut steps = vec![(None, publisher.take())];
+
+    for (index, entry) in entries().into_iter().enumerate() {
+        bridge.handle(&mut publisher, entry.clone(), start + STEP * index as u32);
+        steps.push((Some(entry), publisher.take()));
+    }
+    steps
+}
+
+fn step_name(entry: &Option<RecordEntry>) -> &'static str {
+    match entry {
+        None => "start",
+        Some(RecordEntry::Variables(_)) => "variables",
+        Some(RecordEntry::Session(_)) => "session",
+        Some(RecordEntry::Telemetry(_)) => "telemetry",
+        Some(RecordEntry::Disconnected) => "disconnected",
+    }
+}
+
+fn is_discovery(message: &Published) -> bool {
+    message.qos == DISCOVERY_QOS && message.topic.ends_with("/config")
+}
+
+/// State topics of a discovery message, under their full or abbreviated names
+fn discovered_topics(message: &Published) -> Vec<String> {
+    let Ok(payload) = serde_json::from_slice::<Value>(&message.payload) else {
+        return Vec::new();
+    };
+    ["state_topic", "stat_t"]
+        .iter()
+        .filter_map(|name| payload.get(*name).and_then(Value::as_str))
+        .map(str::to_string)
+        .collect()
+}
+
+#[test]
+fn should_match_golden_replay() {
+    let mut actual = String::new();
+    for (entry, messages) in replay_steps(Instant::now()).iter() {
+        actual.push_str(&format!("> {}\n", step_name(entry)));
+        for message in messages.iter() {
+            actual.push_str(&message.line());
+            actual.push('\n');
+        }
+    }
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(GOLDEN);
+
+    if std::env::var("UPDATE_GOLDEN").is_ok() {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, actual).unwrap();
+        eprintln!("Wrote {}", path.display());
+        return;
+    }
+
+    let expected = std::fs::read_to_string(&path).unwrap_or_else(|e| {
+        panic!(
+            "Failed to read {}, generate it with UPDATE_GOLDEN=1 and commit it: {}",
+            GOLDEN, e
+        )
+    });
+    let difference = expected
+        .lines()
+        .zip(actual.lines())
+        .enumerate()
+        .find(|(_, (expected, actual))| expected != actual)
+        .map(|(line, (expected, actual))| {
+            format!("line {}:\n  {}\n  {}", line + 1, expected, actual)
+        })
+        .unwrap_or_else(|| {
+            format!(
+                "{} lines expected, {} published",
+                expected.lines().count(),
+                actual.lines().count()
+            )
+        });
+    assert!(
+        expected == actual,
+        "Replay differs from {}, run with UPDATE_GOLDEN=1 if this is intended:\n{}",
+        GOLDEN,
+        difference
+    );
+}
+
+#[test]
+fn should_discover_before_publishing_states() {
+    let steps = replay_steps(Instant::now());
+
+    // Only the bridge diagnostics before iRacing sends anything
+    let (_, started) = &steps[0];
+    assert!(!started.is_empty());
+    assert!(started.iter().all(is_discovery));
+
+    let mut discovered = HashSet::new();
+    let mut connections = Vec::new();
+    for (entry, messages) in steps.iter() {
+        if matches!(entry, Some(RecordEntry::Disconnected)) {
+            connections.push(std::mem::take(&mut discovered));
+        }
+        for message in messages.iter() {
+            if is_discovery(message) {
+                discovered.extend(discovered_topics(message));
+            } else if message.topic.starts_with(&format!("{}/", TELEMETRY_STATE)) {
+                assert!(
+                    discovered.contains(&message.topic),
+                    "{} published before it was discovered",
+                    message.topic
+                );
+            }
+        }
+    }
+    connections.push(discovered);
+
+    // Reconnecting discovers the same entities again
+    assert_eq!(connections.len(), 2);
+    assert!(connections[0]
+        .iter()
+        .any(|topic| topic.starts_with(TELEMETRY_STATE)));
+    assert_eq!(connections[0], connections[1]);
+}
+
+#[test]
+fn should_publish_connection_state() {
+    let steps = replay_steps(Instant::now());
+    let connection = |messages: &[Published]| {
+        messages
+            .iter()
+            .filter(|message| message.topic == "hairmqtt/connected")
+            .map(|message| String::from_utf8_lossy(&message.payload).to_string())
+            .collect::<Vec<String>>()
+    };
+
+    for (entry, messages) in steps.iter() {
+        match entry {
+            Some(RecordEntry::Telemetry(_)) => {
+                assert_eq!(connection(messages), vec!["connected"]);
+                assert!(messages
+                    .iter()
+                    .any(|message| message.topic == TELEMETRY_STATE));
+            }
+            Some(RecordEntry::Disconnected) => {
+                assert_eq!(connection(messages), vec!["disconnected"]);
+                // Nothing is discovered until iRacing is back
+                assert!(!messages.iter().any(is_discovery));
+            }
+            _ => assert!(connection(messages).is_empty()),
+        }
+        assert!(messages.iter().all(|message| !message.retain));
+    }
+
+    // The session arrives before the vars on reconnect, so telemetry discovery waits for the vars
+    let reconnect: Vec<&(Option<RecordEntry>, Vec<Published>)> = steps
+        .iter()
+        .skip_while(|(entry, _)| !matches!(entry, Some(RecordEntry::Disconnected)))
+        .collect();
+    let (_, session) = reconnect[1];
+    let (_, variables) = reconnect[2];
+    let discovers_telemetry = |messages: &[Published]| {
+        messages
+            .iter()
+            .filter(|message| is_discovery(message))
+            .flat_map(discovered_topics)
+            .any(|topic| topic.starts_with(TELEMETRY_STATE))
+    };
+    assert!(session.iter().any(is_discovery));
+    assert!(!discovers_telemetry(session));
+    assert!(discovers_telemetry(variables));
+}
+
+#[test]
+fn should_publish_session_once_when_heartbeat_is_due() {
+    let mut publisher = MemoryPublisher::default();
+    let mut bridge = bridge();
+    let session = entries()
+        .into_iter()
+        .find(|entry| matches!(entry, RecordEntry::Session(_)))
+        .unwrap();
+    let heartbeat = EntityConfig::default_entities().session_interval().max;
+
+    let start = Instant::now();
+    bridge.handle(&mut publisher, session.clone(), start);
+    publisher.take();
+
+    bridge.handle(&mut publisher, session, start + heartbeat);
+    let sessions = publisher
+        .take()
+        .iter()
+        .filter(|message| message.topic == SESSION_STATE)
+        .count();
+    assert_eq!(sessions, 1);
+}
+
+/// An embedded broker on a free local port, with a websocket listener like the one the bridge expects.
+fn start_broker() -> u16 {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .map(|addr| addr.port())
+        .unwrap();
+
+    let config = format!(
+        r#"
+id = 0
+
+[router]
+id = 0
+max_connections = 10
+max_outgoing_packet_count = 1000
+max_segment_size = 104857600
+max_segment_count = 10
+
+[ws.1]
+name = "ws-1"
+listen = "127.0.0.1:{port}"
+next_connection_delay_ms = 1
+
+[ws.1.connections]
+connection_timeout_ms = 60000
+max_client_id_len = 256
+throttle_delay_ms = 0
+max_payload_size = 1048576
+max_inflight_count = 1000
+max_inflight_size = 1024
+dynamic_filters = true
+"#
+    );
+    let config: rumqttd::Config = toml::from_str(&config).expect("Broker config is valid");
+    std::thread::spawn(move || {
+        let mut broker = rumqttd::Broker::new(config);
+        if let Err(e) = broker.start() {
+            log::error!("Broker stopped: {:?}", e);
+        }
+    });
+    port
+}
+
+/// Subscribes to everything, returning the received messages once the subscription is acknowledged.
+fn subscribe_all(port: u16) -> mpsc::Receiver<(String, Vec<u8>)> {
+    let mut options = MqttOptions::new("replay-observer", format!("ws://127.0.0.1:{}", port), port);
+    options.set_transport(Transport::Ws);
+    options.set_max_packet_size(MAX_PACKET_SIZE * 16, MAX_PACKET_SIZE);
+    let (client, mut connection) = Client::new(options, 10);
+
+    let (subscribed_tx, subscribed_rx) = mpsc::channel();
+    let (message_tx, message_rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        for event in connection.iter() {
+            match event {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    client.subscribe("#", QoS::AtLeastOnce).unwrap();
+                }
+                Ok(Event::Incoming(Packet::SubAck(_))) => {
+                    let _ = subscribed_tx.send(());
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    if message_tx
+                        .send((publish.topic, publish.payload.to_vec()))
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+                Ok(_) => (),
+                // The broker is not up yet
+                Err(_) => std::thread::sleep(Duration::from_millis(100)),
+            }
+        }
+    });
+
+    subscribed_rx
+        .recv_timeout(Duration::from_secs(10))
+        .expect("Subscribed to the embedded broker");
+    message_rx
+}
+
+#[test]
+fn should_deliver_replay_through_broker() {
+    let port = start_broker();
+    let messages = subscribe_all(port);
+
+    let start = Instant::now();
+    let expected: Vec<(String, Vec<u8>)> = replay_steps(start)
+        .into_iter()
+        .flat_map(|(_, messages)| messages)
+        .map(|message| (message.topic, message.payload))
+        .collect();
+
+    // Room for the whole replay, so nothing is dropped while the event loop catches up
+    let diagnostics = Arc::new(Diagnostics::default());
+    let (mut client, mut connection) =
+        MqttConnection::connect_to("127.0.0.1", port, expected.len() * 2, diagnostics.clone());
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopping = stop.clone();
+    let events = std::thread::spawn(move || {
+        for event in connection.events() {
+            // Dropping the connection closes it without a disconnect, like a bridge that crashed
+            if stopping.load(Ordering::Relaxed) {
+                break;
+            }
+            if let MqttEvent::Error(_) = event {
+                std::thread::sleep(Duration::from_millis(100));
+            }
+        }
+    });
+
+    let mut bridge = bridge();
+    bridge.start(&mut client);
+    for (index, entry) in entries().into_iter().enumerate() {
+        bridge.handle(&mut client, entry, start + STEP * index as u32);
+    }
+
+    let mut received = Vec::new();
+    while received.len() < expected.len() {
+        match messages.recv_timeout(Duration::from_secs(10)) {
+            Ok(message) => received.push(message),
+            Err(_) => break,
+        }
+    }
+
+    assert_eq!(diagnostics.dropped_messages(), 0);
+    assert_eq!(diagnostics.publish_errors(), 0);
+    let topics = |messages: &[(String, Vec<u8>)]| {
+        messages
+            .iter()
+            .map(|(topic, _)| topic.clone())
+            .collect::<Vec<String>>()
+    };
+    assert_eq!(topics(&received), topics(&expected));
+    assert_eq!(received, expected);
+
+    // One more publish wakes the event loop so it sees the stop
+    stop.store(true, Ordering::Relaxed);
+    client.direct_publish("hairmqtt/replay", b"done");
+    events.join().unwrap();
+
+    let will = std::iter::from_fn(|| messages.recv_timeout(Duration::from_secs(10)).ok())
+        .find(|(topic, _)| topic == CONNECTED_STATE);
+    assert_eq!(
+        will,
+        Some((CONNECTED_STATE.to_string(), b"disconnected".to_vec()))
+    );
+}
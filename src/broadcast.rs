@@ -0,0 +1,237 @@
+use std::fmt::{Debug, Display, Formatter};
+
+/// `irsdk_BroadcastMsg`.  The messages iRacing accepts from other programs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum BroadcastMessage {
+    /// Switches the camera group, keeping the focused car
+    CamSwitchGroup {
+        group: i64,
+    },
+    /// 1 to play, 0 to pause
+    ReplaySetPlaySpeed {
+        speed: i64,
+    },
+    ReplaySearch(ReplaySearch),
+    ChatMacro {
+        number: i64,
+    },
+    Pit(PitCommand),
+    Telemetry(TelemetryCommand),
+}
+
+/// `irsdk_PitCommandMode`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum PitCommand {
+    Clear,
+    Windshield,
+    /// Litres to add, 0 for the last amount
+    Fuel(i64),
+    /// kPa, 0 for the current pressure
+    LeftFront(i64),
+    RightFront(i64),
+    LeftRear(i64),
+    RightRear(i64),
+    ClearTires,
+    FastRepair,
+    ClearWindshield,
+    ClearFastRepair,
+    ClearFuel,
+}
+
+/// `irsdk_RpySrchMode`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ReplaySearch {
+    ToStart,
+    ToEnd,
+    PrevSession,
+    NextSession,
+    PrevLap,
+    NextLap,
+    PrevFrame,
+    NextFrame,
+    PrevIncident,
+    NextIncident,
+}
+
+/// `irsdk_TelemCommandMode`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum TelemetryCommand {
+    Stop,
+    Start,
+    Restart,
+}
+
+impl BroadcastMessage {
+    /// `(msg, var1, var2)` as passed to `irsdk_broadcastMsg`
+    pub fn params(&self) -> (u16, i16, i32) {
+        match *self {
+            // Car 0 is irsdk_csFocusAtDriver, the car already being watched
+            BroadcastMessage::CamSwitchGroup { group } => (0, 0, group as i32),
+            BroadcastMessage::ReplaySetPlaySpeed { speed } => (3, speed as i16, 0),
+            BroadcastMessage::ReplaySearch(mode) => (5, mode as i16, 0),
+            BroadcastMessage::ChatMacro { number } => (8, 0, number as i32),
+            BroadcastMessage::Pit(command) => {
+                let (mode, parameter) = command.params();
+                (9, mode, parameter)
+            }
+            BroadcastMessage::Telemetry(command) => (10, command as i16, 0),
+        }
+    }
+}
+
+impl PitCommand {
+    fn params(&self) -> (i16, i32) {
+        match *self {
+            PitCommand::Clear => (0, 0),
+            PitCommand::Windshield => (1, 0),
+            PitCommand::Fuel(litres) => (2, litres as i32),
+            PitCommand::LeftFront(kpa) => (3, kpa as i32),
+            PitCommand::RightFront(kpa) => (4, kpa as i32),
+            PitCommand::LeftRear(kpa) => (5, kpa as i32),
+            PitCommand::RightRear(kpa) => (6, kpa as i32),
+            PitCommand::ClearTires => (7, 0),
+            PitCommand::FastRepair => (8, 0),
+            PitCommand::ClearWindshield => (9, 0),
+            PitCommand::ClearFastRepair => (10, 0),
+            PitCommand::ClearFuel => (11, 0),
+        }
+    }
+}
+
+pub(crate) enum BroadcastError {
+    /// iRacing's broadcast window message could not be registered
+    Register,
+    Send(BroadcastMessage),
+}
+
+impl Display for BroadcastError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BroadcastError::Register => {
+                write!(f, "Could not register the iRacing broadcast message")
+            }
+            BroadcastError::Send(message) => write!(f, "Failed to send {:?} to iRacing", message),
+        }
+    }
+}
+
+impl Debug for BroadcastError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl std::error::Error for BroadcastError {}
+
+/// Sends broadcast messages to iRacing.  Behind a trait so commands can be logged or recorded off Windows.
+pub(crate) trait BroadcastSender {
+    fn send(&mut self, message: BroadcastMessage) -> Result<(), BroadcastError>;
+}
+
+/// Stand in for when iRacing is not there to receive, eg on Linux or with `BROADCAST=log`.
+pub(crate) struct LoggingSender;
+
+impl BroadcastSender for LoggingSender {
+    fn send(&mut self, message: BroadcastMessage) -> Result<(), BroadcastError> {
+        let (msg, var1, var2) = message.params();
+        log::info!("Broadcast {:?} ({}, {}, {})", message, msg, var1, var2);
+        Ok(())
+    }
+}
+
+/// The Windows sender, unless `BROADCAST=log`.  Always the logging sender elsewhere.
+pub(crate) fn sender_from_env() -> Box<dyn BroadcastSender + Send> {
+    if std::env::var("BROADCAST").as_deref() == Ok("log") {
+        return Box::new(LoggingSender);
+    }
+
+    #[cfg(windows)]
+    {
+        Box::new(windows::WindowsSender::default())
+    }
+    #[cfg(not(windows))]
+    {
+        Box::new(LoggingSender)
+    }
+}
+
+#[cfg(windows)]
+mod windows {
+    use super::{BroadcastError, BroadcastMessage, BroadcastSender};
+
+    const HWND_BROADCAST: isize = 0xffff;
+    const BROADCAST_MESSAGE_NAME: &[u8] = b"IRSDK_BROADCASTMSG\0";
+
+    #[link(name = "user32")]
+    extern "system" {
+        fn RegisterWindowMessageA(name: *const u8) -> u32;
+        fn SendNotifyMessageA(hwnd: isize, msg: u32, wparam: usize, lparam: isize) -> i32;
+    }
+
+    /// Broadcasts to all windows, as `irsdk_broadcastMsg` does
+    #[derive(Default)]
+    pub(super) struct WindowsSender {
+        message_id: Option<u32>,
+    }
+
+    impl BroadcastSender for WindowsSender {
+        fn send(&mut self, message: BroadcastMessage) -> Result<(), BroadcastError> {
+            let message_id = match self.message_id {
+                Some(id) => id,
+                None => {
+                    // SAFETY: the name is a null terminated string that outlives the call
+                    let id = unsafe { RegisterWindowMessageA(BROADCAST_MESSAGE_NAME.as_ptr()) };
+                    if id == 0 {
+                        return Err(BroadcastError::Register);
+                    }
+                    self.message_id = Some(id);
+                    id
+                }
+            };
+
+            let (msg, var1, var2) = message.params();
+            let wparam = (msg as u32 | ((var1 as u16 as u32) << 16)) as usize;
+
+            // SAFETY: plain values, no pointers are passed
+            let sent =
+                unsafe { SendNotifyMessageA(HWND_BROADCAST, message_id, wparam, var2 as isize) };
+            if sent == 0 {
+                return Err(BroadcastError::Send(message));
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_map_messages_to_irsdk_params() {
+        assert_eq!(
+            BroadcastMessage::Pit(PitCommand::Fuel(45)).params(),
+            (9, 2, 45)
+        );
+        assert_eq!(
+            BroadcastMessage::Pit(PitCommand::ClearFuel).params(),
+            (9, 11, 0)
+        );
+        assert_eq!(
+            BroadcastMessage::ChatMacro { number: 1 }.params(),
+            (8, 0, 1)
+        );
+        assert_eq!(
+            BroadcastMessage::ReplaySearch(ReplaySearch::NextIncident).params(),
+            (5, 9, 0)
+        );
+        assert_eq!(
+            BroadcastMessage::Telemetry(TelemetryCommand::Restart).params(),
+            (10, 2, 0)
+        );
+        assert_eq!(
+            BroadcastMessage::CamSwitchGroup { group: 12 }.params(),
+            (0, 0, 12)
+        );
+    }
+}
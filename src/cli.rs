@@ -0,0 +1,414 @@
+use ir_telemetry::client::UpdatePacket;
+use ir_telemetry::Client as IracingClient;
+use ir_telemetry::Session;
+use serde_json::{json, Value};
+use std::fmt;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::config::entities::EntityConfig;
+use crate::config::error::ConfigError;
+use crate::config::validate::{validate, Level, ValidationContext};
+use crate::recording::{read_recording, session_paths, RecordingError, Snapshot, VarInfo};
+
+const USAGE: &str = "Usage: hairmqtt [command]
+
+Without a command, runs the bridge.
+
+Commands:
+  list-vars [--json] [--recording <file>]     Lists the telemetry vars
+  dump-session [--json] [--recording <file>]  Lists the session paths and their values
+  validate [--config <file>] [--recording <file>] [--vars <file>]
+                                              Checks the entities file, ENTITIES_FILE or the built in entities
+
+Without --recording, the vars and session are read from iRacing, which must be running.  validate checks var names
+against a recording, or a --vars file saved from `list-vars --json`.";
+
+/// How long to wait for iRacing to send the vars and session
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Diagnostic commands, run instead of the bridge when given on the command line.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Command {
+    ListVars(Options),
+    DumpSession(Options),
+    Validate(Options),
+    Help,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Options {
+    pub json: bool,
+    /// Read from a recording instead of iRacing
+    pub recording: Option<PathBuf>,
+    /// Entities file to validate
+    pub config: Option<PathBuf>,
+    /// Var list to validate against, from `list-vars --json`
+    pub vars: Option<PathBuf>,
+}
+
+pub(crate) enum CliError {
+    Usage(String),
+    Recording(RecordingError),
+    Config(ConfigError),
+    Vars(String, String),
+    Invalid(usize),
+    Session(serde_yaml::Error),
+    NotConnected,
+    MissingVariables,
+    MissingSession,
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::Usage(message) => write!(f, "{}\n\n{}", message, USAGE),
+            CliError::Recording(e) => write!(f, "{}", e),
+            CliError::Config(e) => write!(f, "{}", e),
+            CliError::Vars(path, e) => write!(f, "Failed to read vars {}: {}", path, e),
+            CliError::Invalid(errors) => write!(f, "Found {} errors", errors),
+            CliError::Session(e) => write!(f, "Failed to parse the session: {}", e),
+            CliError::NotConnected => write!(
+                f,
+                "iRacing did not send the vars and session within {} seconds, is it running?",
+                CONNECT_TIMEOUT.as_secs()
+            ),
+            CliError::MissingVariables => write!(f, "The recording has no vars"),
+            CliError::MissingSession => write!(f, "The recording has no session"),
+        }
+    }
+}
+
+impl fmt::Debug for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for CliError {}
+
+impl From<RecordingError> for CliError {
+    fn from(e: RecordingError) -> Self {
+        CliError::Recording(e)
+    }
+}
+
+impl From<ConfigError> for CliError {
+    fn from(e: ConfigError) -> Self {
+        CliError::Config(e)
+    }
+}
+
+impl Command {
+    /// Parses the arguments after the program name.  `None` runs the bridge.
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Option<Self>, CliError> {
+        let Some(command) = args.next() else {
+            return Ok(None);
+        };
+
+        let mut options = Options::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--json" => options.json = true,
+                "--recording" => {
+                    let path = args
+                        .next()
+                        .ok_or_else(|| CliError::Usage("--recording needs a file".to_string()))?;
+                    options.recording = Some(PathBuf::from(path));
+                }
+                "--config" | "--vars" => {
+                    let path = args
+                        .next()
+                        .map(PathBuf::from)
+                        .ok_or_else(|| CliError::Usage(format!("{} needs a file", arg)))?;
+                    if arg == "--config" {
+                        options.config = Some(path);
+                    } else {
+                        options.vars = Some(path);
+                    }
+                }
+                _ => return Err(CliError::Usage(format!("Unknown option {}", arg))),
+            }
+        }
+
+        match command.as_str() {
+            "list-vars" => Ok(Some(Command::ListVars(options))),
+            "dump-session" => Ok(Some(Command::DumpSession(options))),
+            "validate" => Ok(Some(Command::Validate(options))),
+            "help" | "--help" | "-h" => Ok(Some(Command::Help)),
+            _ => Err(CliError::Usage(format!("Unknown command {}", command))),
+        }
+    }
+
+    /// Runs the command and returns the exit code.  Errors are printed to stderr.
+    pub fn run(&self) -> i32 {
+        let result = match self {
+            Command::ListVars(options) => list_vars(options),
+            Command::DumpSession(options) => dump_session(options),
+            Command::Validate(options) => validate_config(options),
+            Command::Help => {
+                println!("{}", USAGE);
+                Ok(())
+            }
+        };
+
+        match result {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("{}", e);
+                match e {
+                    CliError::Usage(_) => 2,
+                    _ => 1,
+                }
+            }
+        }
+    }
+}
+
+fn snapshot(options: &Options) -> Result<Snapshot, CliError> {
+    match &options.recording {
+        Some(path) => Ok(Snapshot::from_entries(&read_recording(path)?)),
+        None => live_snapshot(),
+    }
+}
+
+/// Waits for iRacing to send the var headers and the session.
+fn live_snapshot() -> Result<Snapshot, CliError> {
+    let started = Instant::now();
+    let mut snapshot = Snapshot::default();
+
+    for packet in IracingClient::connect(1.) {
+        match packet {
+            UpdatePacket::VariableHeaders(var_headers) => {
+                snapshot.variables = VarInfo::from_headers(&var_headers);
+            }
+            UpdatePacket::SessionInfo(session) => {
+                let session: Session = serde_yaml::from_str(&session).map_err(CliError::Session)?;
+                snapshot.session = Some(crate::handle_session(&session));
+            }
+            _ => (),
+        }
+
+        if !snapshot.variables.is_empty() && snapshot.session.is_some() {
+            return Ok(snapshot);
+        }
+        if started.elapsed() > CONNECT_TIMEOUT {
+            break;
+        }
+    }
+    Err(CliError::NotConnected)
+}
+
+fn list_vars(options: &Options) -> Result<(), CliError> {
+    let variables = snapshot(options)?.variables;
+    if variables.is_empty() {
+        return Err(CliError::MissingVariables);
+    }
+
+    if options.json {
+        println!("{}", json!(variables));
+    } else {
+        print!("{}", vars_table(&variables));
+    }
+    Ok(())
+}
+
+fn dump_session(options: &Options) -> Result<(), CliError> {
+    let session = snapshot(options)?.session.ok_or(CliError::MissingSession)?;
+    let paths = session_paths(&Value::Object(session));
+
+    if options.json {
+        let paths: Vec<Value> = paths
+            .into_iter()
+            .map(|(path, value)| json!({ "path": path, "value": value }))
+            .collect();
+        println!("{}", Value::Array(paths));
+    } else {
+        print!("{}", paths_table(&paths));
+    }
+    Ok(())
+}
+
+/// Prints every issue, and fails if any are errors.
+fn validate_config(options: &Options) -> Result<(), CliError> {
+    let config = match &options.config {
+        // Not checked on load, so every invalid value is reported below
+        Some(path) => EntityConfig::parse_file(&path.to_string_lossy())?,
+        None => match std::env::var("ENTITIES_FILE") {
+            Ok(path) => EntityConfig::parse_file(&path)?,
+            Err(_) => EntityConfig::default_entities(),
+        },
+    };
+
+    let mut context = ValidationContext::default();
+    if let Some(path) = &options.recording {
+        let snapshot = Snapshot::from_entries(&read_recording(path)?);
+        if !snapshot.variables.is_empty() {
+            context.variables = Some(snapshot.variables);
+        }
+        context.telemetry = snapshot.telemetry;
+        context.session = snapshot.session.map(Value::Object);
+    }
+    if let Some(path) = &options.vars {
+        let name = path.display().to_string();
+        let vars = std::fs::read_to_string(path)
+            .map_err(|e| CliError::Vars(name.clone(), e.to_string()))?;
+        let vars = serde_json::from_str(&vars).map_err(|e| CliError::Vars(name, e.to_string()))?;
+        context.variables = Some(vars);
+    }
+    if context.variables.is_none() {
+        eprintln!(
+            "No var list, var names are not checked.  Use --recording or --vars to check them."
+        );
+    }
+
+    let issues = validate(&config, &context);
+    for issue in issues.iter() {
+        println!("{}", issue);
+    }
+
+    let errors = issues
+        .iter()
+        .filter(|issue| issue.level == Level::Error)
+        .count();
+    match errors {
+        0 => {
+            println!("{} entities checked, no errors", entity_count(&config));
+            Ok(())
+        }
+        errors => Err(CliError::Invalid(errors)),
+    }
+}
+
+fn entity_count(config: &EntityConfig) -> usize {
+    config.telemetry.len()
+        + config.session.len()
+        + config.groups.len()
+        + config
+            .profiles
+            .iter()
+            .map(|profile| profile.telemetry.len())
+            .sum::<usize>()
+}
+
+/// Columns padded to the widest entry.  The description is last, since it is the longest.
+fn vars_table(variables: &[VarInfo]) -> String {
+    let rows: Vec<[String; 5]> = std::iter::once([
+        "NAME".to_string(),
+        "TYPE".to_string(),
+        "UNITS".to_string(),
+        "COUNT".to_string(),
+        "DESCRIPTION".to_string(),
+    ])
+    .chain(variables.iter().map(|var| {
+        [
+            var.name.clone(),
+            var.var_type.clone(),
+            var.units.clone(),
+            var.count.to_string(),
+            var.description.clone(),
+        ]
+    }))
+    .collect();
+
+    let widths: Vec<usize> = (0..4)
+        .map(|column| {
+            rows.iter()
+                .map(|row| row[column].len())
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+
+    let mut table = String::new();
+    for row in rows.iter() {
+        for (column, width) in widths.iter().enumerate() {
+            table.push_str(&format!("{:width$}  ", row[column], width = width));
+        }
+        table.push_str(&row[4]);
+        table.push('\n');
+    }
+    table
+}
+
+/// One path per line, with its value as JSON.
+fn paths_table(paths: &[(String, Value)]) -> String {
+    let width = paths
+        .iter()
+        .map(|(path, _)| path.len())
+        .max()
+        .unwrap_or_default();
+
+    paths
+        .iter()
+        .map(|(path, value)| format!("{:width$}  {}\n", path, value, width = width))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Option<Command>, CliError> {
+        Command::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn should_parse_commands() {
+        assert_eq!(parse(&[]).unwrap(), None);
+        assert_eq!(
+            parse(&["list-vars"]).unwrap(),
+            Some(Command::ListVars(Options::default()))
+        );
+        assert_eq!(
+            parse(&["dump-session", "--recording", "spa.jsonl", "--json"]).unwrap(),
+            Some(Command::DumpSession(Options {
+                json: true,
+                recording: Some(PathBuf::from("spa.jsonl")),
+                ..Options::default()
+            }))
+        );
+        assert_eq!(
+            parse(&["validate", "--config", "mine.yaml", "--vars", "vars.json"]).unwrap(),
+            Some(Command::Validate(Options {
+                config: Some(PathBuf::from("mine.yaml")),
+                vars: Some(PathBuf::from("vars.json")),
+                ..Options::default()
+            }))
+        );
+
+        assert!(matches!(
+            parse(&["list-vars", "--recording"]),
+            Err(CliError::Usage(_))
+        ));
+        assert!(matches!(
+            parse(&["list-vars", "--csv"]),
+            Err(CliError::Usage(_))
+        ));
+        assert!(matches!(parse(&["list-cars"]), Err(CliError::Usage(_))));
+    }
+
+    #[test]
+    fn should_align_tables() {
+        let variables = vec![VarInfo {
+            name: "Speed".to_string(),
+            var_type: "Float".to_string(),
+            units: "m/s".to_string(),
+            count: 1,
+            description: "GPS vehicle speed".to_string(),
+        }];
+        assert_eq!(
+            vars_table(&variables),
+            "NAME   TYPE   UNITS  COUNT  DESCRIPTION\nSpeed  Float  m/s    1      GPS vehicle speed\n"
+        );
+
+        let paths = vec![
+            ("weekend_info.track_name".to_string(), json!("spa")),
+            ("weekend_info.num_car_types".to_string(), json!(1)),
+        ];
+        assert_eq!(
+            paths_table(&paths),
+            "weekend_info.track_name     \"spa\"\nweekend_info.num_car_types  1\n"
+        );
+    }
+}
@@ -0,0 +1,643 @@
+use ha_mqtt::components::binary_sensor::BinarySensor;
+use ha_mqtt::device::Device;
+use serde_json::{Map, Value};
+use std::fmt::{Debug, Display, Formatter};
+use std::time::Instant;
+
+use crate::broadcast::BroadcastMessage::{
+    Pit, ReplaySearch as Search, ReplaySetPlaySpeed, Telemetry,
+};
+use crate::broadcast::{
+    BroadcastError, BroadcastMessage, BroadcastSender, PitCommand, ReplaySearch, TelemetryCommand,
+};
+use crate::config::entities::DeviceKind;
+use crate::devices::Devices;
+use crate::irmqtt::client::DiscoveryPrepPacket;
+use crate::pit_plan::{PitPlanner, PIT_PLAN_STATE};
+use crate::CONNECTED_STATE;
+
+pub(crate) const COMMAND_TOPIC: &str = "hairmqtt/command";
+pub(crate) const COMMAND_SUBSCRIPTION: &str = "hairmqtt/command/#";
+
+/// Litres.  The most `pit_fuel` and the pit plan will ask for.
+pub(crate) const MAX_FUEL: f64 = 200.;
+const CHAT_MACROS: i64 = 15;
+
+/// A HA button and the broadcast messages it sends
+struct Button {
+    object_id: &'static str,
+    name: &'static str,
+    icon: &'static str,
+    device: DeviceKind,
+    messages: &'static [BroadcastMessage],
+}
+
+const fn button(
+    object_id: &'static str,
+    name: &'static str,
+    icon: &'static str,
+    device: DeviceKind,
+    messages: &'static [BroadcastMessage],
+) -> Button {
+    Button {
+        object_id,
+        name,
+        icon,
+        device,
+        messages,
+    }
+}
+
+const BUTTONS: [Button; 19] = [
+    button(
+        "pit_clear",
+        "Clear Pit Commands",
+        "mdi:close-box-multiple",
+        DeviceKind::Car,
+        &[Pit(PitCommand::Clear)],
+    ),
+    button(
+        "pit_tyres",
+        "Change Tyres",
+        "mdi:tire",
+        DeviceKind::Car,
+        &[
+            Pit(PitCommand::LeftFront(0)),
+            Pit(PitCommand::RightFront(0)),
+            Pit(PitCommand::LeftRear(0)),
+            Pit(PitCommand::RightRear(0)),
+        ],
+    ),
+    button(
+        "pit_clear_tyres",
+        "Clear Tyre Change",
+        "mdi:tire",
+        DeviceKind::Car,
+        &[Pit(PitCommand::ClearTires)],
+    ),
+    button(
+        "pit_fast_repair",
+        "Fast Repair",
+        "mdi:wrench-clock",
+        DeviceKind::Car,
+        &[Pit(PitCommand::FastRepair)],
+    ),
+    button(
+        "pit_clear_fast_repair",
+        "Clear Fast Repair",
+        "mdi:wrench-clock",
+        DeviceKind::Car,
+        &[Pit(PitCommand::ClearFastRepair)],
+    ),
+    button(
+        "pit_windshield",
+        "Windshield Tear Off",
+        "mdi:car-windshield",
+        DeviceKind::Car,
+        &[Pit(PitCommand::Windshield)],
+    ),
+    button(
+        "pit_clear_windshield",
+        "Clear Windshield Tear Off",
+        "mdi:car-windshield",
+        DeviceKind::Car,
+        &[Pit(PitCommand::ClearWindshield)],
+    ),
+    button(
+        "pit_clear_fuel",
+        "Clear Fuel",
+        "mdi:gas-station-off",
+        DeviceKind::Car,
+        &[Pit(PitCommand::ClearFuel)],
+    ),
+    button(
+        "replay_play",
+        "Replay Play",
+        "mdi:play",
+        DeviceKind::Bridge,
+        &[ReplaySetPlaySpeed { speed: 1 }],
+    ),
+    button(
+        "replay_pause",
+        "Replay Pause",
+        "mdi:pause",
+        DeviceKind::Bridge,
+        &[ReplaySetPlaySpeed { speed: 0 }],
+    ),
+    button(
+        "replay_start",
+        "Replay to Start",
+        "mdi:skip-backward",
+        DeviceKind::Bridge,
+        &[Search(ReplaySearch::ToStart)],
+    ),
+    button(
+        "replay_live",
+        "Replay to Live",
+        "mdi:skip-forward",
+        DeviceKind::Bridge,
+        &[Search(ReplaySearch::ToEnd)],
+    ),
+    button(
+        "replay_prev_lap",
+        "Replay Previous Lap",
+        "mdi:skip-previous",
+        DeviceKind::Bridge,
+        &[Search(ReplaySearch::PrevLap)],
+    ),
+    button(
+        "replay_next_lap",
+        "Replay Next Lap",
+        "mdi:skip-next",
+        DeviceKind::Bridge,
+        &[Search(ReplaySearch::NextLap)],
+    ),
+    button(
+        "replay_prev_incident",
+        "Replay Previous Incident",
+        "mdi:alert-circle-outline",
+        DeviceKind::Bridge,
+        &[Search(ReplaySearch::PrevIncident)],
+    ),
+    button(
+        "replay_next_incident",
+        "Replay Next Incident",
+        "mdi:alert-circle",
+        DeviceKind::Bridge,
+        &[Search(ReplaySearch::NextIncident)],
+    ),
+    button(
+        "telemetry_start",
+        "Start Telemetry Recording",
+        "mdi:record-rec",
+        DeviceKind::Bridge,
+        &[Telemetry(TelemetryCommand::Start)],
+    ),
+    button(
+        "telemetry_stop",
+        "Stop Telemetry Recording",
+        "mdi:stop",
+        DeviceKind::Bridge,
+        &[Telemetry(TelemetryCommand::Stop)],
+    ),
+    button(
+        "telemetry_restart",
+        "Restart Telemetry Recording",
+        "mdi:restart",
+        DeviceKind::Bridge,
+        &[Telemetry(TelemetryCommand::Restart)],
+    ),
+];
+
+pub(crate) enum CommandError {
+    UnknownCommand(String),
+    InvalidPayload(String, String),
+    Broadcast(BroadcastError),
+}
+
+impl Display for CommandError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::UnknownCommand(topic) => write!(f, "Unknown command topic {}", topic),
+            CommandError::InvalidPayload(command, payload) => {
+                write!(f, "Invalid payload {} for command {}", payload, command)
+            }
+            CommandError::Broadcast(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Debug for CommandError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl From<BroadcastError> for CommandError {
+    fn from(e: BroadcastError) -> Self {
+        CommandError::Broadcast(e)
+    }
+}
+
+/// Turns the HA button, select and number command topics into iRacing broadcast messages.
+pub(crate) struct Commands {
+    sender: Box<dyn BroadcastSender + Send>,
+    /// `(group_name, group_num)` from the session's camera info
+    camera_groups: Vec<(String, i64)>,
+    planner: PitPlanner,
+}
+
+impl Commands {
+    pub fn new(sender: Box<dyn BroadcastSender + Send>, planner: PitPlanner) -> Self {
+        Self {
+            sender,
+            camera_groups: Vec::new(),
+            planner,
+        }
+    }
+
+    /// Applies the pit plan on pit entry.  Returns the plan state when it is due to be sent.
+    pub fn telemetry(&mut self, telemetry: &Map<String, Value>, now: Instant) -> Option<Value> {
+        for message in self.planner.telemetry(telemetry) {
+            if let Err(e) = self.sender.send(message) {
+                log::error!("{}", e);
+            }
+        }
+
+        self.planner.state(now)
+    }
+
+    /// Keeps the camera groups for the camera select.
+    pub fn session(&mut self, session: &Map<String, Value>) {
+        self.camera_groups = session
+            .get("camera_info")
+            .and_then(|info| info.get("groups"))
+            .and_then(Value::as_array)
+            .map(|groups| {
+                groups
+                    .iter()
+                    .filter_map(|group| {
+                        let name = group.get("group_name")?.as_str()?;
+                        let num = group.get("group_num")?.as_i64()?;
+                        Some((name.to_string(), num))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+    }
+
+    pub fn clear(&mut self) {
+        self.camera_groups.clear();
+        self.planner.clear();
+    }
+
+    /// Handles a message from a command topic.
+    pub fn handle(&mut self, topic: &str, payload: &[u8]) -> Result<(), CommandError> {
+        let command = topic
+            .strip_prefix(COMMAND_TOPIC)
+            .and_then(|command| command.strip_prefix('/'))
+            .ok_or_else(|| CommandError::UnknownCommand(topic.to_string()))?;
+        let payload = String::from_utf8_lossy(payload);
+
+        for message in self.messages(command, payload.trim())? {
+            self.sender.send(message)?;
+        }
+        Ok(())
+    }
+
+    fn messages(
+        &mut self,
+        command: &str,
+        payload: &str,
+    ) -> Result<Vec<BroadcastMessage>, CommandError> {
+        if let Some(result) = self.planner.command(command, payload) {
+            return result;
+        }
+
+        let invalid = || CommandError::InvalidPayload(command.to_string(), payload.to_string());
+
+        if let Some(button) = BUTTONS.iter().find(|button| button.object_id == command) {
+            return Ok(button.messages.to_vec());
+        }
+
+        match command {
+            "pit_fuel" => {
+                let litres = payload
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|litres| (0. ..=MAX_FUEL).contains(litres))
+                    .ok_or_else(invalid)?;
+                Ok(vec![Pit(PitCommand::Fuel(litres.round() as i64))])
+            }
+            "chat_macro" => {
+                let number = payload
+                    .parse::<i64>()
+                    .ok()
+                    .filter(|number| (1..=CHAT_MACROS).contains(number))
+                    .ok_or_else(invalid)?;
+                Ok(vec![BroadcastMessage::ChatMacro { number }])
+            }
+            "camera" => {
+                let (_, group) = self
+                    .camera_groups
+                    .iter()
+                    .find(|(name, _)| name == payload)
+                    .ok_or_else(invalid)?;
+                Ok(vec![BroadcastMessage::CamSwitchGroup { group: *group }])
+            }
+            _ => Err(CommandError::UnknownCommand(command.to_string())),
+        }
+    }
+
+    /// Buttons, selects and numbers for the commands.  Pit commands are on the car, the rest on the bridge.  The camera
+    /// select is only discovered once the session has the camera groups.
+    pub fn discovery_packet(&self, devices: &Devices) -> Vec<DiscoveryPrepPacket> {
+        let mut discoverables: Vec<DiscoveryPrepPacket> = BUTTONS
+            .iter()
+            .map(|button| {
+                command_entity(
+                    "button",
+                    button.object_id,
+                    button.name,
+                    button.icon,
+                    devices.get(button.device),
+                    Map::new(),
+                )
+            })
+            .collect();
+
+        let mut fuel = Map::new();
+        fuel.insert("min".to_string(), Value::from(0));
+        fuel.insert("max".to_string(), Value::from(MAX_FUEL));
+        fuel.insert("step".to_string(), Value::from(1));
+        fuel.insert("mode".to_string(), Value::from("box"));
+        fuel.insert("unit_of_measurement".to_string(), Value::from("L"));
+        discoverables.push(command_entity(
+            "number",
+            "pit_fuel",
+            "Fuel to Add",
+            "mdi:gas-station",
+            &devices.car,
+            fuel,
+        ));
+
+        let mut chat = Map::new();
+        let macros: Vec<String> = (1..=CHAT_MACROS).map(|number| number.to_string()).collect();
+        chat.insert("options".to_string(), Value::from(macros));
+        discoverables.push(command_entity(
+            "select",
+            "chat_macro",
+            "Chat Macro",
+            "mdi:chat",
+            &devices.car,
+            chat,
+        ));
+
+        discoverables.extend(plan_discovery_packet(&devices.car));
+
+        if !self.camera_groups.is_empty() {
+            let mut camera = Map::new();
+            let names: Vec<&str> = self
+                .camera_groups
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect();
+            camera.insert("options".to_string(), Value::from(names));
+            discoverables.push(command_entity(
+                "select",
+                "camera",
+                "Camera",
+                "mdi:cctv",
+                &devices.bridge,
+                camera,
+            ));
+        }
+
+        discoverables
+    }
+}
+
+/// The pit plan's fuel, switches, apply button and whether iRacing confirms the plan.  The state comes back from the
+/// plan's topic, so HA shows the saved plan.
+fn plan_discovery_packet(device: &Device) -> Vec<DiscoveryPrepPacket> {
+    let with_state = |template: String| {
+        let mut config = Map::new();
+        config.insert("state_topic".to_string(), Value::from(PIT_PLAN_STATE));
+        config.insert("value_template".to_string(), Value::from(template));
+        config
+    };
+
+    let mut fuel = with_state("{{ value_json.fuel }}".to_string());
+    fuel.insert("min".to_string(), Value::from(0));
+    fuel.insert("max".to_string(), Value::from(MAX_FUEL));
+    fuel.insert("step".to_string(), Value::from(1));
+    fuel.insert("mode".to_string(), Value::from("box"));
+    fuel.insert("unit_of_measurement".to_string(), Value::from("L"));
+
+    let mut discoverables = vec![
+        command_entity(
+            "number",
+            "plan_fuel",
+            "Planned Fuel",
+            "mdi:gas-station",
+            device,
+            fuel,
+        ),
+        command_entity(
+            "button",
+            "plan_apply",
+            "Apply Pit Plan",
+            "mdi:clipboard-check",
+            device,
+            Map::new(),
+        ),
+    ];
+
+    let switches = [
+        ("lf", "Plan Left Front Tyre", "mdi:tire"),
+        ("rf", "Plan Right Front Tyre", "mdi:tire"),
+        ("lr", "Plan Left Rear Tyre", "mdi:tire"),
+        ("rr", "Plan Right Rear Tyre", "mdi:tire"),
+        (
+            "windshield",
+            "Plan Windshield Tear Off",
+            "mdi:car-windshield",
+        ),
+        ("fast_repair", "Plan Fast Repair", "mdi:wrench-clock"),
+        (
+            "auto_apply",
+            "Apply Pit Plan on Pit Entry",
+            "mdi:clipboard-flow",
+        ),
+    ];
+    discoverables.extend(switches.into_iter().map(|(key, name, icon)| {
+        command_entity(
+            "switch",
+            &format!("plan_{}", key),
+            name,
+            icon,
+            device,
+            with_state(format!("{{{{ 'ON' if value_json.{} else 'OFF' }}}}", key)),
+        )
+    }));
+
+    discoverables.push(crate::prepare_payload(
+        BinarySensor::new(PIT_PLAN_STATE)
+            .with_name("Pit Plan Confirmed")
+            .with_device(device)
+            .with_icon("mdi:clipboard-check-outline")
+            .with_payload_on("on")
+            .with_payload_off("off")
+            .with_value_template("{{ 'on' if value_json.confirmed == true else 'off' }}")
+            .with_unique_id("hairmqtt-plan_confirmed")
+            .with_object_id("plan_confirmed"),
+    ));
+
+    discoverables
+}
+
+/// Discovery for an entity with a command topic.  The ha_mqtt crate has no button, select or number, so the config
+/// is built here.  Commands are unavailable while iRacing is not connected.
+fn command_entity(
+    component: &str,
+    object_id: &str,
+    name: &str,
+    icon: &str,
+    device: &Device,
+    mut config: Map<String, Value>,
+) -> DiscoveryPrepPacket {
+    let topic = format!("homeassistant/{}/hairmqtt/{}/config", component, object_id);
+
+    config.insert("name".to_string(), Value::from(name));
+    config.insert(
+        "unique_id".to_string(),
+        Value::from(format!("hairmqtt-{}", object_id)),
+    );
+    config.insert("object_id".to_string(), Value::from(object_id));
+    config.insert(
+        "command_topic".to_string(),
+        Value::from(format!("{}/{}", COMMAND_TOPIC, object_id)),
+    );
+    config.insert("icon".to_string(), Value::from(icon));
+    config.insert(
+        "availability_topic".to_string(),
+        Value::from(CONNECTED_STATE),
+    );
+    config.insert("payload_available".to_string(), Value::from("connected"));
+    config.insert(
+        "payload_not_available".to_string(),
+        Value::from("disconnected"),
+    );
+    if component != "button" && !config.contains_key("state_topic") {
+        // No state topic to follow, so HA shows the last value it sent
+        config.insert("optimistic".to_string(), Value::from(true));
+    }
+
+    let payload = serde_json::to_value(device).and_then(|device| {
+        config.insert("device".to_string(), device);
+        serde_json::to_vec(&config)
+    });
+
+    (topic, payload.map_err(|e| e.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pit_plan::PitPlan;
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct RecordingSender {
+        sent: Arc<Mutex<Vec<BroadcastMessage>>>,
+    }
+
+    impl BroadcastSender for RecordingSender {
+        fn send(&mut self, message: BroadcastMessage) -> Result<(), BroadcastError> {
+            self.sent.lock().unwrap().push(message);
+            Ok(())
+        }
+    }
+
+    fn commands() -> (Commands, RecordingSender) {
+        let sender = RecordingSender::default();
+        let planner = PitPlanner::new(PitPlan::default(), None);
+        (Commands::new(Box::new(sender.clone()), planner), sender)
+    }
+
+    #[test]
+    fn should_send_button_messages() {
+        let (mut commands, sender) = commands();
+        commands
+            .handle("hairmqtt/command/pit_tyres", b"PRESS")
+            .unwrap();
+        commands
+            .handle("hairmqtt/command/replay_pause", b"PRESS")
+            .unwrap();
+
+        assert_eq!(
+            *sender.sent.lock().unwrap(),
+            vec![
+                Pit(PitCommand::LeftFront(0)),
+                Pit(PitCommand::RightFront(0)),
+                Pit(PitCommand::LeftRear(0)),
+                Pit(PitCommand::RightRear(0)),
+                ReplaySetPlaySpeed { speed: 0 },
+            ]
+        );
+    }
+
+    #[test]
+    fn should_parse_number_and_select_payloads() {
+        let (mut commands, sender) = commands();
+        commands.session(
+            json!({ "camera_info": { "groups": [
+                { "group_num": 1, "group_name": "Nose" },
+                { "group_num": 9, "group_name": "Cockpit" },
+            ] } })
+            .as_object()
+            .unwrap(),
+        );
+
+        commands
+            .handle("hairmqtt/command/pit_fuel", b"42.6")
+            .unwrap();
+        commands
+            .handle("hairmqtt/command/chat_macro", b"3")
+            .unwrap();
+        commands
+            .handle("hairmqtt/command/camera", b"Cockpit")
+            .unwrap();
+
+        assert_eq!(
+            *sender.sent.lock().unwrap(),
+            vec![
+                Pit(PitCommand::Fuel(43)),
+                BroadcastMessage::ChatMacro { number: 3 },
+                BroadcastMessage::CamSwitchGroup { group: 9 },
+            ]
+        );
+    }
+
+    #[test]
+    fn should_apply_pit_plan_from_button() {
+        let (mut commands, sender) = commands();
+        commands
+            .handle("hairmqtt/command/plan_fuel", b"20")
+            .unwrap();
+        commands
+            .handle("hairmqtt/command/plan_apply", b"PRESS")
+            .unwrap();
+
+        assert_eq!(
+            *sender.sent.lock().unwrap(),
+            vec![Pit(PitCommand::Clear), Pit(PitCommand::Fuel(20))]
+        );
+    }
+
+    #[test]
+    fn should_reject_bad_commands() {
+        let (mut commands, sender) = commands();
+
+        assert!(matches!(
+            commands.handle("hairmqtt/command/pit_fuel", b"lots"),
+            Err(CommandError::InvalidPayload(_, _))
+        ));
+        assert!(matches!(
+            commands.handle("hairmqtt/command/chat_macro", b"16"),
+            Err(CommandError::InvalidPayload(_, _))
+        ));
+        assert!(matches!(
+            commands.handle("hairmqtt/command/camera", b"Blimp"),
+            Err(CommandError::InvalidPayload(_, _))
+        ));
+        assert!(matches!(
+            commands.handle("hairmqtt/command/eject", b"PRESS"),
+            Err(CommandError::UnknownCommand(_))
+        ));
+        assert!(sender.sent.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,542 @@
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use super::error::ConfigError;
+use crate::rules::Rule;
+use crate::scheduler::PublishInterval;
+use crate::session_path::SessionPath;
+
+/// Entities shipped with the bridge.  Used when `ENTITIES_FILE` is not set.
+const DEFAULT_ENTITIES: &str = include_str!("../../entities.yaml");
+
+/// Changed values are sent at 2 Hz unless an entity sets `min_interval`.
+const DEFAULT_MIN_INTERVAL: Duration = Duration::from_millis(500);
+const DEFAULT_HEARTBEAT: f64 = 5.;
+const DEFAULT_SESSION_HEARTBEAT: f64 = 20.;
+
+/// The entities to discover, from `ENTITIES_FILE` or the built in `entities.yaml`.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub(crate) struct EntityConfig {
+    /// Seconds.  Default `max_interval` for telemetry entities.
+    pub heartbeat: Option<f64>,
+    /// Seconds.  How often the last session is re-sent when iRacing has not sent an update.
+    pub session_heartbeat: Option<f64>,
+    #[serde(default)]
+    pub telemetry: Vec<TelemetryEntity>,
+    #[serde(default)]
+    pub session: Vec<SessionEntity>,
+    #[serde(default)]
+    pub groups: Vec<GroupEntity>,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    /// Extra telemetry entities for particular cars.  The first matching profile is used.
+    #[serde(default)]
+    pub profiles: Vec<CarProfile>,
+}
+
+/// Telemetry entities for a group of cars, eg the ERS vars of the hybrids.  Matched on the player's car path or car
+/// class short name from the session's `DriverInfo`.  Patterns are case insensitive and can start or end with `*`.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct CarProfile {
+    pub name: String,
+    #[serde(default)]
+    pub car_paths: Vec<String>,
+    #[serde(default)]
+    pub car_classes: Vec<String>,
+    #[serde(default)]
+    pub telemetry: Vec<TelemetryEntity>,
+}
+
+impl CarProfile {
+    pub fn matches(&self, car_path: Option<&str>, car_class: Option<&str>) -> bool {
+        let any = |patterns: &[String], value: Option<&str>| {
+            value.map_or(false, |value| {
+                patterns
+                    .iter()
+                    .any(|pattern| pattern_matches(pattern, value))
+            })
+        };
+
+        any(&self.car_paths, car_path) || any(&self.car_classes, car_class)
+    }
+}
+
+fn pattern_matches(pattern: &str, value: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let value = value.to_lowercase();
+
+    match (pattern.strip_prefix('*'), pattern.strip_suffix('*')) {
+        (Some(suffix), _) if suffix.ends_with('*') => value.contains(suffix.trim_end_matches('*')),
+        (Some(suffix), _) => value.ends_with(suffix),
+        (None, Some(prefix)) => value.starts_with(prefix),
+        (None, None) => value == pattern,
+    }
+}
+
+/// Which of the HA devices an entity is grouped under
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DeviceKind {
+    Bridge,
+    #[default]
+    Car,
+    Track,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum EntityKind {
+    #[default]
+    Sensor,
+    BinarySensor,
+}
+
+/// An entity whose state comes from a telemetry var.  Only discovered if the var is in the variable headers.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct TelemetryEntity {
+    pub var: String,
+    #[serde(default)]
+    pub kind: EntityKind,
+    #[serde(default)]
+    pub device: DeviceKind,
+    /// Defaults to the var name
+    pub name: Option<String>,
+    /// Defaults to the var name
+    pub object_id: Option<String>,
+    /// Defaults to `hairmqtt-<object_id>`
+    pub unique_id: Option<String>,
+    pub icon: Option<String>,
+    pub device_class: Option<String>,
+    pub state_class: Option<String>,
+    /// Defaults to the units in the var header.  `~` removes the unit, eg for bitfields.
+    #[serde(default, deserialize_with = "explicit_null")]
+    pub unit: Option<Option<String>>,
+    /// Defaults to `{{ value_json.<var> }}`
+    pub value_template: Option<String>,
+    pub payload_on: Option<String>,
+    pub payload_off: Option<String>,
+    /// Defaults to a few heartbeats (`max_interval`).  If set, the heartbeat is shortened to fit inside it.
+    pub expire_after: Option<u64>,
+    /// Seconds.  A changed value is not published more often than this.
+    pub min_interval: Option<f64>,
+    /// Seconds.  The value is published at least this often, even if it has not changed.
+    pub max_interval: Option<f64>,
+}
+
+impl TelemetryEntity {
+    pub fn object_id(&self) -> &str {
+        self.object_id.as_deref().unwrap_or(&self.var)
+    }
+
+    pub fn unique_id(&self) -> String {
+        self.unique_id
+            .clone()
+            .unwrap_or_else(|| format!("hairmqtt-{}", self.object_id()))
+    }
+
+    /// The entity's interval, with the defaults filled in.  An explicit `expire_after` shortens the heartbeat to fit.
+    pub fn interval(&self, default: PublishInterval) -> PublishInterval {
+        entity_interval(
+            self.min_interval,
+            self.max_interval,
+            self.expire_after,
+            default,
+        )
+    }
+
+    /// `expire_after` for discovery.  Defaults to a few heartbeats.
+    pub fn expire_after(&self, default: PublishInterval) -> u64 {
+        self.expire_after
+            .unwrap_or_else(|| self.interval(default).expire_after())
+    }
+}
+
+/// How a group's vars are combined into its state
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum GroupState {
+    #[default]
+    Average,
+    Min,
+    Max,
+}
+
+/// One sensor made from several telemetry vars, eg a corner's inner, middle and outer tyre temps.  The state is the
+/// vars combined, and each var is an attribute.  Discovered if any of the vars are in the variable headers.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct GroupEntity {
+    pub name: String,
+    pub object_id: String,
+    /// Defaults to `hairmqtt-<object_id>`
+    pub unique_id: Option<String>,
+    /// Attribute name to telemetry var
+    pub vars: BTreeMap<String, String>,
+    #[serde(default)]
+    pub state: GroupState,
+    /// Multiplies each var, eg 100 for vars that are fractions
+    pub scale: Option<f64>,
+    /// Decimal places HA shows
+    pub precision: Option<u32>,
+    #[serde(default)]
+    pub device: DeviceKind,
+    pub icon: Option<String>,
+    pub device_class: Option<String>,
+    pub state_class: Option<String>,
+    pub unit: Option<String>,
+    pub expire_after: Option<u64>,
+    pub min_interval: Option<f64>,
+    pub max_interval: Option<f64>,
+}
+
+impl GroupEntity {
+    pub fn unique_id(&self) -> String {
+        self.unique_id
+            .clone()
+            .unwrap_or_else(|| format!("hairmqtt-{}", self.object_id))
+    }
+
+    pub fn interval(&self, default: PublishInterval) -> PublishInterval {
+        entity_interval(
+            self.min_interval,
+            self.max_interval,
+            self.expire_after,
+            default,
+        )
+    }
+
+    pub fn expire_after(&self, default: PublishInterval) -> u64 {
+        self.expire_after
+            .unwrap_or_else(|| self.interval(default).expire_after())
+    }
+}
+
+fn entity_interval(
+    min_interval: Option<f64>,
+    max_interval: Option<f64>,
+    expire_after: Option<u64>,
+    default: PublishInterval,
+) -> PublishInterval {
+    let min = seconds(min_interval).unwrap_or(default.min);
+    let max = seconds(max_interval).unwrap_or(default.max);
+    let interval = PublishInterval::new(min, max);
+
+    match expire_after {
+        Some(expire_after) => interval.within_expiry(expire_after),
+        None => interval,
+    }
+}
+
+/// An entity whose state comes from the session, found with a `SessionPath`.  The bridge resolves the paths and
+/// publishes the values keyed by object id, since symbolic indices like `current` can't be resolved in a HA template.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct SessionEntity {
+    pub name: String,
+    pub object_id: String,
+    /// Defaults to `hairmqtt-<object_id>`
+    pub unique_id: Option<String>,
+    pub path: SessionPath,
+    /// Path to an object published as the entity's attributes, eg the player's `results_positions` entry
+    pub attributes: Option<SessionPath>,
+    #[serde(default)]
+    pub kind: EntityKind,
+    #[serde(default)]
+    pub device: DeviceKind,
+    pub icon: Option<String>,
+    pub device_class: Option<String>,
+    pub state_class: Option<String>,
+    pub unit: Option<String>,
+    /// Defaults to `{{ value_json.<object_id> }}`
+    pub value_template: Option<String>,
+    pub payload_on: Option<String>,
+    pub payload_off: Option<String>,
+}
+
+impl SessionEntity {
+    pub fn unique_id(&self) -> String {
+        self.unique_id
+            .clone()
+            .unwrap_or_else(|| format!("hairmqtt-{}", self.object_id))
+    }
+
+    /// Key the attributes are published under, next to the value
+    pub fn attributes_key(&self) -> String {
+        format!("{}_attributes", self.object_id)
+    }
+}
+
+/// Seconds from the config.  Values `check` rejects are treated as missing, so a config that skipped it can't panic.
+fn seconds(seconds: Option<f64>) -> Option<Duration> {
+    seconds.and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+}
+
+/// A problem with a number of seconds, which must be finite and not negative
+fn invalid_seconds(field: &str, seconds: Option<f64>) -> Option<String> {
+    seconds
+        .filter(|seconds| !seconds.is_finite() || *seconds < 0.)
+        .map(|seconds| {
+            format!(
+                "{} must be a number of seconds that is not negative, found {}",
+                field, seconds
+            )
+        })
+}
+
+/// Distinguishes a field set to `~` (`Some(None)`) from a missing one (`None`).
+fn explicit_null<'de, D>(deserializer: D) -> Result<Option<Option<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer).map(Some)
+}
+
+impl EntityConfig {
+    /// Interval for telemetry entities that do not set their own.
+    pub fn default_interval(&self) -> PublishInterval {
+        let heartbeat =
+            seconds(self.heartbeat).unwrap_or_else(|| Duration::from_secs_f64(DEFAULT_HEARTBEAT));
+        PublishInterval::new(DEFAULT_MIN_INTERVAL, heartbeat)
+    }
+
+    /// Interval for re-sending the session.  Session entities expire after a few of these.
+    pub fn session_interval(&self) -> PublishInterval {
+        let heartbeat = seconds(self.session_heartbeat)
+            .unwrap_or_else(|| Duration::from_secs_f64(DEFAULT_SESSION_HEARTBEAT));
+        PublishInterval::new(Duration::ZERO, heartbeat)
+    }
+
+    /// Intervals and heartbeats that are negative, infinite or not a number, as the unique id of the entity (or
+    /// `config` for the top level settings) and the problem.
+    pub fn invalid_intervals(&self) -> Vec<(String, String)> {
+        let config = [
+            ("heartbeat", self.heartbeat),
+            ("session_heartbeat", self.session_heartbeat),
+        ]
+        .into_iter()
+        .filter_map(|(field, seconds)| invalid_seconds(field, seconds))
+        .map(|message| ("config".to_string(), message));
+
+        let telemetry = self
+            .telemetry
+            .iter()
+            .chain(
+                self.profiles
+                    .iter()
+                    .flat_map(|profile| profile.telemetry.iter()),
+            )
+            .flat_map(|entity| {
+                [
+                    invalid_seconds("min_interval", entity.min_interval),
+                    invalid_seconds("max_interval", entity.max_interval),
+                ]
+                .into_iter()
+                .flatten()
+                .map(|message| (entity.unique_id(), message))
+            });
+
+        let groups = self.groups.iter().flat_map(|group| {
+            [
+                invalid_seconds("min_interval", group.min_interval),
+                invalid_seconds("max_interval", group.max_interval),
+            ]
+            .into_iter()
+            .flatten()
+            .map(|message| (group.unique_id(), message))
+        });
+
+        config.chain(telemetry).chain(groups).collect()
+    }
+
+    /// Fails on the first invalid interval, which would otherwise stop the telemetry thread.
+    fn check(self, path: &str) -> Result<Self, ConfigError> {
+        match self.invalid_intervals().into_iter().next() {
+            Some((entity, message)) => Err(ConfigError::Invalid(
+                path.to_string(),
+                format!("{}: {}", entity, message),
+            )),
+            None => Ok(self),
+        }
+    }
+
+    /// The first profile matching the player's car
+    pub fn profile(&self, car_path: Option<&str>, car_class: Option<&str>) -> Option<&CarProfile> {
+        self.profiles
+            .iter()
+            .find(|profile| profile.matches(car_path, car_class))
+    }
+
+    /// The generic telemetry entities plus the profile's.  A profile entity replaces a generic one with the same
+    /// unique id.
+    pub fn telemetry_entities(&self, profile: Option<&CarProfile>) -> Vec<TelemetryEntity> {
+        let Some(profile) = profile else {
+            return self.telemetry.clone();
+        };
+
+        let mut entities: Vec<TelemetryEntity> = self
+            .telemetry
+            .iter()
+            .filter(|entity| {
+                !profile
+                    .telemetry
+                    .iter()
+                    .any(|replacement| replacement.unique_id() == entity.unique_id())
+            })
+            .cloned()
+            .collect();
+        entities.extend(profile.telemetry.iter().cloned());
+        entities
+    }
+
+    /// Loads `ENTITIES_FILE` if set, otherwise the built in entities.
+    pub fn load() -> Result<Self, ConfigError> {
+        match std::env::var("ENTITIES_FILE") {
+            Ok(path) => Self::from_file(&path),
+            Err(_) => Ok(Self::default_entities()),
+        }
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, ConfigError> {
+        Self::parse_file(path)?.check(path)
+    }
+
+    /// Reads the file without checking the values, so `validate` can report every problem.
+    pub fn parse_file(path: &str) -> Result<Self, ConfigError> {
+        let contents =
+            std::fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_string(), e))?;
+        serde_yaml::from_str(&contents).map_err(|e| ConfigError::Parse(path.to_string(), e))
+    }
+
+    pub fn default_entities() -> Self {
+        serde_yaml::from_str::<Self>(DEFAULT_ENTITIES)
+            .map_err(|e| ConfigError::Parse("entities.yaml".to_string(), e))
+            .and_then(|config| config.check("entities.yaml"))
+            .expect("Built in entities.yaml is invalid")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_default_entities() {
+        let config = EntityConfig::default_entities();
+        assert!(config.telemetry.iter().any(|entity| entity.var == "Lap"));
+    }
+
+    #[test]
+    fn should_match_car_profile_patterns() {
+        assert!(pattern_matches("dallarair18", "DallaraIR18"));
+        assert!(pattern_matches("*gt3", "porsche992rgt3"));
+        assert!(pattern_matches("porsche*", "porsche963gtp"));
+        assert!(pattern_matches("*963*", "porsche963gtp"));
+        assert!(!pattern_matches("*gt3", "porsche963gtp"));
+        assert!(!pattern_matches("gt3", "porsche992rgt3"));
+    }
+
+    #[test]
+    fn should_select_first_matching_profile() {
+        let config: EntityConfig = serde_yaml::from_str(
+            r#"
+telemetry:
+  - var: Lap
+  - var: Gear
+profiles:
+  - name: gtp
+    car_classes: [GTP]
+    telemetry:
+      - var: EnergyERSBatteryPct
+  - name: porsche
+    car_paths: [porsche*]
+    telemetry:
+      - var: Gear
+        icon: mdi:cog
+"#,
+        )
+        .unwrap();
+
+        let profile = config.profile(Some("porsche963gtp"), Some("GTP")).unwrap();
+        assert_eq!(profile.name, "gtp");
+        let vars: Vec<String> = config
+            .telemetry_entities(Some(profile))
+            .into_iter()
+            .map(|entity| entity.var)
+            .collect();
+        assert_eq!(vars, vec!["Lap", "Gear", "EnergyERSBatteryPct"]);
+
+        let profile = config.profile(Some("porsche992rgt3"), Some("GT3")).unwrap();
+        let entities = config.telemetry_entities(Some(profile));
+        assert_eq!(entities.len(), 2);
+        assert_eq!(entities[1].icon.as_deref(), Some("mdi:cog"));
+
+        assert!(config.profile(Some("dallarair18"), None).is_none());
+        assert_eq!(config.telemetry_entities(None), config.telemetry);
+    }
+
+    #[test]
+    fn should_tie_expire_after_to_heartbeat() {
+        let default = PublishInterval::new(Duration::from_millis(500), Duration::from_secs(5));
+        let config: EntityConfig = serde_yaml::from_str(
+            "telemetry:\n  - var: AirTemp\n    max_interval: 10\n  - var: Lap\n    expire_after: 3\n",
+        )
+        .unwrap();
+
+        assert_eq!(config.telemetry[0].expire_after(default), 30);
+        assert_eq!(config.telemetry[1].expire_after(default), 3);
+        assert_eq!(
+            config.telemetry[1].interval(default).max,
+            Duration::from_secs(1)
+        );
+    }
+
+    #[test]
+    fn should_reject_invalid_intervals() {
+        let config: EntityConfig = serde_yaml::from_str(
+            r#"
+heartbeat: .inf
+telemetry:
+  - var: Lap
+    min_interval: -1
+  - var: Gear
+    max_interval: .nan
+groups:
+  - name: Tyres
+    object_id: tyres
+    vars: { lf: LFtempCM }
+    max_interval: 2
+"#,
+        )
+        .unwrap();
+
+        let entities: Vec<String> = config
+            .invalid_intervals()
+            .into_iter()
+            .map(|(entity, _)| entity)
+            .collect();
+        assert_eq!(entities, vec!["config", "hairmqtt-Lap", "hairmqtt-Gear"]);
+        assert!(config.clone().check("entities.yaml").is_err());
+
+        // Never panics, even without the check
+        let default = PublishInterval::new(Duration::from_millis(500), Duration::from_secs(5));
+        assert_eq!(config.default_interval().max, Duration::from_secs(5));
+        assert_eq!(config.telemetry[0].interval(default).min, default.min);
+        assert_eq!(config.telemetry[1].interval(default).max, default.max);
+    }
+
+    #[test]
+    fn should_reject_invalid_session_path() {
+        let config = serde_yaml::from_str::<EntityConfig>(
+            "session:\n  - name: Lap\n    object_id: lap\n    path: drivers[me].lap\n",
+        );
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn should_tell_removed_unit_from_default() {
+        let config: EntityConfig =
+            serde_yaml::from_str("telemetry:\n  - var: SessionState\n    unit: ~\n  - var: Lap\n")
+                .unwrap();
+        assert_eq!(config.telemetry[0].unit, Some(None));
+        assert_eq!(config.telemetry[1].unit, None);
+    }
+}
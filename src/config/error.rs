@@ -0,0 +1,30 @@
+use std::fmt;
+
+pub(crate) enum ConfigError {
+    Read(String, std::io::Error),
+    Parse(String, serde_yaml::Error),
+    /// Parsed, but a value can't be used
+    Invalid(String, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "Failed to read {}: {}", path, e),
+            ConfigError::Parse(path, e) => write!(f, "Failed to parse {}: {}", path, e),
+            ConfigError::Invalid(path, e) => write!(f, "Invalid {}: {}", path, e),
+        }
+    }
+}
+
+impl fmt::Debug for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "Failed to read {}: {:?}", path, e),
+            ConfigError::Parse(path, e) => write!(f, "Failed to parse {}: {:?}", path, e),
+            ConfigError::Invalid(path, e) => write!(f, "Invalid {}: {}", path, e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
@@ -0,0 +1,766 @@
+use minijinja::value::Value as TemplateValue;
+use minijinja::{Environment, ErrorKind, UndefinedBehavior};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use super::entities::{EntityConfig, EntityKind, TelemetryEntity};
+use crate::recording::VarInfo;
+use crate::session_path::{PathContext, Unresolved};
+
+/// HA sensor device classes, with the units HA accepts for them.  An empty list accepts any unit.  Classes that take no
+/// unit are in `UNITLESS_CLASSES`.
+const SENSOR_CLASSES: &[(&str, &[&str])] = &[
+    ("apparent_power", &[]),
+    ("aqi", &[]),
+    ("area", &[]),
+    (
+        "atmospheric_pressure",
+        &[
+            "cbar", "bar", "hPa", "mmHg", "inHg", "kPa", "mbar", "Pa", "psi",
+        ],
+    ),
+    ("battery", &["%"]),
+    ("blood_glucose_concentration", &[]),
+    ("carbon_dioxide", &[]),
+    ("carbon_monoxide", &[]),
+    ("conductivity", &[]),
+    ("current", &["A", "mA"]),
+    ("data_rate", &[]),
+    ("data_size", &[]),
+    (
+        "distance",
+        &["km", "m", "cm", "mm", "mi", "nmi", "yd", "ft", "in"],
+    ),
+    ("duration", &["d", "h", "min", "s", "ms", "μs"]),
+    ("energy", &[]),
+    ("energy_distance", &[]),
+    ("energy_storage", &[]),
+    ("frequency", &["Hz", "kHz", "MHz", "GHz"]),
+    ("gas", &[]),
+    ("humidity", &["%"]),
+    ("illuminance", &["lx"]),
+    ("irradiance", &[]),
+    ("moisture", &["%"]),
+    ("monetary", &[]),
+    ("nitrogen_dioxide", &[]),
+    ("nitrogen_monoxide", &[]),
+    ("nitrous_oxide", &[]),
+    ("ozone", &[]),
+    ("ph", &[]),
+    ("pm1", &[]),
+    ("pm10", &[]),
+    ("pm25", &[]),
+    ("power", &["mW", "W", "kW", "MW", "GW", "TW"]),
+    ("power_factor", &["%"]),
+    ("precipitation", &["cm", "in", "mm"]),
+    ("precipitation_intensity", &["in/d", "in/h", "mm/d", "mm/h"]),
+    (
+        "pressure",
+        &[
+            "cbar", "bar", "hPa", "mmHg", "inHg", "kPa", "mbar", "Pa", "psi",
+        ],
+    ),
+    ("reactive_power", &[]),
+    ("signal_strength", &["dB", "dBm"]),
+    ("sound_pressure", &["dB", "dBA"]),
+    (
+        "speed",
+        &[
+            "ft/s", "in/d", "in/h", "in/s", "km/h", "kn", "m/s", "mph", "mm/d", "mm/s",
+        ],
+    ),
+    ("sulphur_dioxide", &[]),
+    ("temperature", &["°C", "°F", "K"]),
+    ("volatile_organic_compounds", &[]),
+    ("volatile_organic_compounds_parts", &[]),
+    ("voltage", &["V", "mV", "µV", "kV", "MV"]),
+    ("volume", &["L", "mL", "gal", "fl. oz.", "m³", "ft³", "CCF"]),
+    ("volume_flow_rate", &[]),
+    (
+        "volume_storage",
+        &["L", "mL", "gal", "fl. oz.", "m³", "ft³", "CCF"],
+    ),
+    ("water", &[]),
+    ("weight", &["kg", "g", "mg", "µg", "oz", "lb", "st"]),
+    ("wind_direction", &["°"]),
+    (
+        "wind_speed",
+        &["Beaufort", "ft/s", "km/h", "kn", "m/s", "mph"],
+    ),
+];
+
+const UNITLESS_CLASSES: &[&str] = &["date", "enum", "timestamp"];
+
+const BINARY_SENSOR_CLASSES: &[&str] = &[
+    "battery",
+    "battery_charging",
+    "carbon_monoxide",
+    "cold",
+    "connectivity",
+    "door",
+    "garage_door",
+    "gas",
+    "heat",
+    "light",
+    "lock",
+    "moisture",
+    "motion",
+    "moving",
+    "occupancy",
+    "opening",
+    "plug",
+    "power",
+    "presence",
+    "problem",
+    "running",
+    "safety",
+    "smoke",
+    "sound",
+    "tamper",
+    "update",
+    "vibration",
+    "window",
+];
+
+const STATE_CLASSES: &[&str] = &["measurement", "total", "total_increasing"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Level {
+    Error,
+    Warning,
+}
+
+/// A problem with an entity in the config
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Issue {
+    pub level: Level,
+    /// The entity's unique id
+    pub entity: String,
+    pub message: String,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let level = match self.level {
+            Level::Error => "error",
+            Level::Warning => "warning",
+        };
+        write!(f, "{}: {}: {}", level, self.entity, self.message)
+    }
+}
+
+/// What the config is checked against.  Without vars, var names are not checked and telemetry templates are only
+/// parsed.  Without a session, session paths are not resolved and session templates are only parsed.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ValidationContext {
+    pub variables: Option<Vec<VarInfo>>,
+    /// Last telemetry of a recording, used for template samples in place of ones made from the var types
+    pub telemetry: Option<Map<String, Value>>,
+    pub session: Option<Value>,
+}
+
+/// Checks the config for mistakes that would otherwise only show up in HA.  Issues are sorted with errors first.
+pub(crate) fn validate(config: &EntityConfig, context: &ValidationContext) -> Vec<Issue> {
+    let mut validator = Validator {
+        context,
+        variables: context.variables.as_ref().map(|variables| {
+            variables
+                .iter()
+                .map(|var| (var.name.as_str(), var))
+                .collect()
+        }),
+        env: template_env(),
+        issues: Vec::new(),
+    };
+
+    validator.unique_ids(config);
+    validator.intervals(config);
+    validator.telemetry(config);
+    validator.session(config);
+    validator.groups(config);
+
+    let mut issues = validator.issues;
+    issues.sort();
+    issues.dedup();
+    issues
+}
+
+/// A Jinja environment with the HA filters the entities use.  Undefined values are errors, so a template that reads
+/// a missing field fails here rather than rendering blank in HA.
+fn template_env() -> Environment<'static> {
+    let mut env = Environment::new();
+    env.set_undefined_behavior(UndefinedBehavior::Strict);
+    env.add_filter(
+        "float",
+        |value: TemplateValue, default: Option<TemplateValue>| {
+            convert(value, default, |value| {
+                value.parse::<f64>().ok().map(TemplateValue::from)
+            })
+        },
+    );
+    env.add_filter(
+        "int",
+        |value: TemplateValue, default: Option<TemplateValue>| {
+            convert(value, default, |value| {
+                value
+                    .parse::<f64>()
+                    .ok()
+                    .map(|value| TemplateValue::from(value.trunc() as i64))
+            })
+        },
+    );
+    env.add_global("pi", TemplateValue::from(std::f64::consts::PI));
+    env
+}
+
+/// HA's `float` and `int` take a default for values that can not be converted.
+fn convert(
+    value: TemplateValue,
+    default: Option<TemplateValue>,
+    parse: impl Fn(&str) -> Option<TemplateValue>,
+) -> Result<TemplateValue, minijinja::Error> {
+    let converted = match value.as_str() {
+        Some(value) => parse(value.trim()),
+        None => f64::try_from(value.clone())
+            .ok()
+            .and_then(|number| parse(&number.to_string())),
+    };
+
+    converted.or(default).ok_or_else(|| {
+        minijinja::Error::new(
+            ErrorKind::InvalidOperation,
+            format!("can not convert {} and no default was given", value),
+        )
+    })
+}
+
+struct Validator<'a> {
+    context: &'a ValidationContext,
+    variables: Option<HashMap<&'a str, &'a VarInfo>>,
+    env: Environment<'static>,
+    issues: Vec<Issue>,
+}
+
+impl Validator<'_> {
+    fn error(&mut self, entity: &str, message: String) {
+        self.issues.push(Issue {
+            level: Level::Error,
+            entity: entity.to_string(),
+            message,
+        });
+    }
+
+    fn warning(&mut self, entity: &str, message: String) {
+        self.issues.push(Issue {
+            level: Level::Warning,
+            entity: entity.to_string(),
+            message,
+        });
+    }
+
+    /// Paths like the player's results only resolve once the session has results, so those are warnings.  A key the
+    /// session does not have is an error.
+    fn unresolved(&mut self, id: &str, unresolved: &Unresolved, message: String) {
+        if unresolved.can_resolve_later() {
+            self.warning(id, message);
+        } else {
+            self.error(id, message);
+        }
+    }
+
+    /// Intervals that would stop the telemetry thread
+    fn intervals(&mut self, config: &EntityConfig) {
+        for (entity, message) in config.invalid_intervals() {
+            self.error(&entity, message);
+        }
+    }
+
+    /// Entities discovered together must have different unique ids.  The generic entities are checked, then the
+    /// entities of each car profile in place of them.
+    fn unique_ids(&mut self, config: &EntityConfig) {
+        let profiles = std::iter::once(None).chain(config.profiles.iter().map(Some));
+        for profile in profiles {
+            let mut seen: BTreeMap<String, usize> = BTreeMap::new();
+            let telemetry = config.telemetry_entities(profile);
+            let ids = telemetry
+                .iter()
+                .map(TelemetryEntity::unique_id)
+                .chain(config.session.iter().map(|entity| entity.unique_id()))
+                .chain(config.groups.iter().map(|group| group.unique_id()));
+            for id in ids {
+                *seen.entry(id).or_default() += 1;
+            }
+
+            for (id, count) in seen.into_iter().filter(|(_, count)| *count > 1) {
+                let message = match profile {
+                    Some(profile) => format!(
+                        "unique id is used by {} entities with the {} profile",
+                        count, profile.name
+                    ),
+                    None => format!("unique id is used by {} entities", count),
+                };
+                self.error(&id, message);
+            }
+        }
+    }
+
+    /// The generic entities, and the entities of the profile matching the session's car.  Other profiles' vars are
+    /// not checked, since they are for other cars.
+    fn telemetry(&mut self, config: &EntityConfig) {
+        let context = self.context;
+        let session = context.session.as_ref().and_then(Value::as_object);
+        let profile = session.and_then(|session| {
+            config.profile(
+                crate::devices::car_path(session),
+                crate::devices::car_class(session),
+            )
+        });
+        let checked = config.telemetry_entities(profile);
+
+        let unchecked = config
+            .profiles
+            .iter()
+            .filter(|other| Some(*other) != profile)
+            .flat_map(|other| other.telemetry.iter())
+            .filter(|entity| !checked.contains(entity));
+
+        for entity in checked.iter() {
+            self.telemetry_entity(entity, true);
+        }
+        for entity in unchecked {
+            self.telemetry_entity(entity, false);
+        }
+    }
+
+    fn telemetry_entity(&mut self, entity: &TelemetryEntity, check_var: bool) {
+        let id = entity.unique_id();
+        let found = self
+            .variables
+            .as_ref()
+            .filter(|_| check_var)
+            .map(|variables| variables.get(entity.var.as_str()).copied());
+        if let Some(None) = found {
+            self.error(&id, format!("var {} is not in the var list", entity.var));
+        }
+        let var = found.flatten();
+
+        // The var header units are used when the entity does not set its own
+        let unit = match &entity.unit {
+            Some(unit) => unit.clone(),
+            None => var
+                .map(|var| var.units.clone())
+                .filter(|units| !units.is_empty()),
+        };
+        self.classes(
+            &id,
+            entity.kind,
+            entity.device_class.as_deref(),
+            entity.state_class.as_deref(),
+            unit.as_deref(),
+        );
+
+        let default = format!("{{{{ value_json.{} }}}}", entity.var);
+        let template = entity.value_template.as_ref().unwrap_or(&default);
+        let context = self.context;
+        let sample = var.map(|var| {
+            let value = context
+                .telemetry
+                .as_ref()
+                .and_then(|telemetry| telemetry.get(&var.name))
+                .cloned()
+                .unwrap_or_else(|| sample_value(var));
+            let mut payload = Map::new();
+            payload.insert(entity.var.clone(), value);
+            payload
+        });
+        self.template(&id, template, sample);
+    }
+
+    fn session(&mut self, config: &EntityConfig) {
+        let context = self.context;
+        let context = context
+            .session
+            .as_ref()
+            .map(|session| (session, PathContext::new(session, None)));
+
+        for entity in config.session.iter() {
+            let id = entity.unique_id();
+            self.classes(
+                &id,
+                entity.kind,
+                entity.device_class.as_deref(),
+                entity.state_class.as_deref(),
+                entity.unit.as_deref(),
+            );
+
+            let mut sample = None;
+            if let Some((session, context)) = &context {
+                let value = entity.path.try_resolve(session, context);
+                if let Err(unresolved) = &value {
+                    let message = format!("path {} does not resolve: {}", entity.path, unresolved);
+                    self.unresolved(&id, unresolved, message);
+                }
+                if let Some(attributes) = &entity.attributes {
+                    if let Err(unresolved) = attributes.try_resolve(session, context) {
+                        let message =
+                            format!("attributes {} do not resolve: {}", attributes, unresolved);
+                        self.unresolved(&id, &unresolved, message);
+                    }
+                }
+                let value = value.ok();
+
+                let mut payload = Map::new();
+                payload.insert(
+                    entity.object_id.clone(),
+                    value.cloned().unwrap_or(Value::Null),
+                );
+                sample = Some(payload);
+            }
+
+            let default = format!("{{{{ value_json.{} }}}}", entity.object_id);
+            let template = entity.value_template.as_ref().unwrap_or(&default);
+            self.template(&id, template, sample);
+        }
+    }
+
+    fn groups(&mut self, config: &EntityConfig) {
+        for group in config.groups.iter() {
+            let id = group.unique_id();
+            self.classes(
+                &id,
+                EntityKind::Sensor,
+                group.device_class.as_deref(),
+                group.state_class.as_deref(),
+                group.unit.as_deref(),
+            );
+
+            let Some(variables) = &self.variables else {
+                continue;
+            };
+            let missing: Vec<&str> = group
+                .vars
+                .values()
+                .map(String::as_str)
+                .filter(|var| !variables.contains_key(var))
+                .collect();
+            if !missing.is_empty() {
+                self.error(
+                    &id,
+                    format!("vars {} are not in the var list", missing.join(", ")),
+                );
+            }
+        }
+    }
+
+    fn classes(
+        &mut self,
+        id: &str,
+        kind: EntityKind,
+        device_class: Option<&str>,
+        state_class: Option<&str>,
+        unit: Option<&str>,
+    ) {
+        if let Some(device_class) = device_class {
+            match kind {
+                EntityKind::Sensor => self.sensor_class(id, device_class, unit),
+                EntityKind::BinarySensor => {
+                    if !BINARY_SENSOR_CLASSES.contains(&device_class) {
+                        self.error(
+                            id,
+                            format!("unknown binary sensor device class {}", device_class),
+                        );
+                    }
+                }
+            }
+        }
+
+        if let Some(state_class) = state_class {
+            if kind == EntityKind::BinarySensor {
+                self.error(id, "binary sensors do not have a state class".to_string());
+            } else if !STATE_CLASSES.contains(&state_class) {
+                self.error(id, format!("unknown state class {}", state_class));
+            }
+        }
+    }
+
+    fn sensor_class(&mut self, id: &str, device_class: &str, unit: Option<&str>) {
+        if UNITLESS_CLASSES.contains(&device_class) {
+            if let Some(unit) = unit {
+                self.error(
+                    id,
+                    format!(
+                        "device class {} does not take a unit, found {}",
+                        device_class, unit
+                    ),
+                );
+            }
+            return;
+        }
+
+        let Some((_, units)) = SENSOR_CLASSES
+            .iter()
+            .find(|(class, _)| *class == device_class)
+        else {
+            self.error(id, format!("unknown sensor device class {}", device_class));
+            return;
+        };
+        if units.is_empty() {
+            return;
+        }
+
+        match unit {
+            Some(unit) if units.contains(&unit) => (),
+            Some(unit) => self.error(
+                id,
+                format!(
+                    "unit {} is not valid for device class {}, expected one of {}",
+                    unit,
+                    device_class,
+                    units.join(", ")
+                ),
+            ),
+            None => self.error(
+                id,
+                format!(
+                    "device class {} needs one of the units {}",
+                    device_class,
+                    units.join(", ")
+                ),
+            ),
+        }
+    }
+
+    /// Parses the template, and renders it when there is a sample payload
+    fn template(&mut self, id: &str, template: &str, sample: Option<Map<String, Value>>) {
+        let result = match sample {
+            Some(sample) => {
+                let value = Value::Object(sample);
+                let ctx = minijinja::context! {
+                    value_json => &value,
+                    value => value.to_string(),
+                };
+                self.env.render_str(template, ctx).map(|_| ())
+            }
+            None => self.env.template_from_str(template).map(|_| ()),
+        };
+
+        if let Err(e) = result {
+            self.error(id, format!("value template {} failed: {}", template, e));
+        }
+    }
+}
+
+/// A value of the var's type, for rendering templates without a recording.  Bitfields are sent as a list of names.
+fn sample_value(var: &VarInfo) -> Value {
+    let value = match var.var_type.as_str() {
+        "Bool" => Value::from(false),
+        "Int" => Value::from(0),
+        "Float" | "Double" => Value::from(0.),
+        "BitField" => Value::Array(Vec::new()),
+        _ => Value::from(""),
+    };
+
+    if var.count > 1 && var.var_type != "BitField" {
+        Value::Array(vec![value; var.count])
+    } else {
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn var(name: &str, var_type: &str, units: &str) -> VarInfo {
+        VarInfo {
+            name: name.to_string(),
+            var_type: var_type.to_string(),
+            units: units.to_string(),
+            count: 1,
+            description: String::new(),
+        }
+    }
+
+    fn config(yaml: &str) -> EntityConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn messages(issues: &[Issue]) -> Vec<String> {
+        issues.iter().map(Issue::to_string).collect()
+    }
+
+    #[test]
+    fn should_pass_default_entities() {
+        let issues = validate(
+            &EntityConfig::default_entities(),
+            &ValidationContext::default(),
+        );
+        let errors: Vec<&Issue> = issues
+            .iter()
+            .filter(|issue| issue.level == Level::Error)
+            .collect();
+        assert!(errors.is_empty(), "{:?}", errors);
+    }
+
+    #[test]
+    fn should_find_missing_vars_and_colliding_ids() {
+        let config = config(
+            r#"
+telemetry:
+  - var: Speed
+  - var: Sped
+  - var: RPM
+    unique_id: hairmqtt-Speed
+groups:
+  - name: Tyres
+    object_id: tyres
+    vars: { lf: LFtempCM, rf: RFtempCM }
+"#,
+        );
+        let context = ValidationContext {
+            variables: Some(vec![
+                var("Speed", "Float", "m/s"),
+                var("RPM", "Float", "revs/min"),
+                var("LFtempCM", "Float", "C"),
+            ]),
+            ..ValidationContext::default()
+        };
+
+        assert_eq!(
+            messages(&validate(&config, &context)),
+            vec![
+                "error: hairmqtt-Sped: var Sped is not in the var list",
+                "error: hairmqtt-Speed: unique id is used by 2 entities",
+                "error: hairmqtt-tyres: vars RFtempCM are not in the var list",
+            ]
+        );
+    }
+
+    #[test]
+    fn should_check_units_against_device_class() {
+        let config = config(
+            r#"
+telemetry:
+  - var: LFtempCM
+    device_class: temperature
+  - var: Speed
+    device_class: speed
+    unit: km/h
+    state_class: measurement
+  - var: FuelLevel
+    device_class: fuel
+  - var: OnPitRoad
+    kind: binary_sensor
+    state_class: measurement
+"#,
+        );
+        let context = ValidationContext {
+            variables: Some(vec![
+                var("LFtempCM", "Float", "C"),
+                var("Speed", "Float", "m/s"),
+                var("FuelLevel", "Float", "l"),
+                var("OnPitRoad", "Bool", ""),
+            ]),
+            ..ValidationContext::default()
+        };
+
+        assert_eq!(
+            messages(&validate(&config, &context)),
+            vec![
+                "error: hairmqtt-FuelLevel: unknown sensor device class fuel",
+                "error: hairmqtt-LFtempCM: unit C is not valid for device class temperature, expected one of °C, °F, K",
+                "error: hairmqtt-OnPitRoad: binary sensors do not have a state class",
+            ]
+        );
+    }
+
+    #[test]
+    fn should_render_templates_against_samples() {
+        let config = config(
+            r#"
+telemetry:
+  - var: Speed
+    value_template: "{{ (value_json.Speed | float * 3.6) | round(1) }}"
+  - var: RPM
+    value_template: "{{ value_json.Rpm }}"
+  - var: Gear
+    value_template: "{{ value_json.Gear | int(0) + }}"
+session:
+  - name: Session Time
+    object_id: session_time
+    path: session_info.sessions[current].session_time
+    value_template: "{{ value_json.session_time | replace(' sec', '') | float(0) | round(0) }}"
+  - name: Position
+    object_id: position
+    path: session_info.sessions[current].results_positions[player].position
+  - name: Track
+    object_id: track
+    path: weekend_info.trak_name
+"#,
+        );
+        let context = ValidationContext {
+            variables: Some(vec![
+                var("Speed", "Float", ""),
+                var("RPM", "Float", ""),
+                var("Gear", "Int", ""),
+            ]),
+            telemetry: Some(json!({ "Speed": 41.2 }).as_object().unwrap().clone()),
+            session: Some(json!({
+                "weekend_info": { "track_name": "spa" },
+                "session_info": { "sessions": [
+                    { "session_num": 0, "session_time": "unlimited", "results_positions": null }
+                ]}
+            })),
+        };
+
+        let issues = validate(&config, &context);
+        let summary: Vec<(Level, &str)> = issues
+            .iter()
+            .map(|issue| (issue.level, issue.entity.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (Level::Error, "hairmqtt-Gear"),
+                (Level::Error, "hairmqtt-RPM"),
+                (Level::Error, "hairmqtt-track"),
+                (Level::Warning, "hairmqtt-position"),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_reject_invalid_intervals() {
+        let config = config(
+            "heartbeat: -1\ntelemetry:\n  - var: Speed\n    min_interval: .inf\n  - var: RPM\n",
+        );
+
+        assert_eq!(
+            messages(&validate(&config, &ValidationContext::default())),
+            vec![
+                "error: config: heartbeat must be a number of seconds that is not negative, found -1",
+                "error: hairmqtt-Speed: min_interval must be a number of seconds that is not negative, found inf",
+            ]
+        );
+    }
+
+    #[test]
+    fn should_convert_like_ha() {
+        let env = template_env();
+        let render = |template: &str| env.render_str(template, minijinja::context! {}).ok();
+
+        assert_eq!(render("{{ '12.5' | float }}"), Some("12.5".to_string()));
+        assert_eq!(
+            render("{{ 'unlimited' | float(0) }}"),
+            Some("0".to_string())
+        );
+        assert_eq!(render("{{ 'unlimited' | float }}"), None);
+        assert_eq!(render("{{ '3' | int + 1 }}"), Some("4".to_string()));
+        assert_eq!(
+            render("{{ (pi * 100) | round(0) }}"),
+            Some("314.0".to_string())
+        );
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use super::entities::EntityConfig;
+use crate::irmqtt::client::DiscoveryPrepPacket;
+use crate::scheduler::Throttle;
+
+/// How often the entities file is checked for changes
+const RELOAD_CHECK: Duration = Duration::from_secs(2);
+
+/// Watches `ENTITIES_FILE` so entities can be changed without restarting the bridge.
+pub(crate) struct ConfigWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    check: Throttle,
+}
+
+impl ConfigWatcher {
+    /// Only watches `ENTITIES_FILE`.  The built in entities can not change while the bridge runs.
+    pub fn from_env() -> Option<Self> {
+        let path = std::env::var("ENTITIES_FILE").ok()?;
+        Some(Self::new(PathBuf::from(path)))
+    }
+
+    fn new(path: PathBuf) -> Self {
+        let modified = modified(&path);
+        Self {
+            path,
+            modified,
+            check: Throttle::new(RELOAD_CHECK),
+        }
+    }
+
+    /// Returns the new config when the file has changed.  A file that fails to load is logged and the current config
+    /// is kept, so a half saved file does not remove every entity.
+    pub fn check(&mut self, now: Instant) -> Option<EntityConfig> {
+        if !self.check.ready(now) {
+            return None;
+        }
+
+        let modified = modified(&self.path);
+        if modified == self.modified {
+            return None;
+        }
+        self.modified = modified;
+
+        match EntityConfig::from_file(&self.path.to_string_lossy()) {
+            Ok(config) => {
+                log::info!("Reloaded {}", self.path.display());
+                Some(config)
+            }
+            Err(e) => {
+                log::error!("{}, keeping the current entities", e);
+                None
+            }
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// The discovery messages to send when the entities change.  Packets are matched on their config topic, which holds
+/// the entity's object id.  Returns the new or changed packets, and the config topics of removed entities.
+pub(crate) fn diff_discovery(
+    old: Vec<DiscoveryPrepPacket>,
+    new: Vec<DiscoveryPrepPacket>,
+) -> (Vec<DiscoveryPrepPacket>, Vec<String>) {
+    let mut old: HashMap<String, Option<Vec<u8>>> = old
+        .into_iter()
+        .map(|(topic, payload)| (topic, payload.ok()))
+        .collect();
+
+    let mut changed = Vec::new();
+    for (topic, payload) in new.into_iter() {
+        let unchanged = match (old.remove(&topic), &payload) {
+            (Some(Some(old)), Ok(new)) => old == *new,
+            _ => false,
+        };
+        if !unchanged {
+            changed.push((topic, payload));
+        }
+    }
+
+    let mut removed: Vec<String> = old.into_keys().collect();
+    removed.sort();
+    (changed, removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(topic: &str, payload: &str) -> DiscoveryPrepPacket {
+        (topic.to_string(), Ok(payload.as_bytes().to_vec()))
+    }
+
+    fn topics(packets: &[DiscoveryPrepPacket]) -> Vec<&str> {
+        packets.iter().map(|(topic, _)| topic.as_str()).collect()
+    }
+
+    #[test]
+    fn should_diff_discovery_by_topic() {
+        let old = vec![
+            packet("sensor/lap", r#"{"icon":"mdi:flag"}"#),
+            packet("sensor/gear", r#"{"icon":"mdi:cog"}"#),
+            packet("sensor/rpm", "{}"),
+        ];
+        let new = vec![
+            packet("sensor/lap", r#"{"icon":"mdi:flag"}"#),
+            packet("sensor/gear", r#"{"icon":"mdi:car-shift-pattern"}"#),
+            packet("sensor/speed", "{}"),
+        ];
+
+        let (changed, removed) = diff_discovery(old, new);
+        assert_eq!(topics(&changed), vec!["sensor/gear", "sensor/speed"]);
+        assert_eq!(removed, vec!["sensor/rpm"]);
+    }
+
+    #[test]
+    fn should_resend_packets_that_failed_to_serialize() {
+        let failed = || -> DiscoveryPrepPacket { ("sensor/lap".to_string(), Err("failed".into())) };
+
+        let (changed, removed) = diff_discovery(vec![failed()], vec![packet("sensor/lap", "{}")]);
+        assert_eq!(topics(&changed), vec!["sensor/lap"]);
+        assert!(removed.is_empty());
+
+        let (changed, _) = diff_discovery(vec![packet("sensor/lap", "{}")], vec![failed()]);
+        assert_eq!(topics(&changed), vec!["sensor/lap"]);
+    }
+
+    #[test]
+    fn should_reload_changed_file() {
+        let path =
+            std::env::temp_dir().join(format!("hairmqtt-entities-{}.yaml", std::process::id()));
+        std::fs::write(&path, "telemetry:\n  - var: Lap\n").unwrap();
+        let mut watcher = ConfigWatcher::new(path.clone());
+        assert!(watcher.check(Instant::now()).is_none());
+
+        // Modification times can be coarse, so force a change
+        watcher.modified = None;
+        watcher.check = Throttle::new(RELOAD_CHECK);
+        let config = watcher.check(Instant::now()).unwrap();
+        assert_eq!(config.telemetry[0].var, "Lap");
+
+        std::fs::write(&path, "telemetry: [").unwrap();
+        watcher.modified = None;
+        watcher.check = Throttle::new(RELOAD_CHECK);
+        assert!(watcher.check(Instant::now()).is_none());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,97 @@
+use ha_mqtt::device::Device;
+use serde_json::{Map, Value};
+
+use crate::config::entities::DeviceKind;
+
+const MANUFACTURER: &str = "Tim Reed";
+const BRIDGE_IDENTIFIER: &str = "hairmqtt";
+const CAR_IDENTIFIER: &str = "hairmqtt-car";
+const TRACK_IDENTIFIER: &str = "hairmqtt-track";
+
+/// The HA devices entities are grouped under.  The car and track devices are linked to the bridge with `via_device`
+/// so HA shows them as being provided by the bridge.
+pub(crate) struct Devices {
+    pub bridge: Device,
+    pub car: Device,
+    pub track: Device,
+}
+
+impl Devices {
+    pub fn new(version: &str) -> Self {
+        let bridge = Device::new()
+            .with_name("HairMQTT Bridge")
+            .with_manufacturer(MANUFACTURER)
+            .with_sw_version(version)
+            .with_identifiers(vec![BRIDGE_IDENTIFIER.to_string()]);
+
+        Self {
+            bridge,
+            car: linked_device("Car", CAR_IDENTIFIER),
+            track: linked_device("Track", TRACK_IDENTIFIER),
+        }
+    }
+
+    pub fn get(&self, kind: DeviceKind) -> &Device {
+        match kind {
+            DeviceKind::Bridge => &self.bridge,
+            DeviceKind::Car => &self.car,
+            DeviceKind::Track => &self.track,
+        }
+    }
+
+    /// Renames the car and track devices from the serialized session.  The identifiers stay the same so entity history
+    /// in HA carries over when the car or track changes.
+    pub fn update_from_session(&mut self, session: &Map<String, Value>) {
+        if let Some(car) = car_screen_name(session) {
+            self.car = linked_device(car, CAR_IDENTIFIER);
+        }
+
+        if let Some(track) = session
+            .get("weekend_info")
+            .and_then(|info| info.get("track_display_name"))
+            .and_then(Value::as_str)
+        {
+            self.track = linked_device(track, TRACK_IDENTIFIER);
+        }
+    }
+}
+
+fn linked_device(name: &str, identifier: &str) -> Device {
+    Device::new()
+        .with_name(name)
+        .with_manufacturer(MANUFACTURER)
+        .with_identifiers(vec![identifier.to_string()])
+        .with_via_device(BRIDGE_IDENTIFIER)
+}
+
+/// Finds the player's entry in the drivers list.  The list is indexed by car idx, but is matched on `car_idx` rather
+/// than position in case spectators or gaps are in the list.
+fn player_driver(session: &Map<String, Value>) -> Option<&Value> {
+    let driver_info = session.get("driver_info")?;
+    let player_idx = driver_info.get("driver_car_idx")?.as_i64()?;
+
+    driver_info
+        .get("drivers")?
+        .as_array()?
+        .iter()
+        .find(|driver| driver.get("car_idx").and_then(Value::as_i64) == Some(player_idx))
+}
+
+fn player_driver_str<'a>(session: &'a Map<String, Value>, key: &str) -> Option<&'a str> {
+    player_driver(session)?.get(key)?.as_str()
+}
+
+/// The screen name of the player's car
+pub(crate) fn car_screen_name(session: &Map<String, Value>) -> Option<&str> {
+    player_driver_str(session, "car_screen_name")
+}
+
+/// The player's car path, eg `dallarair18`.  Used to pick the car profile.
+pub(crate) fn car_path(session: &Map<String, Value>) -> Option<&str> {
+    player_driver_str(session, "car_path")
+}
+
+/// The short name of the player's car class, eg `GT3`.  Used to pick the car profile.
+pub(crate) fn car_class(session: &Map<String, Value>) -> Option<&str> {
+    player_driver_str(session, "car_class_short_name")
+}
@@ -0,0 +1,291 @@
+use ha_mqtt::components::sensor::Sensor;
+use ha_mqtt::device::Device;
+use serde::Serialize;
+use serde_json::Value;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::irmqtt::client::{DiscoveryPrepPacket, MAX_PACKET_SIZE};
+
+pub(crate) const DIAGNOSTICS_STATE: &str = "hairmqtt/diagnostics";
+
+/// How often the diagnostics are sent.  Rates are averaged over this window.
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Counters for the health of the bridge.  Shared between the telemetry thread and the mqtt event loop, so everything
+/// is atomic.
+#[derive(Default)]
+pub(crate) struct Diagnostics {
+    publishes: AtomicU64,
+    publish_errors: AtomicU64,
+    dropped_messages: AtomicU64,
+    connection_errors: AtomicU64,
+    connects: AtomicU64,
+    last_payload_size: AtomicUsize,
+    // Largest payload since the last report
+    max_payload_size: AtomicUsize,
+    telemetry_ticks: AtomicU64,
+    session_updates: AtomicU64,
+}
+
+impl Diagnostics {
+    pub fn record_publish(&self, payload_size: usize) {
+        self.publishes.fetch_add(1, Ordering::Relaxed);
+        self.last_payload_size
+            .store(payload_size, Ordering::Relaxed);
+        self.max_payload_size
+            .fetch_max(payload_size, Ordering::Relaxed);
+    }
+
+    pub fn record_publish_error(&self) {
+        self.publish_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A message was dropped because the request channel to the event loop was full.
+    pub fn record_dropped(&self) {
+        self.dropped_messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_connection_error(&self) {
+        self.connection_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The broker acknowledged a connection.
+    pub fn record_connect(&self) {
+        self.connects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_telemetry_tick(&self) {
+        self.telemetry_ticks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_session_update(&self) {
+        self.session_updates.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn publishes(&self) -> u64 {
+        self.publishes.load(Ordering::Relaxed)
+    }
+
+    pub fn publish_errors(&self) -> u64 {
+        self.publish_errors.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped_messages(&self) -> u64 {
+        self.dropped_messages.load(Ordering::Relaxed)
+    }
+
+    pub fn connection_errors(&self) -> u64 {
+        self.connection_errors.load(Ordering::Relaxed)
+    }
+
+    /// Connections after the first one.
+    pub fn reconnects(&self) -> u64 {
+        self.connects.load(Ordering::Relaxed).saturating_sub(1)
+    }
+
+    pub fn last_payload_size(&self) -> usize {
+        self.last_payload_size.load(Ordering::Relaxed)
+    }
+
+    /// Largest payload since the last call, which starts the next window.
+    pub fn take_max_payload_size(&self) -> usize {
+        self.max_payload_size.swap(0, Ordering::Relaxed)
+    }
+
+    pub fn telemetry_ticks(&self) -> u64 {
+        self.telemetry_ticks.load(Ordering::Relaxed)
+    }
+
+    pub fn session_updates(&self) -> u64 {
+        self.session_updates.load(Ordering::Relaxed)
+    }
+}
+
+/// State published to `DIAGNOSTICS_STATE`
+#[derive(Serialize)]
+pub(crate) struct DiagnosticsReport {
+    publishes: u64,
+    publish_rate: f64,
+    publish_errors: u64,
+    dropped_messages: u64,
+    connection_errors: u64,
+    max_payload_size: usize,
+    payload_size_pct: f64,
+    telemetry_rate: f64,
+    session_updates: u64,
+}
+
+/// Turns the counters into a report every `REPORT_INTERVAL`.  Keeps the previous counts to work out the rates.
+pub(crate) struct DiagnosticsReporter {
+    // Set on the first entry, so the window is measured in the bridge's time rather than from construction
+    last_report: Option<Instant>,
+    last_publishes: u64,
+    last_ticks: u64,
+}
+
+impl DiagnosticsReporter {
+    pub fn new() -> Self {
+        Self {
+            last_report: None,
+            last_publishes: 0,
+            last_ticks: 0,
+        }
+    }
+
+    /// Returns the report payload if the interval has elapsed.
+    pub fn report(&mut self, diagnostics: &Diagnostics, now: Instant) -> Option<DiagnosticsReport> {
+        let last_report = *self.last_report.get_or_insert(now);
+        let elapsed = now.saturating_duration_since(last_report);
+        if elapsed < REPORT_INTERVAL {
+            return None;
+        }
+
+        let publishes = diagnostics.publishes();
+        let ticks = diagnostics.telemetry_ticks();
+        let seconds = elapsed.as_secs_f64();
+        let max_payload_size = diagnostics.take_max_payload_size();
+
+        let report = DiagnosticsReport {
+            publishes,
+            publish_rate: round((publishes - self.last_publishes) as f64 / seconds),
+            publish_errors: diagnostics.publish_errors(),
+            dropped_messages: diagnostics.dropped_messages(),
+            connection_errors: diagnostics.connection_errors(),
+            max_payload_size,
+            payload_size_pct: round(max_payload_size as f64 * 100. / MAX_PACKET_SIZE as f64),
+            telemetry_rate: round((ticks - self.last_ticks) as f64 / seconds),
+            session_updates: diagnostics.session_updates(),
+        };
+
+        self.last_report = Some(now);
+        self.last_publishes = publishes;
+        self.last_ticks = ticks;
+
+        Some(report)
+    }
+}
+
+fn round(value: f64) -> f64 {
+    (value * 100.).round() / 100.
+}
+
+/// Diagnostic sensors for the bridge device.  They expire if the bridge stops reporting, which is what to alert on.
+pub(crate) fn discovery_packet(device: &Device) -> Vec<DiscoveryPrepPacket> {
+    let sensors = [
+        (
+            "publishes",
+            "Publishes",
+            None,
+            "mdi:send",
+            "total_increasing",
+        ),
+        (
+            "publish_rate",
+            "Publish Rate",
+            Some("msg/s"),
+            "mdi:speedometer",
+            "measurement",
+        ),
+        (
+            "publish_errors",
+            "Publish Errors",
+            None,
+            "mdi:alert-circle",
+            "total_increasing",
+        ),
+        (
+            "dropped_messages",
+            "Dropped Messages",
+            None,
+            "mdi:tray-remove",
+            "total_increasing",
+        ),
+        (
+            "connection_errors",
+            "Connection Errors",
+            None,
+            "mdi:lan-disconnect",
+            "total_increasing",
+        ),
+        (
+            "max_payload_size",
+            "Max Payload Size",
+            Some("B"),
+            "mdi:package-variant",
+            "measurement",
+        ),
+        (
+            "payload_size_pct",
+            "Payload Size of Max",
+            Some("%"),
+            "mdi:gauge",
+            "measurement",
+        ),
+        (
+            "telemetry_rate",
+            "Telemetry Rate",
+            Some("Hz"),
+            "mdi:pulse",
+            "measurement",
+        ),
+        (
+            "session_updates",
+            "Session Updates",
+            None,
+            "mdi:counter",
+            "total_increasing",
+        ),
+    ];
+
+    sensors
+        .into_iter()
+        .map(|(key, name, unit, icon, state_class)| {
+            let mut sensor = Sensor::new(DIAGNOSTICS_STATE)
+                .with_name(name)
+                .with_unique_id(format!("hairmqtt-diagnostics-{}", key))
+                .with_object_id(format!("hairmqtt_{}", key))
+                .with_expire_after(30)
+                .with_device(device)
+                .with_value_template(format!("{{{{ value_json.{} }}}}", key));
+            sensor.icon = Some(icon.to_string());
+            sensor.unit_of_measurement = unit.map(|u| u.to_string());
+
+            crate::prepare_payload_with(
+                sensor,
+                &[
+                    ("entity_category", Value::from("diagnostic")),
+                    ("state_class", Value::from(state_class)),
+                ],
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_report_largest_payload_of_each_window() {
+        let diagnostics = Diagnostics::default();
+        let mut reporter = DiagnosticsReporter::new();
+        let start = Instant::now();
+        assert!(reporter.report(&diagnostics, start).is_none());
+
+        diagnostics.record_publish(5000);
+        diagnostics.record_publish(12);
+        let report = reporter
+            .report(&diagnostics, start + REPORT_INTERVAL)
+            .unwrap();
+        assert_eq!(report.max_payload_size, 5000);
+        assert_eq!(diagnostics.last_payload_size(), 12);
+
+        diagnostics.record_publish(40);
+        let report = reporter
+            .report(&diagnostics, start + REPORT_INTERVAL * 2)
+            .unwrap();
+        assert_eq!(report.max_payload_size, 40);
+        assert_eq!(report.publish_rate, 0.1);
+    }
+}
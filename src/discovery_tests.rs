@@ -0,0 +1,293 @@
+//! Golden file tests for the discovery messages sent to HA.  The full discovery set is built from the fixtures in
+//! `tests/fixtures` and compared to `tests/golden/discovery.json`.  After an intended change, regenerate it with
+//! `UPDATE_GOLDEN=1 cargo test` and review the diff.
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::PathBuf;
+use std::time::Instant;
+
+use crate::broadcast::LoggingSender;
+use crate::commands::Commands;
+use crate::config::entities::EntityConfig;
+use crate::devices::{self, Devices};
+use crate::groups::{group_states, group_topic};
+use crate::pit_plan::{PitPlan, PitPlanner, PIT_PLAN_STATE};
+use crate::recording::VarInfo;
+use crate::sun::{Sun, SUN_STATE};
+use crate::weather::{Weather, WEATHER_STATE};
+use crate::{telemetry_topic, var_state, SESSION_ENTITIES_STATE};
+
+const GOLDEN: &str = "tests/golden/discovery.json";
+
+fn fixture<T: serde::de::DeserializeOwned>(name: &str) -> T {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name);
+    let contents = std::fs::read_to_string(&path).expect("Fixture exists");
+    serde_json::from_str(&contents).expect("Fixture is valid")
+}
+
+struct Fixtures {
+    config: EntityConfig,
+    vars: HashMap<String, VarInfo>,
+    telemetry: Map<String, Value>,
+    session: Map<String, Value>,
+}
+
+impl Fixtures {
+    fn load() -> Self {
+        let vars: Vec<VarInfo> = fixture("vars.json");
+        Self {
+            config: EntityConfig::default_entities(),
+            vars: vars
+                .into_iter()
+                .map(|var| (var.name.clone(), var))
+                .collect(),
+            telemetry: fixture("telemetry.json"),
+            session: fixture("session.json"),
+        }
+    }
+
+    /// Everything the bridge discovers once the session and vars are known, keyed by config topic.
+    fn discovery(&self) -> BTreeMap<String, Value> {
+        let mut devices = Devices::new("golden");
+        devices.update_from_session(&self.session);
+
+        let profile = self.config.profile(
+            devices::car_path(&self.session),
+            devices::car_class(&self.session),
+        );
+        let entities = self.config.telemetry_entities(profile);
+
+        let mut commands = Commands::new(
+            Box::new(LoggingSender),
+            PitPlanner::new(PitPlan::default(), None),
+        );
+        commands.session(&self.session);
+
+        let mut packets =
+            crate::config_discovery_packet(&self.vars, &devices, &entities, &self.config);
+        packets.extend(commands.discovery_packet(&devices));
+
+        let mut discovery = BTreeMap::new();
+        for (topic, payload) in packets.into_iter() {
+            let payload = payload.expect("Discovery payload serializes");
+            let payload: Value =
+                serde_json::from_slice(&payload).expect("Discovery payload is JSON");
+            assert!(
+                discovery.insert(topic.clone(), payload).is_none(),
+                "{} is discovered twice",
+                topic
+            );
+        }
+        discovery
+    }
+
+    /// A state message for each state topic the fixtures can produce.
+    fn states(&self) -> HashMap<String, Map<String, Value>> {
+        let now = Instant::now();
+        let mut states = HashMap::new();
+
+        for (var, value) in self.telemetry.iter() {
+            states.insert(telemetry_topic(var), var_state(var.clone(), value.clone()));
+        }
+
+        let session = Value::Object(self.session.clone());
+        let session_num = self.telemetry.get("SessionNum").and_then(Value::as_i64);
+        states.insert(
+            SESSION_ENTITIES_STATE.to_string(),
+            crate::handle_session_entities(&session, session_num, &self.config),
+        );
+
+        for (object_id, state) in group_states(&self.config.groups, &self.telemetry).into_iter() {
+            if let Value::Object(state) = state {
+                states.insert(group_topic(&object_id), state);
+            }
+        }
+
+        let mut weather = Weather::new();
+        weather.session(&self.session);
+        if let Some(state) = weather.update(&self.telemetry, now) {
+            states.insert(WEATHER_STATE.to_string(), state);
+        }
+
+        let mut sun = Sun::new();
+        sun.session(&self.session);
+        if let Some(state) = sun.update(&self.telemetry, now) {
+            states.insert(SUN_STATE.to_string(), state);
+        }
+
+        let mut planner = PitPlanner::new(PitPlan::default(), None);
+        if let Some(Value::Object(state)) = planner.state(now) {
+            states.insert(PIT_PLAN_STATE.to_string(), state);
+        }
+
+        states
+    }
+}
+
+/// `value_json.<field>` references in a template
+fn template_fields(template: &str) -> Vec<&str> {
+    template
+        .match_indices("value_json.")
+        .map(|(start, marker)| {
+            let rest = &template[start + marker.len()..];
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            &rest[..end]
+        })
+        .collect()
+}
+
+/// The payload's value for a discovery field, under its full or abbreviated name
+fn field<'a>(payload: &'a Value, names: &[&str]) -> Option<&'a str> {
+    names
+        .iter()
+        .find_map(|name| payload.get(*name))
+        .and_then(Value::as_str)
+}
+
+#[test]
+fn should_match_golden_discovery() {
+    let discovery = Fixtures::load().discovery();
+    let actual = serde_json::to_string_pretty(&discovery).unwrap() + "\n";
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(GOLDEN);
+
+    if std::env::var("UPDATE_GOLDEN").is_ok() {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, actual).unwrap();
+        eprintln!("Wrote {}", path.display());
+        return;
+    }
+
+    let contents = std::fs::read_to_string(&path).unwrap_or_else(|e| {
+        panic!(
+            "Failed to read {}, generate it with UPDATE_GOLDEN=1 and commit it: {}",
+            GOLDEN, e
+        )
+    });
+    let expected: BTreeMap<String, Value> = serde_json::from_str(&contents).unwrap();
+
+    let mut differences = Vec::new();
+    for topic in expected
+        .keys()
+        .filter(|topic| !discovery.contains_key(*topic))
+    {
+        differences.push(format!("removed {}", topic));
+    }
+    for (topic, payload) in discovery.iter() {
+        match expected.get(topic) {
+            None => differences.push(format!("added {}", topic)),
+            Some(expected) if expected != payload => {
+                differences.push(format!("changed {}:\n  {}\n  {}", topic, expected, payload))
+            }
+            _ => (),
+        }
+    }
+    assert!(
+        differences.is_empty(),
+        "Discovery differs from {}, run with UPDATE_GOLDEN=1 if this is intended:\n{}",
+        GOLDEN,
+        differences.join("\n")
+    );
+}
+
+#[test]
+fn should_discover_unique_ids() {
+    let discovery = Fixtures::load().discovery();
+
+    let mut seen = HashSet::new();
+    for (topic, payload) in discovery.iter() {
+        let unique_id = field(payload, &["unique_id", "uniq_id"])
+            .unwrap_or_else(|| panic!("{} has no unique id", topic));
+        assert!(
+            seen.insert(unique_id),
+            "{} reuses unique id {}",
+            topic,
+            unique_id
+        );
+    }
+}
+
+#[test]
+fn should_discover_valid_object_ids() {
+    let discovery = Fixtures::load().discovery();
+    let valid = |id: &str| {
+        !id.is_empty()
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    };
+
+    for (topic, payload) in discovery.iter() {
+        let object_id = field(payload, &["object_id", "obj_id"])
+            .unwrap_or_else(|| panic!("{} has no object id", topic));
+        assert!(
+            valid(object_id),
+            "{} has invalid object id {}",
+            topic,
+            object_id
+        );
+
+        // Config topics are `<prefix>/<component>/[<node>/]<object_id>/config`
+        let segments: Vec<&str> = topic.split('/').collect();
+        assert_eq!(segments.last(), Some(&"config"), "{}", topic);
+        assert!(segments.iter().all(|segment| valid(segment)), "{}", topic);
+    }
+}
+
+#[test]
+fn should_template_existing_fields() {
+    let fixtures = Fixtures::load();
+    let discovery = fixtures.discovery();
+    let states = fixtures.states();
+
+    let templates = [
+        (["value_template", "val_tpl"], ["state_topic", "stat_t"]),
+        (
+            ["json_attributes_template", "json_attr_tpl"],
+            ["json_attributes_topic", "json_attr_t"],
+        ),
+    ];
+
+    let mut checked = 0;
+    for (topic, payload) in discovery.iter() {
+        for (template_names, topic_names) in templates.iter() {
+            let Some(template) = field(payload, template_names) else {
+                continue;
+            };
+            let state_topic = field(payload, topic_names)
+                .unwrap_or_else(|| panic!("{} has a template without a topic", topic));
+            // Diagnostics only report after a minute, so have no fixture state
+            let Some(state) = states.get(state_topic) else {
+                continue;
+            };
+
+            for name in template_fields(template) {
+                assert!(
+                    state.contains_key(name),
+                    "{} templates {} which is not in {}",
+                    topic,
+                    name,
+                    state_topic
+                );
+                checked += 1;
+            }
+        }
+    }
+    assert!(checked > 0);
+}
+
+#[test]
+fn should_find_template_fields() {
+    assert_eq!(
+        template_fields("{{ (value_json.EnergyERSBatteryPct | float * 100) | round(0) }}"),
+        vec!["EnergyERSBatteryPct"]
+    );
+    assert_eq!(
+        template_fields("{{ value_json.lf_tyre_temp.attributes | tojson }} {{ value_json.state }}"),
+        vec!["lf_tyre_temp", "state"]
+    );
+    assert!(template_fields("{{ value }}").is_empty());
+}
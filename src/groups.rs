@@ -0,0 +1,182 @@
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+use crate::config::entities::{EntityConfig, GroupEntity, GroupState};
+use crate::devices::Devices;
+use crate::entity_builders::SensorBuilder;
+use crate::irmqtt::client::DiscoveryPrepPacket;
+use crate::scheduler::Scheduler;
+
+pub(crate) const GROUPS_STATE: &str = "hairmqtt/group";
+
+/// Each group has its own topic, with the state and the grouped vars as attributes.
+pub(crate) fn group_topic(object_id: &str) -> String {
+    format!("{}/{}", GROUPS_STATE, object_id)
+}
+
+/// States of the groups that have any of their vars in the telemetry, keyed by object id.
+pub(crate) fn group_states(
+    groups: &[GroupEntity],
+    telemetry: &Map<String, Value>,
+) -> Map<String, Value> {
+    groups
+        .iter()
+        .filter_map(|group| {
+            group_state(group, telemetry).map(|state| (group.object_id.clone(), state))
+        })
+        .collect()
+}
+
+/// `{"state": .., "attributes": {..}}`.  Vars that are missing or not numbers are left out of both.
+fn group_state(group: &GroupEntity, telemetry: &Map<String, Value>) -> Option<Value> {
+    let scale = group.scale.unwrap_or(1.);
+
+    let mut attributes = Map::new();
+    let mut values = Vec::new();
+    for (key, var) in group.vars.iter() {
+        if let Some(value) = telemetry.get(var).and_then(Value::as_f64) {
+            let value = value * scale;
+            attributes.insert(key.clone(), Value::from(value));
+            values.push(value);
+        }
+    }
+
+    if values.is_empty() {
+        return None;
+    }
+
+    let state = match group.state {
+        GroupState::Average => values.iter().sum::<f64>() / values.len() as f64,
+        GroupState::Min => values.iter().copied().fold(f64::INFINITY, f64::min),
+        GroupState::Max => values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+    };
+
+    let mut payload = Map::new();
+    payload.insert("state".to_string(), Value::from(state));
+    payload.insert("attributes".to_string(), Value::Object(attributes));
+    Some(Value::Object(payload))
+}
+
+fn any_var<V>(group: &GroupEntity, var_headers: &HashMap<String, V>) -> bool {
+    group.vars.values().any(|var| var_headers.contains_key(var))
+}
+
+/// Creates a list of discoverable entities from the groups in the config.  Groups with none of their vars in the
+/// variable headers are skipped.
+pub(crate) fn discovery_packet<V>(
+    var_headers: &HashMap<String, V>,
+    devices: &Devices,
+    config: &EntityConfig,
+) -> Vec<DiscoveryPrepPacket> {
+    let default_interval = config.default_interval();
+
+    config
+        .groups
+        .iter()
+        .filter(|group| any_var(group, var_headers))
+        .map(|group| {
+            let topic = group_topic(&group.object_id);
+            let mut sensor = SensorBuilder::new_group(
+                &group.object_id,
+                &group.name,
+                &topic,
+                devices.get(group.device),
+            )
+            .with_ids(&group.object_id, group.unique_id())
+            .with_unit_of_measurement(group.unit.as_ref());
+            if let Some(icon) = &group.icon {
+                sensor = sensor.with_icon(icon);
+            }
+
+            let mut extra = vec![
+                (
+                    "expire_after",
+                    Value::from(group.expire_after(default_interval)),
+                ),
+                ("json_attributes_topic", Value::from(topic.as_str())),
+                (
+                    "json_attributes_template",
+                    Value::from("{{ value_json.attributes | tojson }}"),
+                ),
+            ];
+            if let Some(device_class) = &group.device_class {
+                extra.push(("device_class", Value::from(device_class.as_str())));
+            }
+            if let Some(state_class) = &group.state_class {
+                extra.push(("state_class", Value::from(state_class.as_str())));
+            }
+            if let Some(precision) = group.precision {
+                extra.push(("suggested_display_precision", Value::from(precision)));
+            }
+
+            crate::prepare_payload_with(sensor.build(), &extra)
+        })
+        .collect()
+}
+
+/// Schedules the discovered groups, keyed by object id.
+pub(crate) fn schedule_groups<V>(
+    scheduler: &mut Scheduler,
+    var_headers: &HashMap<String, V>,
+    config: &EntityConfig,
+) {
+    let default_interval = config.default_interval();
+
+    scheduler.clear();
+    for group in config.groups.iter() {
+        if any_var(group, var_headers) {
+            scheduler.register(&group.object_id, group.interval(default_interval));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn group(state: &str) -> GroupEntity {
+        serde_yaml::from_str(&format!(
+            "name: LF Tyre Wear\nobject_id: lf_tyre_wear\nstate: {}\nscale: 100\n\
+             vars:\n  inner: LFwearR\n  middle: LFwearM\n  outer: LFwearL\n",
+            state
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn should_combine_vars_into_state() {
+        let telemetry = json!({ "LFwearL": 0.5, "LFwearM": 0.75, "LFwearR": 1.0 });
+        let telemetry = telemetry.as_object().unwrap();
+
+        let state = group_state(&group("min"), telemetry).unwrap();
+        assert_eq!(
+            state,
+            json!({ "state": 50., "attributes": { "inner": 100., "middle": 75., "outer": 50. } })
+        );
+        assert_eq!(
+            group_state(&group("max"), telemetry).unwrap()["state"],
+            json!(100.)
+        );
+        assert_eq!(
+            group_state(&group("average"), telemetry).unwrap()["state"],
+            json!(75.)
+        );
+    }
+
+    #[test]
+    fn should_skip_missing_vars() {
+        let telemetry = json!({ "LFwearM": 0.5, "Gear": 3 });
+        let telemetry = telemetry.as_object().unwrap();
+
+        let states = group_states(&[group("average")], telemetry);
+        assert_eq!(
+            states,
+            json!({ "lf_tyre_wear": { "state": 50., "attributes": { "middle": 50. } } })
+                .as_object()
+                .unwrap()
+                .clone()
+        );
+        assert!(group_states(&[group("average")], &Map::new()).is_empty());
+    }
+}
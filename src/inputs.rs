@@ -0,0 +1,229 @@
+use serde_json::{Map, Value};
+use std::time::{Duration, Instant};
+
+use crate::scheduler::Throttle;
+
+pub(crate) const INPUTS_STATE: &str = "hairmqtt/inputs";
+
+/// Version of the binary payload, sent as its first byte
+const BINARY_VERSION: u8 = 1;
+
+const MAX_RATE: f64 = 60.;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum InputsFormat {
+    Csv,
+    Binary,
+}
+
+/// The driver inputs and the bits of car state rig lighting and fans need, sent at a high rate as a compact payload
+/// on its own topic.  The full telemetry blob is too big to send this often.
+pub(crate) struct InputsChannel {
+    throttle: Throttle,
+    format: InputsFormat,
+    /// Number of shift light stages.  0 for none.
+    shift_stages: u8,
+}
+
+/// One sample of the inputs
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Inputs {
+    throttle: f64,
+    brake: f64,
+    clutch: f64,
+    rpm: f64,
+    gear: i64,
+    /// m/s
+    speed: f64,
+    shift_pct: f64,
+    shift_rpm: f64,
+    shift_stage: u8,
+}
+
+impl InputsChannel {
+    /// Enabled by `INPUTS_RATE`, in Hz.  The channel can not be faster than `TELEMETRY_RATE`.
+    pub fn from_env() -> Option<Self> {
+        let rate = std::env::var("INPUTS_RATE").ok()?;
+        let rate = match rate.parse::<f64>() {
+            Ok(rate) if rate > 0. => rate.min(MAX_RATE),
+            _ => {
+                log::error!("Invalid INPUTS_RATE {}, inputs channel disabled", rate);
+                return None;
+            }
+        };
+
+        let format = match std::env::var("INPUTS_FORMAT").as_deref() {
+            Ok("binary") => InputsFormat::Binary,
+            Ok("csv") | Err(_) => InputsFormat::Csv,
+            Ok(format) => {
+                log::error!("Invalid INPUTS_FORMAT {}, using csv", format);
+                InputsFormat::Csv
+            }
+        };
+
+        let shift_stages = std::env::var("SHIFT_STAGES")
+            .ok()
+            .and_then(|stages| stages.parse::<u8>().ok())
+            .unwrap_or(0);
+
+        log::info!("Sending inputs to {} at {} Hz", INPUTS_STATE, rate);
+        Some(Self {
+            throttle: Throttle::new(Duration::from_secs_f64(1. / rate)),
+            format,
+            shift_stages,
+        })
+    }
+
+    /// Returns the payload when the next sample is due.
+    pub fn update(&mut self, telemetry: &Map<String, Value>, now: Instant) -> Option<Vec<u8>> {
+        if !self.throttle.ready(now) {
+            return None;
+        }
+
+        let inputs = Inputs::from_telemetry(telemetry, self.shift_stages);
+        Some(match self.format {
+            InputsFormat::Csv => inputs.to_csv().into_bytes(),
+            InputsFormat::Binary => inputs.to_binary(),
+        })
+    }
+}
+
+impl Inputs {
+    fn from_telemetry(telemetry: &Map<String, Value>, shift_stages: u8) -> Self {
+        let number = |var: &str| telemetry.get(var).and_then(Value::as_f64).unwrap_or(0.);
+
+        let rpm = number("RPM");
+        let shift_rpm = number("PlayerCarSLShiftRPM");
+        // Cars without shift lights have no indicator, so fall back to how close the rpm is to the shift point
+        let shift_pct = match telemetry.get("ShiftIndicatorPct").and_then(Value::as_f64) {
+            Some(pct) => pct,
+            None if shift_rpm > 0. => rpm / shift_rpm,
+            None => 0.,
+        }
+        .clamp(0., 1.);
+
+        Self {
+            throttle: number("Throttle"),
+            brake: number("Brake"),
+            clutch: number("Clutch"),
+            rpm,
+            gear: telemetry.get("Gear").and_then(Value::as_i64).unwrap_or(0),
+            speed: number("Speed"),
+            shift_pct,
+            shift_rpm,
+            shift_stage: shift_stage(shift_pct, shift_stages),
+        }
+    }
+
+    fn to_csv(&self) -> String {
+        format!(
+            "{:.3},{:.3},{:.3},{:.0},{},{:.2},{:.3},{:.0},{}",
+            self.throttle,
+            self.brake,
+            self.clutch,
+            self.rpm,
+            self.gear,
+            self.speed,
+            self.shift_pct,
+            self.shift_rpm,
+            self.shift_stage
+        )
+    }
+
+    /// 13 bytes, little endian: version, then throttle, brake and clutch as 0-255, rpm u16, gear i8, speed in cm/s
+    /// u16, shift pct as 0-255, shift rpm u16 and shift stage u8.
+    fn to_binary(&self) -> Vec<u8> {
+        let unit = |value: f64| (value.clamp(0., 1.) * 255.).round() as u8;
+        let word = |value: f64| value.clamp(0., u16::MAX as f64).round() as u16;
+
+        let mut payload = Vec::with_capacity(13);
+        payload.push(BINARY_VERSION);
+        payload.push(unit(self.throttle));
+        payload.push(unit(self.brake));
+        payload.push(unit(self.clutch));
+        payload.extend_from_slice(&word(self.rpm).to_le_bytes());
+        payload.push(self.gear.clamp(i8::MIN as i64, i8::MAX as i64) as i8 as u8);
+        payload.extend_from_slice(&word(self.speed * 100.).to_le_bytes());
+        payload.push(unit(self.shift_pct));
+        payload.extend_from_slice(&word(self.shift_rpm).to_le_bytes());
+        payload.push(self.shift_stage);
+        payload
+    }
+}
+
+/// How many of the shift lights are lit.  All of them once the indicator is full.
+fn shift_stage(shift_pct: f64, stages: u8) -> u8 {
+    (shift_pct.clamp(0., 1.) * stages as f64).floor() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Field order of the CSV payload.  Also the order of the binary one.
+    const INPUT_FIELDS: [&str; 9] = [
+        "throttle",
+        "brake",
+        "clutch",
+        "rpm",
+        "gear",
+        "speed",
+        "shift_pct",
+        "shift_rpm",
+        "shift_stage",
+    ];
+
+    fn inputs(telemetry: Value, stages: u8) -> Inputs {
+        Inputs::from_telemetry(telemetry.as_object().unwrap(), stages)
+    }
+
+    #[test]
+    fn should_find_shift_stage() {
+        assert_eq!(shift_stage(0., 5), 0);
+        assert_eq!(shift_stage(0.59, 5), 2);
+        assert_eq!(shift_stage(1., 5), 5);
+        assert_eq!(shift_stage(0.8, 0), 0);
+    }
+
+    #[test]
+    fn should_fall_back_to_rpm_for_shift_pct() {
+        let sample = inputs(json!({ "RPM": 6000., "PlayerCarSLShiftRPM": 8000. }), 4);
+        assert_eq!(sample.shift_pct, 0.75);
+        assert_eq!(sample.shift_stage, 3);
+
+        let sample = inputs(
+            json!({ "RPM": 6000., "PlayerCarSLShiftRPM": 8000., "ShiftIndicatorPct": 0.2 }),
+            4,
+        );
+        assert_eq!(sample.shift_stage, 0);
+    }
+
+    #[test]
+    fn should_write_csv_in_field_order() {
+        let sample = inputs(
+            json!({
+                "Throttle": 1., "Brake": 0., "Clutch": 1., "RPM": 7250.4, "Gear": 4,
+                "Speed": 55.123, "ShiftIndicatorPct": 0.5, "PlayerCarSLShiftRPM": 8000.,
+            }),
+            2,
+        );
+
+        let csv = sample.to_csv();
+        assert_eq!(csv, "1.000,0.000,1.000,7250,4,55.12,0.500,8000,1");
+        assert_eq!(csv.split(',').count(), INPUT_FIELDS.len());
+    }
+
+    #[test]
+    fn should_write_binary() {
+        let sample = inputs(
+            json!({ "Throttle": 1., "RPM": 7250., "Gear": -1, "Speed": 10., "PlayerCarSLShiftRPM": 8000. }),
+            0,
+        );
+
+        assert_eq!(
+            sample.to_binary(),
+            vec![1, 255, 0, 0, 0x52, 0x1c, 0xff, 0xe8, 0x03, 231, 0x40, 0x1f, 0]
+        );
+    }
+}
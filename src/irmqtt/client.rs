@@ -1,55 +1,313 @@
-use rumqttc::{Client, Connection, MqttOptions, QoS, Transport};
+use rumqttc::v5::mqttbytes::v5::{
+    LastWill as LastWill5, Packet as Packet5, PubAckReason, SubscribeReasonCode,
+};
+use rumqttc::v5::{
+    Client as Client5, ClientError as ClientError5, Connection as Connection5,
+    ConnectionError as ConnectionError5, Event as Event5, MqttOptions as MqttOptions5,
+};
+use rumqttc::{
+    Client, ClientError, Connection, ConnectionError, Event, LastWill, MqttOptions, Packet, QoS,
+    Transport,
+};
 use serde::Serialize;
+use std::sync::Arc;
 
 use super::error::MqttError;
+use super::publisher::{Publisher, DISCOVERY_QOS, STATE_QOS};
+use super::v5::{qos as qos5, refuses_v5, BrokerLimits, MqttVersion, V5Options, V5Properties};
+use crate::diagnostics::Diagnostics;
+use crate::CONNECTED_STATE;
 
 const APPNAME: &str = "HairMqtt";
 
+/// Published by the broker if the bridge drops off without disconnecting, so HA doesn't keep showing it as connected.
+const LAST_WILL: &str = "disconnected";
+
+/// Max packet size for outgoing messages.  Since we can send the entire data update, this is bumped up significantly.
+pub(crate) const MAX_PACKET_SIZE: usize = 10240 * 8;
+
+/// Requests that can queue for the event loop before `try_publish` drops them.  Room for a burst of entity topics on
+/// top of the high rate inputs channel.
+const REQUEST_CAPACITY: usize = 64;
+
 pub(crate) type DiscoveryPrepPacket = (String, Result<Vec<u8>, Box<dyn std::error::Error>>);
-pub(crate) struct MqttClient(Client);
 
-pub(crate) struct MqttConnection {}
+/// Cloned so the connection loop can subscribe while the telemetry thread publishes.
+#[derive(Clone)]
+pub(crate) struct MqttClient {
+    client: ClientKind,
+    diagnostics: Arc<Diagnostics>,
+}
+
+#[derive(Clone)]
+enum ClientKind {
+    V311(Client),
+    V5(Client5, V5Properties),
+}
+
+/// The event loop.  It has to be iterated with `events` to move messages along.
+pub(crate) enum MqttConnection {
+    V311(Connection),
+    V5 {
+        connection: Connection5,
+        limits: Arc<BrokerLimits>,
+        diagnostics: Arc<Diagnostics>,
+        /// The connack was already seen while checking the broker supports MQTT 5
+        connected: bool,
+    },
+}
+
+/// What the connection loop handles, the same for either protocol.
+pub(crate) enum MqttEvent {
+    Connected,
+    Publish(String, Vec<u8>),
+    Error(String),
+    Other,
+}
+
+/// Where and how to connect, for either protocol.
+struct ConnectSettings {
+    host: String,
+    port: u16,
+    credentials: Option<(String, String)>,
+    capacity: usize,
+}
 
 impl MqttConnection {
-    pub fn connect() -> Result<(MqttClient, Connection), MqttError> {
+    /// Connects with MQTT 3.1.1 unless `MQTT_VERSION=5`.
+    pub fn connect(
+        diagnostics: Arc<Diagnostics>,
+    ) -> Result<(MqttClient, MqttConnection), MqttError> {
         let creds = MqttCredentials::new();
         let broker = MqttBroker::new()?;
 
-        // Setting up rumqttc in websockets is a little hokey. https://github.com/bytebeamio/rumqtt/issues/808
-        let connection_string = format!("ws://{}:{}", broker.host, broker.port);
-        let mut mqttoptions = MqttOptions::new(APPNAME, connection_string, broker.port);
+        let credentials = match (creds.username(), creds.password()) {
+            (Some(username), Some(password)) => Some((username.to_string(), password.to_string())),
+            (None, None) => None, // No credentials needed
+            _ => return Err(MqttError::MissingCredendials),
+        };
+
+        let settings = ConnectSettings {
+            host: broker.host,
+            port: broker.port,
+            credentials,
+            capacity: REQUEST_CAPACITY,
+        };
+        Ok(match broker.version {
+            MqttVersion::V311 => settings.v311(diagnostics),
+            MqttVersion::V5 => settings.v5(V5Options::from_env(), diagnostics),
+        })
+    }
+
+    /// Connects to a local broker without credentials.  The request channel can be sized so a test can replay
+    /// faster than the event loop drains it.
+    #[cfg(test)]
+    pub fn connect_to(
+        host: &str,
+        port: u16,
+        capacity: usize,
+        diagnostics: Arc<Diagnostics>,
+    ) -> (MqttClient, MqttConnection) {
+        let settings = ConnectSettings {
+            host: host.to_string(),
+            port,
+            credentials: None,
+            capacity,
+        };
+        settings.v311(diagnostics)
+    }
+
+    pub fn events(&mut self) -> Box<dyn Iterator<Item = MqttEvent> + '_> {
+        match self {
+            MqttConnection::V311(connection) => Box::new(connection.iter().map(v311_event)),
+            MqttConnection::V5 {
+                connection,
+                limits,
+                diagnostics,
+                connected,
+            } => {
+                let pending = std::mem::take(connected).then_some(MqttEvent::Connected);
+                Box::new(
+                    pending.into_iter().chain(
+                        connection
+                            .iter()
+                            .map(move |event| v5_event(event, limits, diagnostics)),
+                    ),
+                )
+            }
+        }
+    }
+}
+
+impl ConnectSettings {
+    // Setting up rumqttc in websockets is a little hokey. https://github.com/bytebeamio/rumqtt/issues/808
+    fn connection_string(&self) -> String {
+        format!("ws://{}:{}", self.host, self.port)
+    }
+
+    fn v311(self, diagnostics: Arc<Diagnostics>) -> (MqttClient, MqttConnection) {
+        let mut mqttoptions = MqttOptions::new(APPNAME, self.connection_string(), self.port);
         mqttoptions.set_transport(Transport::Ws);
+        mqttoptions.set_max_packet_size(10240, MAX_PACKET_SIZE);
+        mqttoptions.set_last_will(LastWill::new(CONNECTED_STATE, LAST_WILL, STATE_QOS, false));
+        if let Some((username, password)) = &self.credentials {
+            mqttoptions.set_credentials(username, password);
+        }
+
+        let (client, connection) = Client::new(mqttoptions, self.capacity);
+        (
+            MqttClient {
+                client: ClientKind::V311(client),
+                diagnostics,
+            },
+            MqttConnection::V311(connection),
+        )
+    }
 
-        // Since we can send the entire data update, lets bump up the max packet size significantly
-        mqttoptions.set_max_packet_size(10240, 10240 * 8);
+    /// Brokers that only speak 3.1.1 refuse the connect, so the first connect decides the protocol.  A broker that is
+    /// not up yet keeps MQTT 5, and the connection loop retries.
+    fn v5(self, options: V5Options, diagnostics: Arc<Diagnostics>) -> (MqttClient, MqttConnection) {
+        let mut mqttoptions = MqttOptions5::new(APPNAME, self.connection_string(), self.port);
+        mqttoptions.set_transport(Transport::Ws);
+        mqttoptions.set_max_packet_size(Some(MAX_PACKET_SIZE as u32));
+        mqttoptions.set_last_will(LastWill5::new(
+            CONNECTED_STATE,
+            LAST_WILL,
+            qos5(STATE_QOS),
+            false,
+            None,
+        ));
+        if let Some((username, password)) = &self.credentials {
+            mqttoptions.set_credentials(username, password);
+        }
 
-        match (creds.username(), creds.password()) {
-            (Some(username), Some(password)) => {
-                mqttoptions.set_credentials(username, password);
+        let (client, mut connection) = Client5::new(mqttoptions, self.capacity);
+        let limits = Arc::new(BrokerLimits::default());
+
+        let first = connection.iter().next();
+        let connected = match first {
+            Some(Ok(Event5::Incoming(Packet5::ConnAck(connack)))) => {
+                limits.connected(connack.properties.and_then(|props| props.topic_alias_max));
+                log::info!("Connected with MQTT 5");
+                true
             }
-            (None, None) => (), // No credentials needed
-            _ => return Err(MqttError::MissingCredendials),
+            Some(Err(e)) if refuses_v5(&e) => {
+                log::warn!("Broker does not support MQTT 5, falling back to 3.1.1");
+                return self.v311(diagnostics);
+            }
+            Some(Err(e)) => {
+                diagnostics.record_connection_error();
+                log::error!("Failed to connect with MQTT 5: {:?}", e);
+                false
+            }
+            _ => false,
+        };
+
+        (
+            MqttClient {
+                client: ClientKind::V5(client, V5Properties::new(options, limits.clone())),
+                diagnostics: diagnostics.clone(),
+            },
+            MqttConnection::V5 {
+                connection,
+                limits,
+                diagnostics,
+                connected,
+            },
+        )
+    }
+}
+
+fn v311_event(event: Result<Event, ConnectionError>) -> MqttEvent {
+    match event {
+        Ok(Event::Incoming(Packet::ConnAck(_))) => MqttEvent::Connected,
+        Ok(Event::Incoming(Packet::Publish(publish))) => {
+            MqttEvent::Publish(publish.topic, publish.payload.to_vec())
         }
+        Ok(_) => MqttEvent::Other,
+        Err(e) => MqttEvent::Error(format!("{:?}", e)),
+    }
+}
 
-        let (client, connection) = Client::new(mqttoptions, 10);
-        Ok((MqttClient(client), connection))
+/// MQTT 5 acks carry reason codes, so failed publishes and subscriptions are logged.  A refused connect shows its
+/// reason code in the error.
+fn v5_event(
+    event: Result<Event5, ConnectionError5>,
+    limits: &BrokerLimits,
+    diagnostics: &Diagnostics,
+) -> MqttEvent {
+    match event {
+        Ok(Event5::Incoming(Packet5::ConnAck(connack))) => {
+            limits.connected(connack.properties.and_then(|props| props.topic_alias_max));
+            MqttEvent::Connected
+        }
+        Ok(Event5::Incoming(Packet5::Publish(publish))) => MqttEvent::Publish(
+            String::from_utf8_lossy(&publish.topic).to_string(),
+            publish.payload.to_vec(),
+        ),
+        Ok(Event5::Incoming(Packet5::PubAck(ack))) => {
+            if !matches!(
+                ack.reason,
+                PubAckReason::Success | PubAckReason::NoMatchingSubscribers
+            ) {
+                diagnostics.record_publish_error();
+                log::error!("Broker rejected a message: {:?}", ack.reason);
+            }
+            MqttEvent::Other
+        }
+        Ok(Event5::Incoming(Packet5::SubAck(ack))) => {
+            for code in ack.return_codes.iter() {
+                if !matches!(code, SubscribeReasonCode::Success(_)) {
+                    log::error!("Broker rejected a subscription: {:?}", code);
+                }
+            }
+            MqttEvent::Other
+        }
+        Ok(Event5::Incoming(Packet5::Disconnect(disconnect))) => {
+            log::warn!("Broker disconnected: {:?}", disconnect.reason_code);
+            MqttEvent::Other
+        }
+        Ok(_) => MqttEvent::Other,
+        Err(e) => MqttEvent::Error(format!("{:?}", e)),
     }
 }
 
-impl MqttClient {
-    pub fn publish_value(&mut self, topic: &str, payload: &impl Serialize) {
-        if let Ok(payload) = serde_json::to_vec(payload) {
-            if let Err(e) = self.0.publish(topic, QoS::AtMostOnce, false, payload) {
-                log::error!("Failed to publish message for {}: {:?}", topic, e);
+/// How a publish went, for either protocol.
+enum PublishResult {
+    Sent,
+    Dropped,
+    Failed(String),
+}
+
+impl PublishResult {
+    fn record(self, diagnostics: &Diagnostics, topic: &str, size: usize) {
+        match self {
+            PublishResult::Sent => diagnostics.record_publish(size),
+            PublishResult::Dropped => {
+                diagnostics.record_dropped();
+                log::warn!("Request channel full, dropped message for {}", topic);
+            }
+            PublishResult::Failed(e) => {
+                diagnostics.record_publish_error();
+                log::error!("Failed to publish message for {}: {}", topic, e);
             }
-        } else {
-            log::error!("Failed to serialize payload for {}", topic);
         }
     }
+}
 
-    pub fn direct_publish(&mut self, topic: &str, payload: &[u8]) {
-        if let Err(e) = self.0.publish(topic, QoS::AtMostOnce, false, payload) {
-            log::error!("Failed to publish message for {}: {:?}", topic, e);
+impl MqttClient {
+    /// Subscriptions are lost with the session, so this is called on every connect.
+    pub fn subscribe(&mut self, topic: &str) {
+        let result = match &mut self.client {
+            ClientKind::V311(client) => client
+                .try_subscribe(topic, QoS::AtLeastOnce)
+                .map_err(|e| format!("{:?}", e)),
+            ClientKind::V5(client, _) => client
+                .try_subscribe(topic, qos5(QoS::AtLeastOnce))
+                .map_err(|e| format!("{:?}", e)),
+        };
+        if let Err(e) = result {
+            log::error!("Failed to subscribe to {}: {}", topic, e);
         }
     }
 
@@ -60,28 +318,102 @@ impl MqttClient {
         }
     }
 
-    pub fn publish_discovery(&mut self, item: DiscoveryPrepPacket) {
+    /// Discovery waits for room in the request channel, since a missed entity stays missing for the session.
+    fn publish_config(&mut self, topic: &str, payload: Vec<u8>) -> PublishResult {
         //Todo revisit?  Sending these messages will happen each session.  I think for now it is okay to not retain them, especially with different cars having different vars.
         // Keeps from having orhpaned entities in HA
         let retain = false;
+
+        let result = match &mut self.client {
+            ClientKind::V311(client) => client
+                .publish(topic, DISCOVERY_QOS, retain, payload)
+                .map_err(|e| format!("{:?}", e)),
+            ClientKind::V5(client, properties) => client
+                .publish_with_properties(
+                    topic,
+                    qos5(DISCOVERY_QOS),
+                    retain,
+                    payload,
+                    properties.discovery(),
+                )
+                .map_err(|e| format!("{:?}", e)),
+        };
+        match result {
+            Ok(()) => PublishResult::Sent,
+            Err(e) => PublishResult::Failed(e),
+        }
+    }
+}
+
+impl Publisher for MqttClient {
+    /// State messages are sent with `try_publish` so a backed up event loop drops updates instead of stalling telemetry.
+    fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) {
+        let sent = match &mut self.client {
+            ClientKind::V311(client) => match client.try_publish(topic, STATE_QOS, retain, payload)
+            {
+                Ok(()) => PublishResult::Sent,
+                Err(ClientError::TryRequest(_)) => PublishResult::Dropped,
+                Err(e) => PublishResult::Failed(format!("{:?}", e)),
+            },
+            ClientKind::V5(client, properties) => {
+                let (alias_topic, publish_properties) = properties.state(topic, retain);
+                let sent = match client.try_publish_with_properties(
+                    alias_topic,
+                    qos5(STATE_QOS),
+                    retain,
+                    payload.to_vec(),
+                    publish_properties,
+                ) {
+                    Ok(()) => PublishResult::Sent,
+                    Err(ClientError5::TryRequest(_)) => PublishResult::Dropped,
+                    Err(e) => PublishResult::Failed(format!("{:?}", e)),
+                };
+                // The broker never saw the topic for this alias
+                if !matches!(sent, PublishResult::Sent) {
+                    properties.forget(topic);
+                }
+                sent
+            }
+        };
+        sent.record(&self.diagnostics, topic, payload.len());
+    }
+
+    fn publish_discovery(&mut self, item: DiscoveryPrepPacket) {
         let (topic, ser_result) = item;
 
         match ser_result {
             Ok(payload) => {
-                if let Err(e) = self.0.publish(&topic, QoS::AtLeastOnce, retain, payload) {
-                    log::error!("Failed to publish discovery message for {}: {:?}", topic, e);
-                }
+                let size = payload.len();
+                self.publish_config(&topic, payload)
+                    .record(&self.diagnostics, &topic, size);
             }
             Err(_) => {
+                self.diagnostics.record_publish_error();
                 log::error!("Failed to serialize payload for {}", topic);
             }
         }
     }
+
+    fn remove_discovery(&mut self, topic: &str) {
+        self.publish_config(topic, Vec::new())
+            .record(&self.diagnostics, topic, 0);
+    }
+
+    fn session_id(&mut self, session_id: Option<String>) {
+        if let ClientKind::V5(_, properties) = &mut self.client {
+            properties.session(session_id);
+        }
+    }
+
+    fn diagnostics(&self) -> &Diagnostics {
+        &self.diagnostics
+    }
 }
 
 struct MqttBroker {
     host: String,
     port: u16,
+    version: MqttVersion,
 }
 
 impl MqttBroker {
@@ -91,7 +423,15 @@ impl MqttBroker {
             .unwrap_or("1884".to_string())
             .parse::<u16>()
             .map_err(|_| MqttError::InvalidPort)?;
-        Ok(Self { host, port })
+        let version = match std::env::var("MQTT_VERSION") {
+            Ok(version) => MqttVersion::parse(&version).ok_or(MqttError::InvalidVersion)?,
+            Err(_) => MqttVersion::V311,
+        };
+        Ok(Self {
+            host,
+            port,
+            version,
+        })
     }
 }
 struct MqttCredentials {
@@ -0,0 +1,168 @@
+use serde::Serialize;
+use std::fmt;
+
+/// Version of the payloads the bridge sends.  Bumped when a state payload changes shape.  The first byte of binary
+/// payloads, and the `schema_version` user property with MQTT 5.
+pub(crate) const SCHEMA_VERSION: u8 = 1;
+
+/// zstd level for compressed JSON.  Low, since telemetry is compressed twice a second.
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 3;
+
+/// How a state payload is encoded.  Topics HA reads, including the per-var and group topics, are always JSON.  Only
+/// the full telemetry map and the session can be sent in a compact encoding for other consumers, eg dashboards.
+///
+/// Anything but JSON starts with a two byte header: the schema version, then the encoding's id.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Encoding {
+    Json,
+    Cbor,
+    MessagePack,
+    /// Only with the `zstd` feature
+    #[cfg(feature = "zstd")]
+    ZstdJson,
+}
+
+impl Encoding {
+    pub fn parse(encoding: &str) -> Option<Self> {
+        match encoding.trim() {
+            "json" => Some(Encoding::Json),
+            "cbor" => Some(Encoding::Cbor),
+            "msgpack" | "messagepack" => Some(Encoding::MessagePack),
+            #[cfg(feature = "zstd")]
+            "zstd" | "zstd-json" => Some(Encoding::ZstdJson),
+            _ => None,
+        }
+    }
+
+    /// Reads the encoding from `env`, eg `TELEMETRY_ENCODING=cbor`.  Defaults to JSON.
+    pub fn from_env(env: &str) -> Self {
+        match std::env::var(env) {
+            Ok(encoding) => Self::parse(&encoding).unwrap_or_else(|| {
+                log::error!("Invalid {} {}, using json", env, encoding);
+                Encoding::Json
+            }),
+            Err(_) => Encoding::Json,
+        }
+    }
+
+    /// The second byte of the header
+    fn id(&self) -> u8 {
+        match self {
+            Encoding::Json => 0,
+            Encoding::Cbor => 1,
+            Encoding::MessagePack => 2,
+            #[cfg(feature = "zstd")]
+            Encoding::ZstdJson => 3,
+        }
+    }
+
+    pub fn encode(&self, payload: &impl Serialize) -> Result<Vec<u8>, EncodingError> {
+        if *self == Encoding::Json {
+            return serde_json::to_vec(payload).map_err(EncodingError::Json);
+        }
+
+        let mut encoded = vec![SCHEMA_VERSION, self.id()];
+        match self {
+            Encoding::Json => (),
+            Encoding::Cbor => ciborium::into_writer(payload, &mut encoded)
+                .map_err(|e| EncodingError::Cbor(e.to_string()))?,
+            Encoding::MessagePack => {
+                // Named fields, so maps can be read back without knowing the struct
+                let mut serializer = rmp_serde::Serializer::new(&mut encoded).with_struct_map();
+                payload
+                    .serialize(&mut serializer)
+                    .map_err(EncodingError::MessagePack)?
+            }
+            #[cfg(feature = "zstd")]
+            Encoding::ZstdJson => {
+                let json = serde_json::to_vec(payload).map_err(EncodingError::Json)?;
+                let compressed =
+                    zstd::encode_all(json.as_slice(), ZSTD_LEVEL).map_err(EncodingError::Zstd)?;
+                encoded.extend(compressed);
+            }
+        }
+        Ok(encoded)
+    }
+}
+
+pub(crate) enum EncodingError {
+    Json(serde_json::Error),
+    Cbor(String),
+    MessagePack(rmp_serde::encode::Error),
+    #[cfg(feature = "zstd")]
+    Zstd(std::io::Error),
+}
+
+impl fmt::Display for EncodingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncodingError::Json(e) => write!(f, "Failed to encode JSON: {}", e),
+            EncodingError::Cbor(e) => write!(f, "Failed to encode CBOR: {}", e),
+            EncodingError::MessagePack(e) => write!(f, "Failed to encode MessagePack: {}", e),
+            #[cfg(feature = "zstd")]
+            EncodingError::Zstd(e) => write!(f, "Failed to compress JSON: {}", e),
+        }
+    }
+}
+
+impl fmt::Debug for EncodingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for EncodingError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn telemetry() -> Value {
+        json!({ "Speed": 41.2, "Gear": 3, "OnPitRoad": false, "SessionFlags": ["Green Flag"] })
+    }
+
+    #[test]
+    fn should_parse_encodings() {
+        assert_eq!(Encoding::parse("cbor"), Some(Encoding::Cbor));
+        assert_eq!(Encoding::parse("msgpack"), Some(Encoding::MessagePack));
+        assert_eq!(Encoding::parse("json"), Some(Encoding::Json));
+        assert_eq!(Encoding::parse("xml"), None);
+    }
+
+    #[test]
+    fn should_send_json_without_header() {
+        let encoded = Encoding::Json.encode(&telemetry()).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Value>(&encoded).unwrap(),
+            telemetry()
+        );
+    }
+
+    #[test]
+    fn should_round_trip_binary_encodings() {
+        let encoded = Encoding::Cbor.encode(&telemetry()).unwrap();
+        assert_eq!(encoded[..2], [SCHEMA_VERSION, 1]);
+        let decoded: Value = ciborium::from_reader(&encoded[2..]).unwrap();
+        assert_eq!(decoded, telemetry());
+
+        let encoded = Encoding::MessagePack.encode(&telemetry()).unwrap();
+        assert_eq!(encoded[..2], [SCHEMA_VERSION, 2]);
+        let decoded: Value = rmp_serde::from_slice(&encoded[2..]).unwrap();
+        assert_eq!(decoded, telemetry());
+
+        // Smaller than the JSON it replaces
+        let json = Encoding::Json.encode(&telemetry()).unwrap();
+        assert!(encoded.len() < json.len());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn should_round_trip_zstd_json() {
+        let encoded = Encoding::ZstdJson.encode(&telemetry()).unwrap();
+        assert_eq!(encoded[..2], [SCHEMA_VERSION, 3]);
+        let json = zstd::decode_all(&encoded[2..]).unwrap();
+        assert_eq!(serde_json::from_slice::<Value>(&json).unwrap(), telemetry());
+    }
+}
@@ -4,6 +4,7 @@ pub(crate) enum MqttError {
     MissingCredendials,
     MissingBrokerHost,
     InvalidPort,
+    InvalidVersion,
 }
 
 impl fmt::Display for MqttError {
@@ -12,6 +13,7 @@ impl fmt::Display for MqttError {
             MqttError::MissingCredendials => write!(f, "Missing MQTT credentials"),
             MqttError::MissingBrokerHost => write!(f, "Missing MQTT broker host"),
             MqttError::InvalidPort => write!(f, "Invalid MQTT port"),
+            MqttError::InvalidVersion => write!(f, "Invalid MQTT version, use 3.1.1 or 5"),
         }
     }
 }
@@ -22,6 +24,7 @@ impl fmt::Debug for MqttError {
             MqttError::MissingCredendials => write!(f, "Missing MQTT credentials"),
             MqttError::MissingBrokerHost => write!(f, "Missing MQTT broker host"),
             MqttError::InvalidPort => write!(f, "Invalid MQTT port"),
+            MqttError::InvalidVersion => write!(f, "Invalid MQTT version, use 3.1.1 or 5"),
         }
     }
 }
@@ -0,0 +1,152 @@
+use rumqttc::QoS;
+use serde::Serialize;
+#[cfg(test)]
+use std::sync::Arc;
+
+use super::client::DiscoveryPrepPacket;
+use super::encoding::Encoding;
+use crate::diagnostics::Diagnostics;
+
+/// State messages are fire and forget, a missed one is replaced by the next.
+pub(crate) const STATE_QOS: QoS = QoS::AtMostOnce;
+
+/// Discovery and removals must reach HA, or the entities are wrong until the next session.
+pub(crate) const DISCOVERY_QOS: QoS = QoS::AtLeastOnce;
+
+/// Where the bridge sends its messages.  `MqttClient` sends them to the broker, `MemoryPublisher` keeps them so tests
+/// can check what would have been sent.
+pub(crate) trait Publisher {
+    /// Sends a state message, with the choice to retain, eg for rule actions.
+    fn publish(&mut self, topic: &str, payload: &[u8], retain: bool);
+
+    fn publish_discovery(&mut self, item: DiscoveryPrepPacket);
+
+    /// An empty config message removes the entity from HA.
+    fn remove_discovery(&mut self, topic: &str);
+
+    /// The iRacing subsession, for protocols that can tag messages with it.
+    fn session_id(&mut self, _session_id: Option<String>) {}
+
+    fn diagnostics(&self) -> &Diagnostics;
+
+    fn direct_publish(&mut self, topic: &str, payload: &[u8]) {
+        self.publish(topic, payload, false);
+    }
+
+    fn publish_value(&mut self, topic: &str, payload: &impl Serialize) {
+        self.publish_encoded(topic, payload, Encoding::Json);
+    }
+
+    fn publish_encoded(&mut self, topic: &str, payload: &impl Serialize, encoding: Encoding) {
+        match encoding.encode(payload) {
+            Ok(payload) => self.direct_publish(topic, &payload),
+            Err(e) => {
+                self.diagnostics().record_publish_error();
+                log::error!("Failed to serialize payload for {}: {}", topic, e);
+            }
+        }
+    }
+}
+
+/// A message as the broker would have received it.
+#[cfg(test)]
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Published {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: QoS,
+    pub retain: bool,
+}
+
+#[cfg(test)]
+impl Published {
+    /// One line for snapshots, eg `0 hairmqtt/connected connected`.  Retained messages are marked with `r`.
+    pub fn line(&self) -> String {
+        format!(
+            "{}{} {} {}",
+            self.qos as u8,
+            if self.retain { "r" } else { "" },
+            self.topic,
+            String::from_utf8_lossy(&self.payload)
+        )
+    }
+}
+
+/// Records everything published, in order.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct MemoryPublisher {
+    pub published: Vec<Published>,
+    diagnostics: Arc<Diagnostics>,
+}
+
+#[cfg(test)]
+impl MemoryPublisher {
+    fn record(&mut self, topic: &str, payload: Vec<u8>, qos: QoS, retain: bool) {
+        self.diagnostics.record_publish(payload.len());
+        self.published.push(Published {
+            topic: topic.to_string(),
+            payload,
+            qos,
+            retain,
+        });
+    }
+
+    /// Everything published since the last call
+    pub fn take(&mut self) -> Vec<Published> {
+        std::mem::take(&mut self.published)
+    }
+}
+
+#[cfg(test)]
+impl Publisher for MemoryPublisher {
+    fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) {
+        self.record(topic, payload.to_vec(), STATE_QOS, retain);
+    }
+
+    fn publish_discovery(&mut self, item: DiscoveryPrepPacket) {
+        let (topic, payload) = item;
+        match payload {
+            Ok(payload) => self.record(&topic, payload, DISCOVERY_QOS, false),
+            Err(_) => self.diagnostics.record_publish_error(),
+        }
+    }
+
+    fn remove_discovery(&mut self, topic: &str) {
+        self.record(topic, Vec::new(), DISCOVERY_QOS, false);
+    }
+
+    fn diagnostics(&self) -> &Diagnostics {
+        &self.diagnostics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn should_record_qos_and_retain() {
+        let mut publisher = MemoryPublisher::default();
+        publisher.publish_value("hairmqtt/telemetry/Speed", &json!({ "Speed": 41.2 }));
+        publisher.publish("hairmqtt/rules/pit", b"on", true);
+        publisher.publish_discovery((
+            "homeassistant/sensor/speed/config".to_string(),
+            Ok(b"{}".to_vec()),
+        ));
+        publisher.remove_discovery("homeassistant/sensor/rpm/config");
+
+        let lines: Vec<String> = publisher.take().iter().map(Published::line).collect();
+        assert_eq!(
+            lines,
+            vec![
+                r#"0 hairmqtt/telemetry/Speed {"Speed":41.2}"#,
+                "0r hairmqtt/rules/pit on",
+                "1 homeassistant/sensor/speed/config {}",
+                "1 homeassistant/sensor/rpm/config ",
+            ]
+        );
+        assert!(publisher.published.is_empty());
+    }
+}
@@ -0,0 +1,302 @@
+use rumqttc::v5::mqttbytes::v5::{ConnectReturnCode, PublishProperties};
+use rumqttc::v5::mqttbytes::{Error as MqttBytesError, QoS as QoS5};
+use rumqttc::v5::{ConnectionError, StateError};
+use rumqttc::QoS;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use super::encoding::SCHEMA_VERSION;
+
+/// State messages older than this are dropped by the broker rather than delivered late.
+const DEFAULT_MESSAGE_EXPIRY: u32 = 60;
+
+/// Topics given an alias, at most.  The broker may allow fewer.
+const DEFAULT_TOPIC_ALIASES: u16 = 64;
+
+/// The protocol to talk to the broker with.  `MQTT_VERSION=5` opts in to MQTT 5, which falls back to 3.1.1 if the
+/// broker does not support it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum MqttVersion {
+    V311,
+    V5,
+}
+
+impl MqttVersion {
+    pub fn parse(version: &str) -> Option<Self> {
+        match version.trim() {
+            "3" | "3.1.1" | "4" => Some(MqttVersion::V311),
+            "5" | "5.0" => Some(MqttVersion::V5),
+            _ => None,
+        }
+    }
+}
+
+/// MQTT 5 publishing options.
+///
+/// `MQTT_MESSAGE_EXPIRY` is the expiry of state messages in seconds, 0 to turn it off.  `MQTT_TOPIC_ALIASES` is how
+/// many topics get an alias, 0 to turn them off.  `RIG_ID` is sent as a user property, to tell rigs apart on a shared
+/// broker.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct V5Options {
+    pub message_expiry: Option<u32>,
+    pub topic_aliases: u16,
+    pub rig_id: Option<String>,
+}
+
+impl V5Options {
+    pub fn from_env() -> Self {
+        let message_expiry = std::env::var("MQTT_MESSAGE_EXPIRY")
+            .ok()
+            .and_then(|expiry| expiry.parse::<u32>().ok())
+            .unwrap_or(DEFAULT_MESSAGE_EXPIRY);
+        let topic_aliases = std::env::var("MQTT_TOPIC_ALIASES")
+            .ok()
+            .and_then(|aliases| aliases.parse::<u16>().ok())
+            .unwrap_or(DEFAULT_TOPIC_ALIASES);
+
+        Self {
+            message_expiry: Some(message_expiry).filter(|expiry| *expiry > 0),
+            topic_aliases,
+            rig_id: std::env::var("RIG_ID").ok(),
+        }
+    }
+}
+
+/// What the broker allows, from its last connack.  Shared by the connection loop, which sees the connack, and the
+/// clients that publish.
+#[derive(Default)]
+pub(crate) struct BrokerLimits {
+    topic_alias_max: AtomicU16,
+    connects: AtomicU64,
+}
+
+impl BrokerLimits {
+    /// Aliases do not survive a reconnect, so every connect starts them over.
+    pub fn connected(&self, topic_alias_max: Option<u16>) {
+        self.topic_alias_max
+            .store(topic_alias_max.unwrap_or(0), Ordering::Relaxed);
+        self.connects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn topic_alias_max(&self) -> u16 {
+        self.topic_alias_max.load(Ordering::Relaxed)
+    }
+
+    fn connects(&self) -> u64 {
+        self.connects.load(Ordering::Relaxed)
+    }
+}
+
+/// Builds the properties of each publish: expiry, user properties and topic aliases.  Topics get an alias the first
+/// time they are published, until the aliases run out.  Later publishes send the alias with an empty topic.
+#[derive(Clone)]
+pub(crate) struct V5Properties {
+    options: V5Options,
+    limits: Arc<BrokerLimits>,
+    session_id: Option<String>,
+    aliases: HashMap<String, u16>,
+    /// `BrokerLimits::connects` the aliases were given for
+    connects: u64,
+}
+
+impl V5Properties {
+    pub fn new(options: V5Options, limits: Arc<BrokerLimits>) -> Self {
+        Self {
+            options,
+            limits,
+            session_id: None,
+            aliases: HashMap::new(),
+            connects: 0,
+        }
+    }
+
+    /// The iRacing subsession, sent as the `session_id` user property.
+    pub fn session(&mut self, session_id: Option<String>) {
+        self.session_id = session_id;
+    }
+
+    /// Topic and properties for a state message.  Retained messages, such as rule actions, do not expire.
+    pub fn state(&mut self, topic: &str, retain: bool) -> (String, PublishProperties) {
+        let (topic, topic_alias) = self.alias(topic);
+        let properties = PublishProperties {
+            message_expiry_interval: self.options.message_expiry.filter(|_| !retain),
+            topic_alias,
+            ..self.discovery()
+        };
+        (topic, properties)
+    }
+
+    /// Discovery is for HA, so only carries the user properties.
+    pub fn discovery(&self) -> PublishProperties {
+        let mut user_properties = vec![("schema_version".to_string(), SCHEMA_VERSION.to_string())];
+        if let Some(rig_id) = &self.options.rig_id {
+            user_properties.push(("rig_id".to_string(), rig_id.clone()));
+        }
+        if let Some(session_id) = &self.session_id {
+            user_properties.push(("session_id".to_string(), session_id.clone()));
+        }
+
+        PublishProperties {
+            user_properties,
+            ..Default::default()
+        }
+    }
+
+    /// A publish that set up an alias did not go out, so the broker does not know the alias.
+    pub fn forget(&mut self, topic: &str) {
+        self.aliases.remove(topic);
+    }
+
+    fn alias(&mut self, topic: &str) -> (String, Option<u16>) {
+        if self.connects != self.limits.connects() {
+            self.connects = self.limits.connects();
+            self.aliases.clear();
+        }
+
+        if let Some(alias) = self.aliases.get(topic) {
+            return (String::new(), Some(*alias));
+        }
+
+        // Forgotten aliases leave gaps, so the lowest free one is used
+        let max = self
+            .options
+            .topic_aliases
+            .min(self.limits.topic_alias_max());
+        let free = (1..=max).find(|alias| !self.aliases.values().any(|used| used == alias));
+        if let Some(alias) = free {
+            self.aliases.insert(topic.to_string(), alias);
+        }
+        (topic.to_string(), free)
+    }
+}
+
+pub(crate) fn qos(qos: QoS) -> QoS5 {
+    match qos {
+        QoS::AtMostOnce => QoS5::AtMostOnce,
+        QoS::AtLeastOnce => QoS5::AtLeastOnce,
+        QoS::ExactlyOnce => QoS5::ExactlyOnce,
+    }
+}
+
+/// The first connect failed because the broker does not speak MQTT 5, rather than for any other reason.  A 3.1.1
+/// broker answers with its own return code 1, which is not an MQTT 5 reason code.
+pub(crate) fn refuses_v5(error: &ConnectionError) -> bool {
+    matches!(
+        error,
+        ConnectionError::ConnectionRefused(ConnectReturnCode::UnsupportedProtocolVersion)
+            | ConnectionError::MqttState(StateError::Deserialization(
+                MqttBytesError::InvalidConnectReturnCode(1)
+            ))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> V5Options {
+        V5Options {
+            message_expiry: Some(10),
+            topic_aliases: 2,
+            rig_id: Some("rig-1".to_string()),
+        }
+    }
+
+    #[test]
+    fn should_only_fall_back_when_v5_is_refused() {
+        assert!(refuses_v5(&ConnectionError::ConnectionRefused(
+            ConnectReturnCode::UnsupportedProtocolVersion
+        )));
+        assert!(refuses_v5(&ConnectionError::MqttState(
+            StateError::Deserialization(MqttBytesError::InvalidConnectReturnCode(1))
+        )));
+
+        assert!(!refuses_v5(&ConnectionError::ConnectionRefused(
+            ConnectReturnCode::NotAuthorized
+        )));
+        assert!(!refuses_v5(&ConnectionError::MqttState(
+            StateError::Deserialization(MqttBytesError::PayloadTooLong)
+        )));
+        assert!(!refuses_v5(&ConnectionError::MqttState(
+            StateError::AwaitPingResp
+        )));
+    }
+
+    #[test]
+    fn should_parse_versions() {
+        assert_eq!(MqttVersion::parse("5"), Some(MqttVersion::V5));
+        assert_eq!(MqttVersion::parse("3.1.1"), Some(MqttVersion::V311));
+        assert_eq!(MqttVersion::parse("6"), None);
+    }
+
+    #[test]
+    fn should_alias_topics_within_broker_limit() {
+        let limits = Arc::new(BrokerLimits::default());
+        let mut properties = V5Properties::new(options(), limits.clone());
+
+        // The broker did not allow aliases
+        limits.connected(None);
+        let (topic, state) = properties.state("hairmqtt/telemetry", false);
+        assert_eq!(topic, "hairmqtt/telemetry");
+        assert_eq!(state.topic_alias, None);
+
+        // Fewer than the broker allows, when configured so
+        limits.connected(Some(10));
+        let (_, state) = properties.state("hairmqtt/telemetry", false);
+        assert_eq!(state.topic_alias, Some(1));
+        let (topic, state) = properties.state("hairmqtt/telemetry", false);
+        assert_eq!((topic.as_str(), state.topic_alias), ("", Some(1)));
+        assert_eq!(
+            properties.state("hairmqtt/weather", false).1.topic_alias,
+            Some(2)
+        );
+        assert_eq!(properties.state("hairmqtt/sun", false).1.topic_alias, None);
+
+        // A dropped publish gives its alias back
+        properties.forget("hairmqtt/telemetry");
+        assert_eq!(
+            properties.state("hairmqtt/sun", false).1.topic_alias,
+            Some(1)
+        );
+        assert_eq!(
+            properties.state("hairmqtt/weather", false).1.topic_alias,
+            Some(2)
+        );
+
+        // Reconnecting starts over
+        limits.connected(Some(10));
+        let (topic, state) = properties.state("hairmqtt/sun", false);
+        assert_eq!(
+            (topic.as_str(), state.topic_alias),
+            ("hairmqtt/sun", Some(1))
+        );
+    }
+
+    #[test]
+    fn should_expire_state_but_not_retained_or_discovery() {
+        let mut properties = V5Properties::new(options(), Arc::new(BrokerLimits::default()));
+        properties.session(Some("71234567".to_string()));
+
+        let (_, state) = properties.state("hairmqtt/telemetry", false);
+        assert_eq!(state.message_expiry_interval, Some(10));
+        assert_eq!(
+            properties
+                .state("hairmqtt/rules/pit", true)
+                .1
+                .message_expiry_interval,
+            None
+        );
+
+        let discovery = properties.discovery();
+        assert_eq!(discovery.message_expiry_interval, None);
+        assert_eq!(
+            discovery.user_properties,
+            vec![
+                ("schema_version".to_string(), SCHEMA_VERSION.to_string()),
+                ("rig_id".to_string(), "rig-1".to_string()),
+                ("session_id".to_string(), "71234567".to_string()),
+            ]
+        );
+    }
+}
@@ -1,30 +1,84 @@
+use bridge::Bridge;
+use commands::{Commands, COMMAND_SUBSCRIPTION};
+use config::entities::{EntityConfig, EntityKind, TelemetryEntity};
+use devices::Devices;
+use diagnostics::Diagnostics;
 use dotenvy::dotenv;
 use entity_builders::BinarySensorBuilder;
 use entity_builders::SensorBuilder;
 use ha_mqtt::components::binary_sensor::BinarySensor;
-use ha_mqtt::components::sensor::SensorClass;
-use ha_mqtt::device::Device;
 use ha_mqtt::discoverable::Discoverable;
 use ir_telemetry::client::UpdatePacket;
 use ir_telemetry::mapped_file::var_header::VarHeader;
 use ir_telemetry::Client as IracingClient;
 use ir_telemetry::IrData;
 use ir_telemetry::Session;
-use irmqtt::client::DiscoveryPrepPacket;
+use irmqtt::client::{DiscoveryPrepPacket, MqttEvent};
+use pit_plan::PitPlanner;
+use recording::{RecordEntry, VarDescription, VarInfo};
 use serde::Serialize;
 use serde_json::{Map, Value};
+use session_path::PathContext;
+use sinks::api::ApiSink;
+use sinks::csv_log::CsvSink;
+use sinks::influx::InfluxSink;
+use sinks::metrics::MetricsSink;
+use sinks::recording::RecordingSink;
+use sinks::sink::{Sink, ThrottledSink};
 use std::collections::HashMap;
-use std::time::Duration;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 const VERSION: Option<&str> = option_env!("CARGO_PKG_VERSION");
 const TELEMETRY_STATE: &str = "hairmqtt/telemetry";
 const SESSION_STATE: &str = "hairmqtt/session";
-
+const SESSION_ENTITIES_STATE: &str = "hairmqtt/session/entities";
+/// `connected` or `disconnected` from iRacing, and `disconnected` as the last will if the bridge goes away.
+const CONNECTED_STATE: &str = "hairmqtt/connected";
+
+pub(crate) mod bridge;
+#[cfg(test)]
+mod bridge_tests;
+pub(crate) mod broadcast;
+pub(crate) mod cli;
+pub(crate) mod commands;
+pub(crate) mod config {
+    pub(crate) mod entities;
+    pub(crate) mod error;
+    pub(crate) mod validate;
+    pub(crate) mod watcher;
+}
 pub(crate) mod irmqtt {
     pub(crate) mod client;
+    pub(crate) mod encoding;
     pub(crate) mod error;
+    pub(crate) mod publisher;
+    pub(crate) mod v5;
 }
+pub(crate) mod devices;
+pub(crate) mod diagnostics;
+#[cfg(test)]
+mod discovery_tests;
 pub(crate) mod entity_builders;
+pub(crate) mod groups;
+pub(crate) mod inputs;
+pub(crate) mod pit_plan;
+pub(crate) mod recording;
+pub(crate) mod rules;
+pub(crate) mod scheduler;
+pub(crate) mod scripting;
+pub(crate) mod session_path;
+pub(crate) mod sinks {
+    pub(crate) mod api;
+    pub(crate) mod csv_log;
+    pub(crate) mod influx;
+    pub(crate) mod metrics;
+    pub(crate) mod recording;
+    pub(crate) mod sink;
+}
+pub(crate) mod sun;
+pub(crate) mod weather;
 
 fn main() {
     pretty_env_logger::init_timed();
@@ -32,257 +86,224 @@ fn main() {
         log::debug!("Did not find .env file");
     }
 
+    match cli::Command::parse(std::env::args().skip(1)) {
+        Ok(None) => (),
+        Ok(Some(command)) => std::process::exit(command.run()),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    }
+
     log::info!("Starting iracing telemetry to mqtt bridge");
 
-    // Update 2 times a second.  This is reasonable for this application
-    let telemetry = IracingClient::connect(2.);
+    // Sampled faster than most entities need, so that fast entities are possible.  The scheduler decides what is sent.
+    let rate = std::env::var("TELEMETRY_RATE")
+        .ok()
+        .and_then(|rate| rate.parse::<f64>().ok())
+        .unwrap_or(20.);
+    let telemetry = IracingClient::connect(rate);
+
+    let entity_config = match EntityConfig::load() {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("{}, using the built in entities", e);
+            EntityConfig::default_entities()
+        }
+    };
 
-    let (mut client, mut connection) = irmqtt::client::MqttConnection::connect().unwrap();
+    let diagnostics = Arc::new(Diagnostics::default());
+    let (mut client, mut connection) =
+        irmqtt::client::MqttConnection::connect(diagnostics.clone()).unwrap();
+
+    // Outputs alongside mqtt.  Each one is enabled through its own env vars.
+    let mut sinks: Vec<Box<dyn Sink + Send>> = Vec::new();
+    if let Some(metrics) = MetricsSink::from_env(diagnostics.clone()) {
+        sinks.push(ThrottledSink::from_env(metrics, "METRICS_INTERVAL"));
+    }
+    if let Some(api) = ApiSink::from_env() {
+        sinks.push(ThrottledSink::from_env(api, "API_INTERVAL"));
+    }
+    if let Some(influx) = InfluxSink::from_env() {
+        sinks.push(ThrottledSink::from_env(influx, "INFLUX_INTERVAL"));
+    }
+    if let Some(csv) = CsvSink::from_env() {
+        sinks.push(ThrottledSink::from_env(csv, "CSV_INTERVAL"));
+    }
+    if let Some(recording) = RecordingSink::from_env() {
+        sinks.push(ThrottledSink::from_env(recording, "RECORDING_INTERVAL"));
+    }
+
+    // Incoming publishes on the command topics, handed from the connection loop to the telemetry thread.  The
+    // telemetry thread knows the session, which some commands need.
+    let mut subscriber = client.clone();
+    let (command_tx, command_rx) = mpsc::channel::<(String, Vec<u8>)>();
+    let commands = Commands::new(broadcast::sender_from_env(), PitPlanner::from_env());
+    let mut bridge = Bridge::from_env(entity_config, commands, sinks);
 
     std::thread::spawn(move || {
-        // Common device.  This groups everything in HA under one device.
-        let device = Device::new()
-            .with_name("Iracing Telemetry")
-            .with_manufacturer("Tim Reed")
-            .with_sw_version(VERSION.unwrap_or("unavailable"))
-            .with_identifiers(vec!["hairmqtt".to_string()]);
+        bridge.start(&mut client);
 
+        // Kept to read the values out of the data packets.  The bridge gets the same vars as recordings have them.
         let mut var_headers: HashMap<String, VarHeader> = HashMap::new();
 
-        // Session discovery packet is only sent once per session.
-        let mut session_discory_sent: bool = false;
-
         for packet in telemetry {
-            match packet {
+            let now = Instant::now();
+            while let Ok((topic, payload)) = command_rx.try_recv() {
+                bridge.command(&topic, &payload);
+            }
+
+            let entry = match packet {
                 UpdatePacket::Data(data) => {
-                    client.direct_publish("hairmqtt/connected", "connected".as_bytes());
-                    let payload = handle_data(&data, &var_headers);
-                    client.publish_value(TELEMETRY_STATE, &payload);
+                    RecordEntry::Telemetry(handle_data(&data, &var_headers))
                 }
-
                 UpdatePacket::SessionInfo(session) => {
                     let session: Session = serde_yaml::from_str(&session).unwrap();
-                    if !session_discory_sent {
-                        let entities = session_discovery_packet(&session, &device);
-                        for entity in entities.into_iter() {
-                            client.publish_discovery(entity);
-                        }
-                        session_discory_sent = true;
-                        log::trace!("Session Discovery sent");
-                    }
-
-                    let payload = handle_session(&session);
-                    client.publish_value(SESSION_STATE, &payload);
-
-                    log::trace!("Session Info updated");
+                    RecordEntry::Session(handle_session(&session))
                 }
-
-                // Clears session specific data
                 UpdatePacket::NotConnected => {
                     var_headers.clear();
-                    session_discory_sent = false;
-
-                    client.direct_publish("hairmqtt/connected", "disconnected".as_bytes());
-                    log::trace!("Ir-telemetry is not connected");
+                    RecordEntry::Disconnected
                 }
-
                 // This update packet should only be recieved when the race session loads.
                 UpdatePacket::VariableHeaders(var_header) => {
                     var_headers = var_header;
-
-                    let entities = discovery_packet(&var_headers, &device);
-                    for entity in entities.into_iter() {
-                        client.publish_discovery(entity);
-                    }
-                    log::trace!("Updated Variable Headers");
+                    RecordEntry::Variables(VarInfo::from_headers(&var_headers))
                 }
                 _ => {
                     // UpdatePacket is a non-exhaustive enum.  This is a catch all for any new packet types.
                     log::info!("Ir_telemetry has been updated to send a new packet type and this type has not been processed");
+                    continue;
                 }
-            }
+            };
+            bridge.handle(&mut client, entry, now);
         }
     });
 
     // Need to loop over connection to move the event loop along
-    for msg in connection.iter() {
-        if let Err(error) = msg {
-            log::error!("Error: {:?}", error);
-            std::thread::sleep(Duration::from_secs(10));
+    for event in connection.events() {
+        match event {
+            MqttEvent::Connected => {
+                diagnostics.record_connect();
+                subscriber.subscribe(COMMAND_SUBSCRIPTION);
+            }
+            MqttEvent::Publish(topic, payload) => {
+                if command_tx.send((topic, payload)).is_err() {
+                    log::error!("Telemetry thread stopped, dropping command");
+                }
+            }
+            MqttEvent::Other => (),
+            MqttEvent::Error(error) => {
+                diagnostics.record_connection_error();
+                log::error!("Error: {}", error);
+                std::thread::sleep(Duration::from_secs(10));
+            }
         }
     }
 }
 
-/// Creates a list of discoverable entities from the telemetry data.  Bit long and could be refactored.
-fn discovery_packet(
-    var_headers: &HashMap<String, VarHeader>,
-    device: &Device,
+/// Creates a list of discoverable entities from the telemetry entities for the car.  Entities whose var is not in the
+/// variable headers are skipped.
+fn discovery_packet<V: VarDescription>(
+    var_headers: &HashMap<String, V>,
+    devices: &Devices,
+    entities: &[TelemetryEntity],
+    config: &EntityConfig,
 ) -> Vec<DiscoveryPrepPacket> {
     let mut discoverables: Vec<DiscoveryPrepPacket> = Vec::new();
+    let default_interval = config.default_interval();
+
+    for entity in entities.iter() {
+        let Some(var) = var_headers.get(&entity.var) else {
+            continue;
+        };
+        let device = devices.get(entity.device);
+        let state_topic = telemetry_topic(&entity.var);
+
+        // Fields the builders do not have.  `expire_after` is tied to the entity's heartbeat.
+        let mut extra = vec![(
+            "expire_after",
+            Value::from(entity.expire_after(default_interval)),
+        )];
+        if let Some(device_class) = &entity.device_class {
+            extra.push(("device_class", Value::from(device_class.as_str())));
+        }
+        if let Some(state_class) = &entity.state_class {
+            extra.push(("state_class", Value::from(state_class.as_str())));
+        }
 
-    if let Some(var) = var_headers.get("AirTemp") {
-        let sensor = SensorBuilder::new_var(var, TELEMETRY_STATE, device)
-            .with_device_class(SensorClass::Temperature)
-            .with_icon("mdi:thermometer")
-            .with_value_tempate("{{ value_json.AirTemp | float | round(2) }}")
-            .build();
-        discoverables.push(prepare_payload(sensor));
-    }
-
-    if let Some(var) = var_headers.get("TrackTempCrew") {
-        let sensor = SensorBuilder::new_var(var, TELEMETRY_STATE, device)
-            .with_device_class(SensorClass::Temperature)
-            .with_icon("mdi:thermometer")
-            .with_name("Track Temperature")
-            .with_value_tempate("{{ value_json.TrackTempCrew | float | round(2) }}")
-            .build();
-        discoverables.push(prepare_payload(sensor));
-    }
-
-    if let Some(var) = var_headers.get("WindDir") {
-        let sensor = SensorBuilder::new_var(var, TELEMETRY_STATE, device)
-            .with_unit_of_measurement(Some("degrees"))
-            .with_value_tempate("{{ (value_json.WindDir | float * 180 / pi) | float | round(2)}}")
-            .build();
-        discoverables.push(prepare_payload(sensor));
-    }
-
-    if let Some(var) = var_headers.get("WindVel") {
-        let sensor = SensorBuilder::new_var(var, TELEMETRY_STATE, device)
-            .with_unit_of_measurement(Some("km/h"))
-            .with_value_tempate("{{ (value_json.WindVel | float * 3.6) | round(2)}}")
-            .build();
-        discoverables.push(prepare_payload(sensor));
-    }
-
-    if let Some(var) = var_headers.get("IsOnTrack") {
-        let sensor = BinarySensorBuilder::new_var(var, TELEMETRY_STATE, device)
-            .with_icon("mdi:go-kart-track")
-            .with_payload_on("on")
-            .with_payload_off("off")
-            .with_value_tempate("{{ 'on' if value_json.IsOnTrack == true else 'off' }}")
-            .build();
-        discoverables.push(prepare_payload(sensor));
-    }
-
-    if let Some(var) = var_headers.get("Lap") {
-        let sensor = SensorBuilder::new_var(var, TELEMETRY_STATE, device)
-            .with_icon("mdi:counter")
-            .build();
-        discoverables.push(prepare_payload(sensor));
-    }
-
-    if let Some(var) = var_headers.get("SessionState") {
-        let sensor = SensorBuilder::new_var(var, TELEMETRY_STATE, device)
-            .with_icon("mdi:state-machine")
-            .with_unit_of_measurement(None::<&str>) // Data is a bitfield, TODO fix this issue with sending data.
-            .build();
-        discoverables.push(prepare_payload(sensor));
-    }
-
-    if let Some(var) = var_headers.get("PlayerCarClassPosition") {
-        let sensor = SensorBuilder::new_var(var, TELEMETRY_STATE, device)
-            .with_icon("mdi:podium")
-            .build();
-        discoverables.push(prepare_payload(sensor));
-    }
-
-    if let Some(var) = var_headers.get("TrackWetness") {
-        let sensor = SensorBuilder::new_var(var, TELEMETRY_STATE, device)
-            .with_icon("mdi:weather-rainy")
-            .with_unit_of_measurement(None::<&str>) // Data is a bitfield, TODO fix this issue with sending data.
-            .build();
-        discoverables.push(prepare_payload(sensor));
+        let packet = match entity.kind {
+            EntityKind::Sensor => {
+                let mut sensor = SensorBuilder::new_var(var, state_topic, device)
+                    .with_ids(entity.object_id(), entity.unique_id());
+                if let Some(name) = &entity.name {
+                    sensor = sensor.with_name(name);
+                }
+                if let Some(icon) = &entity.icon {
+                    sensor = sensor.with_icon(icon);
+                }
+                if let Some(unit) = &entity.unit {
+                    sensor = sensor.with_unit_of_measurement(unit.as_ref());
+                }
+                if let Some(template) = &entity.value_template {
+                    sensor = sensor.with_value_tempate(template);
+                }
+                prepare_payload_with(sensor.build(), &extra)
+            }
+            EntityKind::BinarySensor => {
+                let mut sensor = BinarySensorBuilder::new_var(var, state_topic, device)
+                    .with_ids(entity.object_id(), entity.unique_id());
+                if let Some(name) = &entity.name {
+                    sensor = sensor.with_name(name);
+                }
+                if let Some(icon) = &entity.icon {
+                    sensor = sensor.with_icon(icon);
+                }
+                if let Some(payload) = &entity.payload_on {
+                    sensor = sensor.with_payload_on(payload);
+                }
+                if let Some(payload) = &entity.payload_off {
+                    sensor = sensor.with_payload_off(payload);
+                }
+                if let Some(template) = &entity.value_template {
+                    sensor = sensor.with_value_tempate(template);
+                }
+                prepare_payload_with(sensor.build(), &extra)
+            }
+        };
+        discoverables.push(packet);
     }
 
-    if let Some(var) = var_headers.get("SolarAzimuth") {
-        let sensor = SensorBuilder::new_var(var, TELEMETRY_STATE, device)
-            .with_icon("mdi:sun-compass")
-            .with_unit_of_measurement(Some("degrees"))
-            .with_value_tempate(
-                "{{ (value_json.SolarAzimuth | float * 180 / pi) | float | round(2)}}",
-            )
-            .build();
-        discoverables.push(prepare_payload(sensor));
-    }
+    discoverables
+}
 
-    if let Some(var) = var_headers.get("SolarAltitude") {
-        let sensor = SensorBuilder::new_var(var, TELEMETRY_STATE, device)
-            .with_icon("mdi:sun-angle")
-            .with_unit_of_measurement(Some("degrees"))
-            .with_value_tempate(
-                "{{ (value_json.SolarAltitude | float * 180 / pi) | float | round(2)}}",
-            )
-            .build();
-        discoverables.push(prepare_payload(sensor));
+/// Discovery for everything the entities file controls, to diff when it is reloaded.  Telemetry entities, groups and
+/// weather wait for the variable headers.
+fn config_discovery_packet<V: VarDescription>(
+    var_headers: &HashMap<String, V>,
+    devices: &Devices,
+    entities: &[TelemetryEntity],
+    config: &EntityConfig,
+) -> Vec<DiscoveryPrepPacket> {
+    let mut discoverables = session_discovery_packet(devices, config);
+    if !var_headers.is_empty() {
+        discoverables.extend(discovery_packet(var_headers, devices, entities, config));
+        discoverables.extend(groups::discovery_packet(var_headers, devices, config));
+        discoverables.extend(weather::discovery_packet(var_headers, &devices.track));
     }
+    discoverables
+}
 
-    let yellow = BinarySensor::new(TELEMETRY_STATE)
-        .with_name("Yellow Flag")
-        .with_device(device)
-        .with_expire_after(5)
-        .with_icon("mdi:flag")
-        .with_payload_on("on")
-        .with_payload_off("off")
-        .with_value_template("{{ 'on' if 'Yellow' in value_json.SessionFlags else 'off' }}")
-        .with_unique_id("hairmqtt-yellow-flag")
-        .with_object_id("yellow_flag");
-
-    let green = BinarySensor::new(TELEMETRY_STATE)
-        .with_name("Green Flag")
-        .with_device(device)
-        .with_icon("mdi:flag")
-        .with_expire_after(5)
-        .with_payload_on("on")
-        .with_payload_off("off")
-        .with_value_template("{{ 'on' if 'Green Flag' in value_json.SessionFlags else 'off' }}")
-        .with_unique_id("hairmqtt-green-flag")
-        .with_object_id("green_flag");
-
-    let checkered = BinarySensor::new(TELEMETRY_STATE)
-        .with_name("Checkered Flag")
-        .with_device(device)
-        .with_expire_after(5)
-        .with_icon("mdi:flag")
-        .with_payload_on("on")
-        .with_payload_off("off")
-        .with_value_template("{{ 'on' if 'Checkered Flag' in value_json.SessionFlags else 'off' }}")
-        .with_unique_id("hairmqtt-checkered-flag")
-        .with_object_id("checkered_flag");
-
-    let white = BinarySensor::new(TELEMETRY_STATE)
-        .with_name("White Flag")
-        .with_device(device)
-        .with_expire_after(5)
-        .with_icon("mdi:flag")
-        .with_payload_on("on")
-        .with_payload_off("off")
-        .with_value_template("{{ 'on' if 'White Flag' in value_json.SessionFlags else 'off' }}")
-        .with_unique_id("hairmqtt-white-flag")
-        .with_object_id("white_flag");
-
-    let blue = BinarySensor::new(TELEMETRY_STATE)
-        .with_name("Blue Flag")
-        .with_device(device)
-        .with_expire_after(5)
-        .with_icon("mdi:flag")
-        .with_payload_on("on")
-        .with_payload_off("off")
-        .with_value_template("{{ 'on' if 'Blue Flag' in value_json.SessionFlags else 'off' }}")
-        .with_unique_id("hairmqtt-blue-flag")
-        .with_object_id("blue_flag");
-
-    discoverables.push(prepare_payload(yellow));
-
-    discoverables.push(prepare_payload(white));
-
-    discoverables.push(prepare_payload(green));
-
-    discoverables.push(prepare_payload(blue));
-
-    discoverables.push(prepare_payload(checkered));
+/// Each var has its own state topic, so it can be published at its own rate.
+fn telemetry_topic(var: &str) -> String {
+    format!("{}/{}", TELEMETRY_STATE, var)
+}
 
-    discoverables
+/// State for a var's topic.  Keyed by the var so templates read the same as for the full telemetry map.
+fn var_state(var: String, value: Value) -> Map<String, Value> {
+    let mut state = Map::new();
+    state.insert(var, value);
+    state
 }
 
 /// Sends the full telemetry data to HA
@@ -313,32 +334,107 @@ fn handle_session(session: &Session) -> Map<String, Value> {
     }
 }
 
-/// Creates a list of discoverable entities from the session data.
-fn session_discovery_packet(session: &Session, device: &Device) -> Vec<DiscoveryPrepPacket> {
+/// Resolves the session entity paths against the live session.  Paths that do not resolve are sent as null, so HA
+/// shows the entity as unknown.
+fn handle_session_entities(
+    session: &Value,
+    session_num: Option<i64>,
+    config: &EntityConfig,
+) -> Map<String, Value> {
+    let context = PathContext::new(session, session_num);
+
+    let mut entities = Map::new();
+    for entity in config.session.iter() {
+        let value = entity.path.resolve(session, &context).cloned();
+        entities.insert(entity.object_id.clone(), value.unwrap_or(Value::Null));
+
+        if let Some(attributes) = &entity.attributes {
+            let value = attributes.resolve(session, &context).cloned();
+            entities.insert(entity.attributes_key(), value.unwrap_or(Value::Null));
+        }
+    }
+
+    entities
+}
+
+/// Creates a list of discoverable entities from the session entities in the config.
+fn session_discovery_packet(devices: &Devices, config: &EntityConfig) -> Vec<DiscoveryPrepPacket> {
     let mut discoverables: Vec<DiscoveryPrepPacket> = Vec::new();
 
-    discoverables.push(prepare_payload(
-        SensorBuilder::new_session(session, "DriverCarIdx", SESSION_STATE, device, None)
-            .with_icon("mdi:account")
-            .build(),
-    ));
+    // Session entities expire after a few missed session heartbeats
+    let expire_after = Value::from(config.session_interval().expire_after());
 
-    discoverables.push(prepare_payload(
-        SensorBuilder::new_session(session, "DriverSetupName", SESSION_STATE, device, None)
-            .with_icon("mdi:cog")
-            .build(),
-    ));
+    for entity in config.session.iter() {
+        let mut extra = vec![("expire_after", expire_after.clone())];
+        if let Some(device_class) = &entity.device_class {
+            extra.push(("device_class", Value::from(device_class.as_str())));
+        }
+        if let Some(state_class) = &entity.state_class {
+            extra.push(("state_class", Value::from(state_class.as_str())));
+        }
 
-    discoverables.push(prepare_payload(
-        SensorBuilder::new_session(session, "TrackName", SESSION_STATE, device, Some(3))
-            .with_icon("mdi:go-kart-track")
-            .build(),
-    ));
+        if entity.attributes.is_some() {
+            extra.push(("json_attributes_topic", Value::from(SESSION_ENTITIES_STATE)));
+            extra.push((
+                "json_attributes_template",
+                Value::from(format!(
+                    "{{{{ value_json.{} | tojson }}}}",
+                    entity.attributes_key()
+                )),
+            ));
+        }
+
+        let device = devices.get(entity.device);
+        let packet = match entity.kind {
+            EntityKind::Sensor => {
+                let mut sensor = SensorBuilder::new_session(
+                    &entity.object_id,
+                    &entity.name,
+                    SESSION_ENTITIES_STATE,
+                    device,
+                )
+                .with_ids(&entity.object_id, entity.unique_id());
+                if let Some(icon) = &entity.icon {
+                    sensor = sensor.with_icon(icon);
+                }
+                if entity.unit.is_some() {
+                    sensor = sensor.with_unit_of_measurement(entity.unit.as_ref());
+                }
+                if let Some(template) = &entity.value_template {
+                    sensor = sensor.with_value_tempate(template);
+                }
+                prepare_payload_with(sensor.build(), &extra)
+            }
+            EntityKind::BinarySensor => {
+                let mut sensor = BinarySensorBuilder::new_session(
+                    &entity.object_id,
+                    &entity.name,
+                    SESSION_ENTITIES_STATE,
+                    device,
+                )
+                .with_ids(&entity.object_id, entity.unique_id());
+                if let Some(icon) = &entity.icon {
+                    sensor = sensor.with_icon(icon);
+                }
+                if let Some(payload) = &entity.payload_on {
+                    sensor = sensor.with_payload_on(payload);
+                }
+                if let Some(payload) = &entity.payload_off {
+                    sensor = sensor.with_payload_off(payload);
+                }
+                if let Some(template) = &entity.value_template {
+                    sensor = sensor.with_value_tempate(template);
+                }
+                prepare_payload_with(sensor.build(), &extra)
+            }
+        };
+        discoverables.push(packet);
+    }
 
     discoverables.push(prepare_payload(
-        BinarySensor::new("hairmqtt/connected")
+        BinarySensor::new(CONNECTED_STATE)
             .with_name("Connection")
-            .with_device(device)
+            .with_device(&devices.bridge)
             .with_icon("mdi:connection")
             .with_payload_on("connected")
             .with_payload_off("disconnected")
@@ -346,6 +442,9 @@ fn session_discovery_packet(session: &Session, device: &Device) -> Vec<Discovery
             .with_object_id("connection"),
     ));
 
+    discoverables.extend(diagnostics::discovery_packet(&devices.bridge));
+    discoverables.extend(sun::discovery_packet(&devices.track));
+
     discoverables
 }
 
@@ -367,3 +466,20 @@ where
         serde_json::to_vec(&item).map_err(|e| e.into()),
     )
 }
+
+/// Same as `prepare_payload`, but adds discovery fields that the ha_mqtt types do not have, such as `entity_category`.
+fn prepare_payload_with<T>(item: T, extra: &[(&str, Value)]) -> DiscoveryPrepPacket
+where
+    T: Discoverable + Serialize,
+{
+    let payload = serde_json::to_value(&item).and_then(|mut value| {
+        if let Value::Object(map) = &mut value {
+            for (key, extra_value) in extra {
+                map.insert(key.to_string(), extra_value.clone());
+            }
+        }
+        serde_json::to_vec(&value)
+    });
+
+    (item.config_topic(), payload.map_err(|e| e.into()))
+}
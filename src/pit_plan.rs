@@ -0,0 +1,410 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::broadcast::{BroadcastMessage, PitCommand};
+use crate::commands::{CommandError, MAX_FUEL};
+use crate::scheduler::Throttle;
+
+pub(crate) const PIT_PLAN_STATE: &str = "hairmqtt/pit_plan";
+
+const DEFAULT_PIT_PLAN_FILE: &str = "pit_plan.json";
+
+/// The plan is re-sent this often so its entities do not expire
+const PLAN_HEARTBEAT: Duration = Duration::from_secs(10);
+
+/// Litres.  How far `PitSvFuel` can be from the plan and still confirm it.
+const FUEL_TOLERANCE: f64 = 0.5;
+
+/// `irsdk_PitSvFlags`
+const LF_TIRE_CHANGE: i64 = 0x01;
+const RF_TIRE_CHANGE: i64 = 0x02;
+const LR_TIRE_CHANGE: i64 = 0x04;
+const RR_TIRE_CHANGE: i64 = 0x08;
+const FUEL_FILL: i64 = 0x10;
+const WINDSHIELD_TEAROFF: i64 = 0x20;
+const FAST_REPAIR: i64 = 0x40;
+
+/// The pit stop the driver wants.  Saved to `PIT_PLAN_FILE` so it survives restarts and sessions.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub(crate) struct PitPlan {
+    /// Litres to add, 0 for none
+    pub fuel: f64,
+    pub lf: bool,
+    pub rf: bool,
+    pub lr: bool,
+    pub rr: bool,
+    pub windshield: bool,
+    pub fast_repair: bool,
+    /// Apply the plan when the car enters pit road
+    pub auto_apply: bool,
+}
+
+impl PitPlan {
+    /// Clears iRacing's pit checkboxes, then ticks the planned ones.
+    pub fn messages(&self) -> Vec<BroadcastMessage> {
+        let mut commands = vec![PitCommand::Clear];
+        let tyres = [
+            (self.lf, PitCommand::LeftFront(0)),
+            (self.rf, PitCommand::RightFront(0)),
+            (self.lr, PitCommand::LeftRear(0)),
+            (self.rr, PitCommand::RightRear(0)),
+        ];
+        commands.extend(
+            tyres
+                .into_iter()
+                .filter(|(change, _)| *change)
+                .map(|(_, command)| command),
+        );
+        if self.fuel > 0. {
+            commands.push(PitCommand::Fuel(self.fuel.round() as i64));
+        }
+        if self.windshield {
+            commands.push(PitCommand::Windshield);
+        }
+        if self.fast_repair {
+            commands.push(PitCommand::FastRepair);
+        }
+
+        commands.into_iter().map(BroadcastMessage::Pit).collect()
+    }
+
+    /// Keeps the fuel within what can be sent to iRacing, for plans saved by hand or by an older version.
+    fn clamped(self) -> Self {
+        let fuel = if self.fuel.is_finite() {
+            self.fuel.clamp(0., MAX_FUEL)
+        } else {
+            0.
+        };
+        Self { fuel, ..self }
+    }
+
+    /// The `PitSvFlags` iRacing shows once the plan is applied
+    fn service_flags(&self) -> i64 {
+        [
+            (self.lf, LF_TIRE_CHANGE),
+            (self.rf, RF_TIRE_CHANGE),
+            (self.lr, LR_TIRE_CHANGE),
+            (self.rr, RR_TIRE_CHANGE),
+            (self.fuel > 0., FUEL_FILL),
+            (self.windshield, WINDSHIELD_TEAROFF),
+            (self.fast_repair, FAST_REPAIR),
+        ]
+        .into_iter()
+        .filter(|(planned, _)| *planned)
+        .fold(0, |flags, (_, flag)| flags | flag)
+    }
+
+    /// Whether iRacing's pit service matches the plan
+    fn confirmed(&self, service_flags: i64, service_fuel: Option<f64>) -> bool {
+        let all_flags = LF_TIRE_CHANGE
+            | RF_TIRE_CHANGE
+            | LR_TIRE_CHANGE
+            | RR_TIRE_CHANGE
+            | FUEL_FILL
+            | WINDSHIELD_TEAROFF
+            | FAST_REPAIR;
+        let fuel_matches = self.fuel <= 0.
+            || service_fuel.map_or(false, |fuel| (fuel - self.fuel).abs() <= FUEL_TOLERANCE);
+
+        service_flags & all_flags == self.service_flags() && fuel_matches
+    }
+}
+
+/// `PitSvFlags` as a bitfield.  Bitfields can also arrive as a list of flag names, eg `["LF Tire Change"]`.
+fn service_flags(value: &Value) -> Option<i64> {
+    if let Some(flags) = value.as_i64() {
+        return Some(flags);
+    }
+
+    let names = value.as_array()?;
+    let flags = names
+        .iter()
+        .filter_map(Value::as_str)
+        .map(|name| {
+            let name: String = name
+                .chars()
+                .filter(char::is_ascii_alphanumeric)
+                .collect::<String>()
+                .to_lowercase();
+            match name.as_str() {
+                "lftirechange" => LF_TIRE_CHANGE,
+                "rftirechange" => RF_TIRE_CHANGE,
+                "lrtirechange" => LR_TIRE_CHANGE,
+                "rrtirechange" => RR_TIRE_CHANGE,
+                "fuelfill" => FUEL_FILL,
+                "windshieldtearoff" => WINDSHIELD_TEAROFF,
+                "fastrepair" => FAST_REPAIR,
+                _ => 0,
+            }
+        })
+        .fold(0, |flags, flag| flags | flag);
+    Some(flags)
+}
+
+/// Keeps the pit plan, applies it on pit entry or when asked, and reports whether iRacing agrees with it.
+pub(crate) struct PitPlanner {
+    plan: PitPlan,
+    path: Option<PathBuf>,
+    on_pit_road: bool,
+    service_flags: Option<i64>,
+    service_fuel: Option<f64>,
+    heartbeat: Throttle,
+    changed: bool,
+}
+
+impl PitPlanner {
+    /// Loads the plan from `PIT_PLAN_FILE`, `pit_plan.json` by default.
+    pub fn from_env() -> Self {
+        let path = PathBuf::from(
+            std::env::var("PIT_PLAN_FILE").unwrap_or_else(|_| DEFAULT_PIT_PLAN_FILE.to_string()),
+        );
+        let plan = match std::fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str::<PitPlan>(&contents)
+                .map(PitPlan::clamped)
+                .unwrap_or_else(|e| {
+                    log::error!("Invalid pit plan {}: {}", path.display(), e);
+                    PitPlan::default()
+                }),
+            Err(_) => PitPlan::default(),
+        };
+
+        Self::new(plan, Some(path))
+    }
+
+    /// A planner that saves to `path`, or only keeps the plan in memory without one.
+    pub fn new(plan: PitPlan, path: Option<PathBuf>) -> Self {
+        Self {
+            plan,
+            path,
+            on_pit_road: false,
+            service_flags: None,
+            service_fuel: None,
+            heartbeat: Throttle::new(PLAN_HEARTBEAT),
+            changed: true,
+        }
+    }
+
+    /// Handles the plan's command topics.  `None` if the command is not for the plan.
+    pub fn command(
+        &mut self,
+        command: &str,
+        payload: &str,
+    ) -> Option<Result<Vec<BroadcastMessage>, CommandError>> {
+        let invalid = || CommandError::InvalidPayload(command.to_string(), payload.to_string());
+        let switch = |payload: &str| match payload {
+            "ON" => Ok(true),
+            "OFF" => Ok(false),
+            _ => Err(invalid()),
+        };
+
+        let plan = &mut self.plan;
+        let result = match command {
+            "plan_apply" => return Some(Ok(plan.messages())),
+            "plan_fuel" => payload
+                .parse::<f64>()
+                .ok()
+                .filter(|fuel| (0. ..=MAX_FUEL).contains(fuel))
+                .map(|fuel| plan.fuel = fuel)
+                .ok_or_else(invalid),
+            "plan_lf" => switch(payload).map(|on| plan.lf = on),
+            "plan_rf" => switch(payload).map(|on| plan.rf = on),
+            "plan_lr" => switch(payload).map(|on| plan.lr = on),
+            "plan_rr" => switch(payload).map(|on| plan.rr = on),
+            "plan_windshield" => switch(payload).map(|on| plan.windshield = on),
+            "plan_fast_repair" => switch(payload).map(|on| plan.fast_repair = on),
+            "plan_auto_apply" => switch(payload).map(|on| plan.auto_apply = on),
+            _ => return None,
+        };
+
+        Some(result.map(|_| {
+            self.changed = true;
+            self.save();
+            Vec::new()
+        }))
+    }
+
+    /// Follows the pit service vars.  Returns the plan's messages when the car enters pit road with auto apply on.
+    pub fn telemetry(&mut self, telemetry: &Map<String, Value>) -> Vec<BroadcastMessage> {
+        let service_flags = telemetry.get("PitSvFlags").and_then(service_flags);
+        let service_fuel = telemetry.get("PitSvFuel").and_then(Value::as_f64);
+        if service_flags != self.service_flags || service_fuel != self.service_fuel {
+            self.service_flags = service_flags;
+            self.service_fuel = service_fuel;
+            self.changed = true;
+        }
+
+        let on_pit_road = telemetry
+            .get("OnPitRoad")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let entered = on_pit_road && !self.on_pit_road;
+        self.on_pit_road = on_pit_road;
+
+        if entered && self.plan.auto_apply {
+            log::info!("Entered pit road, applying the pit plan");
+            self.plan.messages()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Returns the plan state when it has changed or is due a heartbeat.
+    pub fn state(&mut self, now: Instant) -> Option<Value> {
+        if !self.heartbeat.ready(now) && !self.changed {
+            return None;
+        }
+        self.heartbeat.mark(now);
+        self.changed = false;
+
+        let mut state = match serde_json::to_value(&self.plan) {
+            Ok(Value::Object(state)) => state,
+            _ => Map::new(),
+        };
+        let confirmed = self
+            .service_flags
+            .map(|flags| self.plan.confirmed(flags, self.service_fuel));
+        state.insert("confirmed".to_string(), Value::from(confirmed));
+        state.insert("service_flags".to_string(), Value::from(self.service_flags));
+        state.insert("service_fuel".to_string(), Value::from(self.service_fuel));
+
+        Some(Value::Object(state))
+    }
+
+    /// Telemetry stops while iRacing is not connected, so the service is unknown until it comes back.
+    pub fn clear(&mut self) {
+        self.on_pit_road = false;
+        self.service_flags = None;
+        self.service_fuel = None;
+        self.changed = true;
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+
+        let result = serde_json::to_vec_pretty(&self.plan)
+            .map_err(|e| e.to_string())
+            .and_then(|contents| std::fs::write(path, contents).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            log::error!("Failed to save the pit plan to {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn plan() -> PitPlan {
+        PitPlan {
+            fuel: 30.,
+            lf: true,
+            lr: true,
+            windshield: true,
+            ..PitPlan::default()
+        }
+    }
+
+    #[test]
+    fn should_clear_then_apply_plan() {
+        assert_eq!(
+            plan().messages(),
+            vec![
+                BroadcastMessage::Pit(PitCommand::Clear),
+                BroadcastMessage::Pit(PitCommand::LeftFront(0)),
+                BroadcastMessage::Pit(PitCommand::LeftRear(0)),
+                BroadcastMessage::Pit(PitCommand::Fuel(30)),
+                BroadcastMessage::Pit(PitCommand::Windshield),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_confirm_plan_from_service_flags() {
+        let flags = LF_TIRE_CHANGE | LR_TIRE_CHANGE | FUEL_FILL | WINDSHIELD_TEAROFF;
+        assert!(plan().confirmed(flags, Some(30.2)));
+        assert!(!plan().confirmed(flags, Some(20.)));
+        assert!(!plan().confirmed(flags | FAST_REPAIR, Some(30.)));
+        assert!(!plan().confirmed(LF_TIRE_CHANGE, Some(30.)));
+        assert!(PitPlan::default().confirmed(0, None));
+    }
+
+    #[test]
+    fn should_read_service_flags_as_names() {
+        assert_eq!(service_flags(&json!(0x11)), Some(0x11));
+        assert_eq!(
+            service_flags(&json!(["LF Tire Change", "FuelFill"])),
+            Some(LF_TIRE_CHANGE | FUEL_FILL)
+        );
+        assert_eq!(service_flags(&json!("none")), None);
+    }
+
+    #[test]
+    fn should_update_plan_from_commands() {
+        let mut planner = PitPlanner::new(PitPlan::default(), None);
+
+        assert!(planner
+            .command("plan_fuel", "45")
+            .unwrap()
+            .unwrap()
+            .is_empty());
+        assert!(planner.command("plan_rf", "ON").unwrap().is_ok());
+        assert!(planner.command("plan_rf", "maybe").unwrap().is_err());
+        assert!(planner.command("plan_fuel", "inf").unwrap().is_err());
+        assert!(planner.command("plan_fuel", "1e12").unwrap().is_err());
+        assert!(planner.command("pit_clear", "PRESS").is_none());
+        assert_eq!(planner.plan.fuel, 45.);
+        assert!(planner.plan.rf);
+
+        let messages = planner.command("plan_apply", "PRESS").unwrap().unwrap();
+        assert_eq!(messages.len(), 3);
+    }
+
+    #[test]
+    fn should_clamp_saved_fuel() {
+        let plan: PitPlan = serde_json::from_str(r#"{ "fuel": 1e12, "lf": true }"#).unwrap();
+        assert_eq!(plan.clamped().fuel, MAX_FUEL);
+        let plan: PitPlan = serde_json::from_str(r#"{ "fuel": -5 }"#).unwrap();
+        assert_eq!(plan.clamped().fuel, 0.);
+    }
+
+    #[test]
+    fn should_apply_on_pit_entry_when_auto() {
+        let mut planner = PitPlanner::new(
+            PitPlan {
+                auto_apply: true,
+                ..plan()
+            },
+            None,
+        );
+        let on_track = json!({ "OnPitRoad": false });
+        let on_pit_road = json!({ "OnPitRoad": true });
+
+        assert!(planner.telemetry(on_track.as_object().unwrap()).is_empty());
+        assert_eq!(planner.telemetry(on_pit_road.as_object().unwrap()).len(), 5);
+        // Only on the edge
+        assert!(planner
+            .telemetry(on_pit_road.as_object().unwrap())
+            .is_empty());
+    }
+
+    #[test]
+    fn should_send_state_when_changed() {
+        let mut planner = PitPlanner::new(plan(), None);
+        let now = Instant::now();
+
+        let state = planner.state(now).unwrap();
+        assert_eq!(state["fuel"], json!(30.));
+        assert_eq!(state["confirmed"], Value::Null);
+        assert!(planner.state(now).is_none());
+
+        let telemetry = json!({ "PitSvFlags": LF_TIRE_CHANGE | LR_TIRE_CHANGE | FUEL_FILL | WINDSHIELD_TEAROFF, "PitSvFuel": 30. });
+        planner.telemetry(telemetry.as_object().unwrap());
+        assert_eq!(planner.state(now).unwrap()["confirmed"], json!(true));
+    }
+}
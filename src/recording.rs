@@ -0,0 +1,233 @@
+use ir_telemetry::mapped_file::var_header::VarHeader;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+/// A var header as recorded and as listed by `list-vars`.  Recordings keep these rather than the headers themselves,
+/// so they can be read without iRacing.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct VarInfo {
+    pub name: String,
+    pub var_type: String,
+    pub units: String,
+    pub count: usize,
+    pub description: String,
+}
+
+impl VarInfo {
+    pub fn from_header(var: &VarHeader) -> Self {
+        Self {
+            name: var.name().to_string(),
+            var_type: format!("{:?}", var.var_type()),
+            units: var.units().to_string(),
+            count: var.count() as usize,
+            description: var.description().to_string(),
+        }
+    }
+
+    /// Sorted by name, since the headers come as a map.
+    pub fn from_headers(var_headers: &HashMap<String, VarHeader>) -> Vec<Self> {
+        let mut vars: Vec<Self> = var_headers.values().map(Self::from_header).collect();
+        vars.sort_by(|a, b| a.name.cmp(&b.name));
+        vars
+    }
+}
+
+/// The parts of a var header that discovery needs, so it can be built from a recording's vars as well as iRacing's.
+pub(crate) trait VarDescription {
+    fn name(&self) -> &str;
+    fn units(&self) -> &str;
+}
+
+impl VarDescription for VarHeader {
+    fn name(&self) -> &str {
+        VarHeader::name(self)
+    }
+
+    fn units(&self) -> &str {
+        VarHeader::units(self)
+    }
+}
+
+impl VarDescription for VarInfo {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn units(&self) -> &str {
+        &self.units
+    }
+}
+
+/// A line of a recording.  Each line is one of these as JSON, eg `{"telemetry":{"Speed":41.2}}`, in the order the
+/// bridge received them.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RecordEntry {
+    Variables(Vec<VarInfo>),
+    /// The serialized session, as published to `hairmqtt/session`
+    Session(Map<String, Value>),
+    Telemetry(Map<String, Value>),
+    Disconnected,
+}
+
+pub(crate) enum RecordingError {
+    Read(String, std::io::Error),
+    Parse(String, usize, serde_json::Error),
+}
+
+impl fmt::Display for RecordingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecordingError::Read(path, e) => write!(f, "Failed to read recording {}: {}", path, e),
+            RecordingError::Parse(path, line, e) => {
+                write!(f, "Invalid recording {} at line {}: {}", path, line, e)
+            }
+        }
+    }
+}
+
+impl fmt::Debug for RecordingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for RecordingError {}
+
+/// Reads every entry of a recording.  Blank lines are skipped.
+pub(crate) fn read_recording(path: &Path) -> Result<Vec<RecordEntry>, RecordingError> {
+    let name = path.display().to_string();
+    let file = std::fs::File::open(path).map_err(|e| RecordingError::Read(name.clone(), e))?;
+
+    let mut entries = Vec::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| RecordingError::Read(name.clone(), e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(&line)
+            .map_err(|e| RecordingError::Parse(name.clone(), number + 1, e))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// The vars and session of a recording or of the running sim, for the diagnostic commands.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Snapshot {
+    pub variables: Vec<VarInfo>,
+    pub session: Option<Map<String, Value>>,
+    /// Only from recordings.  Samples for `validate`.
+    pub telemetry: Option<Map<String, Value>>,
+}
+
+impl Snapshot {
+    /// The last vars, session and telemetry in the entries
+    pub fn from_entries(entries: &[RecordEntry]) -> Self {
+        let mut snapshot = Self::default();
+        for entry in entries.iter() {
+            match entry {
+                RecordEntry::Variables(variables) => snapshot.variables = variables.clone(),
+                RecordEntry::Session(session) => snapshot.session = Some(session.clone()),
+                RecordEntry::Telemetry(telemetry) => snapshot.telemetry = Some(telemetry.clone()),
+                RecordEntry::Disconnected => (),
+            }
+        }
+        snapshot
+    }
+}
+
+/// Flattens the session into the paths entities use, eg `weekend_info.track_name`, with the value at each leaf.
+/// Lists use numeric indices.
+pub(crate) fn session_paths(session: &Value) -> Vec<(String, Value)> {
+    let mut paths = Vec::new();
+    flatten(String::new(), session, &mut paths);
+    paths
+}
+
+fn flatten(path: String, value: &Value, paths: &mut Vec<(String, Value)>) {
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            for (key, value) in map.iter() {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                flatten(path, value, paths);
+            }
+        }
+        Value::Array(list) if !list.is_empty() => {
+            for (index, value) in list.iter().enumerate() {
+                flatten(format!("{}[{}]", path, index), value, paths);
+            }
+        }
+        _ => paths.push((path, value.clone())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session_path::{PathContext, SessionPath};
+    use serde_json::json;
+
+    #[test]
+    fn should_read_last_vars_and_session() {
+        let lines = [
+            r#"{"variables":[{"name":"Speed","var_type":"Float","units":"m/s","count":1,"description":"GPS vehicle speed"}]}"#,
+            r#"{"session":{"weekend_info":{"track_name":"spa"}}}"#,
+            "",
+            r#"{"telemetry":{"Speed":41.2}}"#,
+            r#""disconnected""#,
+            r#"{"session":{"weekend_info":{"track_name":"monza"}}}"#,
+        ];
+        let path =
+            std::env::temp_dir().join(format!("hairmqtt-recording-{}.jsonl", std::process::id()));
+        std::fs::write(&path, lines.join("\n")).unwrap();
+
+        let entries = read_recording(&path).unwrap();
+        assert_eq!(entries.len(), 5);
+        assert_eq!(entries[3], RecordEntry::Disconnected);
+
+        let snapshot = Snapshot::from_entries(&entries);
+        assert_eq!(snapshot.variables[0].units, "m/s");
+        assert_eq!(snapshot.telemetry.unwrap()["Speed"], json!(41.2));
+        assert_eq!(
+            snapshot.session.unwrap()["weekend_info"]["track_name"],
+            json!("monza")
+        );
+
+        std::fs::write(&path, "{\"telemetry\":").unwrap();
+        assert!(matches!(
+            read_recording(&path),
+            Err(RecordingError::Parse(_, 1, _))
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn should_flatten_session_into_resolvable_paths() {
+        let session = json!({
+            "weekend_info": { "track_name": "spa", "weekend_options": {} },
+            "driver_info": { "drivers": [{ "car_idx": 0, "user_name": "Tim" }] },
+        });
+
+        let paths = session_paths(&session);
+        let names: Vec<&str> = paths.iter().map(|(path, _)| path.as_str()).collect();
+        assert!(names.contains(&"weekend_info.track_name"));
+        assert!(names.contains(&"weekend_info.weekend_options"));
+        assert!(names.contains(&"driver_info.drivers[0].user_name"));
+
+        // Every listed path can be used in the entities file
+        let context = PathContext::new(&session, None);
+        for (path, value) in paths.iter() {
+            let parsed: SessionPath = path.parse().unwrap();
+            assert_eq!(parsed.resolve(&session, &context), Some(value));
+        }
+    }
+}
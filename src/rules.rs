@@ -0,0 +1,352 @@
+use serde::{Deserialize, Deserializer};
+use serde_json::{Map, Value};
+use std::time::{Duration, Instant};
+
+use crate::session_path::{PathContext, SessionPath};
+
+/// A rule evaluated in the bridge against every telemetry tick, so it keeps working without HA and without its
+/// latency.  When all of its conditions start or stop holding, its actions are published.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct Rule {
+    pub name: String,
+    /// All must hold for the rule to be on
+    pub when: Vec<Condition>,
+    /// Seconds the conditions must hold, or stop holding, before the rule changes.  Filters out flickering values.
+    #[serde(default, deserialize_with = "seconds")]
+    pub debounce: f64,
+    /// Published when the rule turns on
+    #[serde(default)]
+    pub on_true: Vec<Action>,
+    /// Published when the rule turns off
+    #[serde(default)]
+    pub on_false: Vec<Action>,
+}
+
+/// Seconds that can be turned into a `Duration`: finite and not negative.
+fn seconds<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let seconds = f64::deserialize(deserializer)?;
+    if seconds.is_finite() && seconds >= 0. {
+        Ok(seconds)
+    } else {
+        Err(serde::de::Error::custom(format!(
+            "expected a number of seconds that is not negative, found {}",
+            seconds
+        )))
+    }
+}
+
+/// A check on a telemetry var or a session path.  Every comparison that is set must pass.  With none set, the value
+/// must be truthy: `true`, a non zero number or a non empty string or list.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct Condition {
+    pub var: Option<String>,
+    pub path: Option<SessionPath>,
+    pub equals: Option<Value>,
+    pub not_equals: Option<Value>,
+    pub above: Option<f64>,
+    pub below: Option<f64>,
+    /// Substring of a string, or of an entry in a list, eg a flag in `SessionFlags`.  Case insensitive.
+    pub contains: Option<String>,
+}
+
+/// An MQTT message to publish.  A payload that is not a string is sent as JSON.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct Action {
+    pub topic: String,
+    pub payload: Value,
+    #[serde(default)]
+    pub retain: bool,
+}
+
+impl Action {
+    pub fn payload(&self) -> Vec<u8> {
+        match &self.payload {
+            Value::String(payload) => payload.clone().into_bytes(),
+            payload => payload.to_string().into_bytes(),
+        }
+    }
+}
+
+impl Condition {
+    fn matches(
+        &self,
+        telemetry: &Map<String, Value>,
+        session: Option<(&Value, &PathContext)>,
+    ) -> bool {
+        let value = match (&self.var, &self.path) {
+            (Some(var), _) => telemetry.get(var),
+            (None, Some(path)) => {
+                session.and_then(|(session, context)| path.resolve(session, context))
+            }
+            (None, None) => None,
+        };
+        let Some(value) = value else {
+            return false;
+        };
+
+        let compared = self.equals.is_some()
+            || self.not_equals.is_some()
+            || self.above.is_some()
+            || self.below.is_some()
+            || self.contains.is_some();
+        if !compared {
+            return truthy(value);
+        }
+
+        self.equals
+            .as_ref()
+            .map_or(true, |equals| loosely_equal(value, equals))
+            && self
+                .not_equals
+                .as_ref()
+                .map_or(true, |not_equals| !loosely_equal(value, not_equals))
+            && self.above.map_or(true, |above| {
+                value.as_f64().map_or(false, |value| value > above)
+            })
+            && self.below.map_or(true, |below| {
+                value.as_f64().map_or(false, |value| value < below)
+            })
+            && self
+                .contains
+                .as_ref()
+                .map_or(true, |contains| value_contains(value, contains))
+    }
+}
+
+fn truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(value) => *value,
+        Value::Number(number) => number.as_f64().map_or(false, |number| number != 0.),
+        Value::String(value) => !value.is_empty(),
+        Value::Array(values) => !values.is_empty(),
+        Value::Object(values) => !values.is_empty(),
+    }
+}
+
+/// Numbers compare by value, so `equals: 1` matches `1.0`
+fn loosely_equal(value: &Value, expected: &Value) -> bool {
+    match (value.as_f64(), expected.as_f64()) {
+        (Some(value), Some(expected)) => value == expected,
+        _ => value == expected,
+    }
+}
+
+fn value_contains(value: &Value, needle: &str) -> bool {
+    let needle = needle.to_lowercase();
+    match value {
+        Value::String(value) => value.to_lowercase().contains(&needle),
+        Value::Array(values) => values.iter().any(|value| value_contains(value, &needle)),
+        _ => false,
+    }
+}
+
+struct RuleState {
+    rule: Rule,
+    active: bool,
+    /// The opposite of `active` and when it was first seen, while it waits out the debounce
+    pending: Option<Instant>,
+}
+
+/// Runs the rules from the config and returns the actions to publish.
+pub(crate) struct RuleEngine {
+    rules: Vec<RuleState>,
+    uses_session: bool,
+}
+
+impl RuleEngine {
+    pub fn new(rules: &[Rule]) -> Self {
+        Self {
+            uses_session: rules
+                .iter()
+                .flat_map(|rule| rule.when.iter())
+                .any(|condition| condition.path.is_some()),
+            rules: rules
+                .iter()
+                .map(|rule| RuleState {
+                    rule: rule.clone(),
+                    active: false,
+                    pending: None,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn evaluate(
+        &mut self,
+        telemetry: &Map<String, Value>,
+        session: Option<&Value>,
+        session_num: Option<i64>,
+        now: Instant,
+    ) -> Vec<Action> {
+        // Resolving the player and leader walks the drivers list, so it is only done for rules that need it
+        let context = session
+            .filter(|_| self.uses_session)
+            .map(|session| PathContext::new(session, session_num));
+        let session = session.zip(context.as_ref());
+
+        let mut actions = Vec::new();
+        for state in self.rules.iter_mut() {
+            let holds = state
+                .rule
+                .when
+                .iter()
+                .all(|condition| condition.matches(telemetry, session));
+
+            if holds == state.active {
+                state.pending = None;
+                continue;
+            }
+
+            let since = *state.pending.get_or_insert(now);
+            if now.duration_since(since)
+                < Duration::try_from_secs_f64(state.rule.debounce).unwrap_or_default()
+            {
+                continue;
+            }
+
+            state.active = holds;
+            state.pending = None;
+            log::debug!(
+                "Rule {} is {}",
+                state.rule.name,
+                if holds { "on" } else { "off" }
+            );
+            if holds {
+                actions.extend(state.rule.on_true.iter().cloned());
+            } else {
+                actions.extend(state.rule.on_false.iter().cloned());
+            }
+        }
+
+        actions
+    }
+
+    /// Turns every rule off, eg when iRacing disconnects.  Returns the `on_false` actions of the rules that were on.
+    pub fn reset(&mut self) -> Vec<Action> {
+        let mut actions = Vec::new();
+        for state in self.rules.iter_mut() {
+            if state.active {
+                actions.extend(state.rule.on_false.iter().cloned());
+            }
+            state.active = false;
+            state.pending = None;
+        }
+        actions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn engine(yaml: &str) -> RuleEngine {
+        let rules: Vec<Rule> = serde_yaml::from_str(yaml).unwrap();
+        RuleEngine::new(&rules)
+    }
+
+    fn telemetry(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    fn topics(actions: Vec<Action>) -> Vec<String> {
+        actions.into_iter().map(|action| action.topic).collect()
+    }
+
+    #[test]
+    fn should_fire_on_edges() {
+        let mut engine = engine(
+            "- name: fan\n  when:\n    - var: IsOnTrack\n  \
+             on_true:\n    - { topic: fan/set, payload: 'on' }\n  \
+             on_false:\n    - { topic: fan/set, payload: 'off' }\n",
+        );
+        let now = Instant::now();
+        let on_track = telemetry(json!({ "IsOnTrack": true }));
+
+        let actions = engine.evaluate(&on_track, None, None, now);
+        assert_eq!(actions.len(), 1);
+        assert_eq!(actions[0].payload(), b"on");
+        // Only on the edge
+        assert!(engine.evaluate(&on_track, None, None, now).is_empty());
+
+        let actions = engine.evaluate(&telemetry(json!({ "IsOnTrack": false })), None, None, now);
+        assert_eq!(actions[0].payload(), b"off");
+    }
+
+    #[test]
+    fn should_debounce() {
+        let mut engine = engine(
+            "- name: yellow\n  debounce: 1\n  when:\n    - var: SessionFlags\n      contains: yellow\n  \
+             on_true:\n    - { topic: lights/set, payload: { color: yellow } }\n",
+        );
+        let start = Instant::now();
+        let yellow = telemetry(json!({ "SessionFlags": ["Green Flag", "Yellow Flag"] }));
+        let green = telemetry(json!({ "SessionFlags": ["Green Flag"] }));
+
+        assert!(engine.evaluate(&yellow, None, None, start).is_empty());
+        // A flicker restarts the debounce
+        assert!(engine
+            .evaluate(&green, None, None, start + Duration::from_millis(500))
+            .is_empty());
+        assert!(engine
+            .evaluate(&yellow, None, None, start + Duration::from_millis(600))
+            .is_empty());
+        assert!(engine
+            .evaluate(&yellow, None, None, start + Duration::from_millis(1200))
+            .is_empty());
+
+        let actions = engine.evaluate(&yellow, None, None, start + Duration::from_millis(1700));
+        assert_eq!(topics(actions.clone()), vec!["lights/set"]);
+        assert_eq!(actions[0].payload(), br#"{"color":"yellow"}"#);
+    }
+
+    #[test]
+    fn should_reject_invalid_debounce() {
+        let rule = |debounce: &str| {
+            serde_yaml::from_str::<Vec<Rule>>(&format!(
+                "- name: pit\n  debounce: {}\n  when:\n    - var: OnPitRoad\n",
+                debounce
+            ))
+        };
+        assert!(rule("0.5").is_ok());
+        assert!(rule(".inf").is_err());
+        assert!(rule(".nan").is_err());
+        assert!(rule("-1").is_err());
+    }
+
+    #[test]
+    fn should_compare_values() {
+        let condition = |yaml: &str| serde_yaml::from_str::<Condition>(yaml).unwrap();
+        let data = telemetry(json!({ "Gear": 3, "Speed": 50.5, "Name": "Spa" }));
+
+        assert!(condition("var: Gear\nequals: 3.0").matches(&data, None));
+        assert!(condition("var: Gear\nnot_equals: 4").matches(&data, None));
+        assert!(condition("var: Speed\nabove: 50\nbelow: 60").matches(&data, None));
+        assert!(!condition("var: Speed\nabove: 60").matches(&data, None));
+        assert!(condition("var: Name\ncontains: SPA").matches(&data, None));
+        assert!(!condition("var: Missing").matches(&data, None));
+    }
+
+    #[test]
+    fn should_check_session_paths() {
+        let mut engine = engine(
+            "- name: race\n  when:\n    - path: session_info.sessions[current].session_type\n      equals: Race\n  \
+             on_true:\n    - { topic: race, payload: start, retain: true }\n",
+        );
+        let session = json!({ "session_info": { "sessions": [
+            { "session_num": 0, "session_type": "Practice" },
+            { "session_num": 1, "session_type": "Race" },
+        ] } });
+
+        assert!(engine
+            .evaluate(&Map::new(), Some(&session), Some(0), Instant::now())
+            .is_empty());
+        let actions = engine.evaluate(&Map::new(), Some(&session), Some(1), Instant::now());
+        assert!(actions[0].retain);
+        assert_eq!(topics(engine.reset()), Vec::<String>::new());
+    }
+}
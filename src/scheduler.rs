@@ -0,0 +1,262 @@
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// Heartbeats that can be missed before HA marks an entity unavailable.
+const HEARTBEATS_PER_EXPIRY: f64 = 3.;
+
+/// How often a topic is published.  A changed value waits for `min` since the last publish, an unchanged value is
+/// re-sent after `max`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct PublishInterval {
+    pub min: Duration,
+    pub max: Duration,
+}
+
+impl PublishInterval {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        // A max below the min would publish unchanged values faster than changed ones
+        Self {
+            min,
+            max: max.max(min),
+        }
+    }
+
+    /// `expire_after` for an entity using this interval.  Long enough for a couple of heartbeats to go missing.
+    pub fn expire_after(&self) -> u64 {
+        (self.max.as_secs_f64() * HEARTBEATS_PER_EXPIRY).ceil() as u64
+    }
+
+    /// Shortens the heartbeat so it fits inside an entity's `expire_after`.
+    pub fn within_expiry(self, expire_after: u64) -> Self {
+        let max = Duration::from_secs_f64(expire_after as f64 / HEARTBEATS_PER_EXPIRY);
+        Self::new(self.min.min(max), self.max.min(max))
+    }
+
+    /// Combines two intervals for a topic shared by multiple entities.  The topic is sent often enough for both.
+    pub fn strictest(self, other: PublishInterval) -> Self {
+        Self::new(self.min.min(other.min), self.max.min(other.max))
+    }
+}
+
+struct Schedule {
+    interval: PublishInterval,
+    last_sent: Option<Instant>,
+    last_value: Option<Value>,
+}
+
+impl Schedule {
+    fn due(&self, value: &Value, now: Instant) -> bool {
+        let Some(last_sent) = self.last_sent else {
+            return true;
+        };
+
+        let elapsed = now.duration_since(last_sent);
+        let changed = self.last_value.as_ref() != Some(value);
+        (changed && elapsed >= self.interval.min) || elapsed >= self.interval.max
+    }
+}
+
+/// Decides which telemetry vars are published each tick.  Telemetry is sampled faster than most entities need, so
+/// each var is only sent when it changes (at most every `min`) or as a heartbeat every `max`.
+///
+/// Vars are kept in order, so due vars are always published in the same order.
+#[derive(Default)]
+pub(crate) struct Scheduler {
+    schedules: BTreeMap<String, Schedule>,
+}
+
+impl Scheduler {
+    /// Adds a var to the schedule.  A var that is already scheduled keeps the strictest of the intervals.
+    pub fn register(&mut self, var: &str, interval: PublishInterval) {
+        self.schedules
+            .entry(var.to_string())
+            .and_modify(|schedule| schedule.interval = schedule.interval.strictest(interval))
+            .or_insert(Schedule {
+                interval,
+                last_sent: None,
+                last_value: None,
+            });
+    }
+
+    pub fn clear(&mut self) {
+        self.schedules.clear();
+    }
+
+    /// Returns the vars to publish this tick, and marks them as sent.
+    pub fn tick(&mut self, telemetry: &Map<String, Value>, now: Instant) -> Vec<(String, Value)> {
+        let mut due = Vec::new();
+
+        for (var, schedule) in self.schedules.iter_mut() {
+            let Some(value) = telemetry.get(var) else {
+                continue;
+            };
+
+            if schedule.due(value, now) {
+                schedule.last_sent = Some(now);
+                schedule.last_value = Some(value.clone());
+                due.push((var.clone(), value.clone()));
+            }
+        }
+
+        due
+    }
+
+    /// Re-sends the last value of any var that has gone `max` without being published.  Keeps steady values from
+    /// expiring in HA when telemetry stops arriving, eg while iRacing is loading.
+    pub fn heartbeat(&mut self, now: Instant) -> Vec<(String, Value)> {
+        let mut due = Vec::new();
+
+        for (var, schedule) in self.schedules.iter_mut() {
+            if let (Some(last_sent), Some(value)) = (schedule.last_sent, &schedule.last_value) {
+                if now.duration_since(last_sent) >= schedule.interval.max {
+                    schedule.last_sent = Some(now);
+                    due.push((var.clone(), value.clone()));
+                }
+            }
+        }
+
+        due
+    }
+}
+
+/// Lets something through at most once per interval.
+pub(crate) struct Throttle {
+    interval: Duration,
+    last: Option<Instant>,
+}
+
+impl Throttle {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: None,
+        }
+    }
+
+    /// Restarts the interval, for when the thing was sent some other way.
+    pub fn mark(&mut self, now: Instant) {
+        self.last = Some(now);
+    }
+
+    pub fn ready(&mut self, now: Instant) -> bool {
+        match self.last {
+            Some(last) if now.duration_since(last) < self.interval => false,
+            _ => {
+                self.last = Some(now);
+                true
+            }
+        }
+    }
+}
+
+/// Reads an interval in seconds from an env var
+pub(crate) fn interval_from_env(name: &str, default: Duration) -> Duration {
+    match std::env::var(name) {
+        Ok(seconds) => match seconds.parse::<f64>().map(Duration::try_from_secs_f64) {
+            Ok(Ok(interval)) => interval,
+            _ => {
+                log::error!("Invalid {} {}, using {:?}", name, seconds, default);
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn interval(min: f64, max: f64) -> PublishInterval {
+        PublishInterval::new(Duration::from_secs_f64(min), Duration::from_secs_f64(max))
+    }
+
+    fn telemetry(value: Value) -> Map<String, Value> {
+        let mut map = Map::new();
+        map.insert("AirTemp".to_string(), value);
+        map
+    }
+
+    #[test]
+    fn should_publish_first_value() {
+        let mut scheduler = Scheduler::default();
+        scheduler.register("AirTemp", interval(1., 10.));
+
+        let due = scheduler.tick(&telemetry(json!(20.)), Instant::now());
+        assert_eq!(due, vec![("AirTemp".to_string(), json!(20.))]);
+    }
+
+    #[test]
+    fn should_wait_min_interval_for_changes() {
+        let mut scheduler = Scheduler::default();
+        scheduler.register("AirTemp", interval(1., 10.));
+        let start = Instant::now();
+
+        scheduler.tick(&telemetry(json!(20.)), start);
+        assert!(scheduler
+            .tick(&telemetry(json!(21.)), start + Duration::from_millis(500))
+            .is_empty());
+        assert_eq!(
+            scheduler.tick(&telemetry(json!(21.)), start + Duration::from_secs(1)),
+            vec![("AirTemp".to_string(), json!(21.))]
+        );
+    }
+
+    #[test]
+    fn should_only_resend_unchanged_value_after_max_interval() {
+        let mut scheduler = Scheduler::default();
+        scheduler.register("AirTemp", interval(1., 10.));
+        let start = Instant::now();
+
+        scheduler.tick(&telemetry(json!(20.)), start);
+        assert!(scheduler
+            .tick(&telemetry(json!(20.)), start + Duration::from_secs(5))
+            .is_empty());
+        assert_eq!(
+            scheduler
+                .tick(&telemetry(json!(20.)), start + Duration::from_secs(10))
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn should_heartbeat_last_value() {
+        let mut scheduler = Scheduler::default();
+        scheduler.register("AirTemp", interval(1., 10.));
+        let start = Instant::now();
+
+        assert!(scheduler.heartbeat(start).is_empty());
+        scheduler.tick(&telemetry(json!(20.)), start);
+        assert!(scheduler
+            .heartbeat(start + Duration::from_secs(5))
+            .is_empty());
+        assert_eq!(
+            scheduler.heartbeat(start + Duration::from_secs(10)),
+            vec![("AirTemp".to_string(), json!(20.))]
+        );
+    }
+
+    #[test]
+    fn should_tie_expiry_to_heartbeat() {
+        assert_eq!(interval(0.5, 5.).expire_after(), 15);
+        assert_eq!(interval(0.5, 10.).within_expiry(6), interval(0.5, 2.));
+    }
+
+    #[test]
+    fn should_keep_strictest_interval() {
+        let combined = interval(1., 10.).strictest(interval(5., 8.));
+        assert_eq!(combined, interval(1., 8.));
+    }
+
+    #[test]
+    fn should_throttle() {
+        let mut throttle = Throttle::new(Duration::from_secs(1));
+        let start = Instant::now();
+        assert!(throttle.ready(start));
+        assert!(!throttle.ready(start + Duration::from_millis(500)));
+        assert!(throttle.ready(start + Duration::from_secs(1)));
+    }
+}
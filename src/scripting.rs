@@ -0,0 +1,331 @@
+use ha_mqtt::components::sensor::Sensor;
+use rhai::{CallFnOptions, Dynamic, Engine, Map as RhaiMap, Scope, AST};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::config::entities::DeviceKind;
+use crate::devices::Devices;
+use crate::irmqtt::client::DiscoveryPrepPacket;
+use crate::scheduler::{PublishInterval, Scheduler, Throttle};
+
+pub(crate) const SCRIPT_STATE: &str = "hairmqtt/script";
+
+/// How often the script file is checked for changes
+const RELOAD_CHECK: Duration = Duration::from_secs(2);
+
+/// Stops a runaway script from stalling the telemetry thread
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+/// Key the outputs are scheduled under
+const OUTPUTS: &str = "outputs";
+
+/// An output the script wants discovered, from its `entities()` function.  Keyed by the output name.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub(crate) struct ScriptEntity {
+    pub name: Option<String>,
+    pub device: DeviceKind,
+    pub icon: Option<String>,
+    pub unit: Option<String>,
+    pub device_class: Option<String>,
+    pub state_class: Option<String>,
+}
+
+/// Runs a user's Rhai script on each telemetry tick.
+///
+/// The script defines `fn tick(telemetry, session)`, which returns a map of outputs.  `this` is a map kept between
+/// ticks (and reloads), for state like a stint timer.  An optional `fn entities()` returns a map of output name to
+/// entity options, which are discovered like the built in entities.  The outputs are published to `hairmqtt/script`.
+pub(crate) struct ScriptHost {
+    engine: Engine,
+    path: PathBuf,
+    ast: Option<AST>,
+    modified: Option<SystemTime>,
+    reload_check: Throttle,
+    state: Dynamic,
+    session: Dynamic,
+    entities: BTreeMap<String, ScriptEntity>,
+    interval: PublishInterval,
+    scheduler: Scheduler,
+    last_error: Option<String>,
+}
+
+impl ScriptHost {
+    /// Enabled by `SCRIPT_FILE`.  The outputs are published at the entities' default interval.
+    pub fn from_env(interval: PublishInterval) -> Option<Self> {
+        let path = std::env::var("SCRIPT_FILE").ok()?;
+        let mut host = Self::new(PathBuf::from(path), interval);
+        host.reload();
+        Some(host)
+    }
+
+    fn new(path: PathBuf, interval: PublishInterval) -> Self {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+
+        let mut scheduler = Scheduler::default();
+        scheduler.register(OUTPUTS, interval);
+
+        Self {
+            engine,
+            path,
+            ast: None,
+            modified: None,
+            reload_check: Throttle::new(RELOAD_CHECK),
+            state: Dynamic::from_map(RhaiMap::new()),
+            session: Dynamic::UNIT,
+            entities: BTreeMap::new(),
+            interval,
+            scheduler,
+            last_error: None,
+        }
+    }
+
+    /// Recompiles the script if the file has changed.  Returns true if it was reloaded, so its entities can be
+    /// discovered again.  A script that fails to compile is logged and the last good one keeps running.
+    pub fn check_reload(&mut self, now: Instant) -> bool {
+        if !self.reload_check.ready(now) {
+            return false;
+        }
+
+        let modified = std::fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        if modified == self.modified {
+            return false;
+        }
+        self.reload()
+    }
+
+    fn reload(&mut self) -> bool {
+        self.modified = std::fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+
+        let ast = match self.engine.compile_file(self.path.clone()) {
+            Ok(ast) => ast,
+            Err(e) => {
+                log::error!("Failed to load script {}: {}", self.path.display(), e);
+                return false;
+            }
+        };
+
+        self.entities = if ast.iter_functions().any(|f| f.name == "entities") {
+            let entities = self.call(&ast, "entities", ()).and_then(|entities| {
+                rhai::serde::from_dynamic(&entities).map_err(|e| e.to_string())
+            });
+            match entities {
+                Ok(entities) => entities,
+                Err(e) => {
+                    log::error!(
+                        "Invalid entities() in script {}: {}",
+                        self.path.display(),
+                        e
+                    );
+                    BTreeMap::new()
+                }
+            }
+        } else {
+            BTreeMap::new()
+        };
+
+        log::info!("Loaded script {}", self.path.display());
+        self.ast = Some(ast);
+        self.last_error = None;
+        true
+    }
+
+    /// Keeps the session for the script.  Converted once per update rather than every tick.
+    pub fn session(&mut self, session: &Value) {
+        self.session = rhai::serde::to_dynamic(session).unwrap_or(Dynamic::UNIT);
+    }
+
+    /// Forgets the session and the script's state, so a new session starts fresh.
+    pub fn clear(&mut self) {
+        self.session = Dynamic::UNIT;
+        self.state = Dynamic::from_map(RhaiMap::new());
+        self.scheduler.clear();
+        self.scheduler.register(OUTPUTS, self.interval);
+    }
+
+    /// Runs `tick` and returns the outputs when they are due to be published.
+    pub fn tick(&mut self, telemetry: &Map<String, Value>, now: Instant) -> Option<Value> {
+        let ast = self.ast.clone()?;
+        let outputs = rhai::serde::to_dynamic(telemetry)
+            .map_err(|e| e.to_string())
+            .and_then(|telemetry| self.call(&ast, "tick", (telemetry, self.session.clone())))
+            .and_then(|outputs| {
+                rhai::serde::from_dynamic::<Value>(&outputs).map_err(|e| e.to_string())
+            })
+            .and_then(|outputs| match outputs {
+                Value::Object(_) => Ok(outputs),
+                _ => Err("tick must return a map".to_string()),
+            });
+
+        match outputs {
+            Ok(outputs) => {
+                self.last_error = None;
+                let mut scheduled = Map::new();
+                scheduled.insert(OUTPUTS.to_string(), outputs);
+                self.scheduler
+                    .tick(&scheduled, now)
+                    .into_iter()
+                    .next()
+                    .map(|(_, outputs)| outputs)
+            }
+            Err(e) => {
+                // Runs every tick, so the same error is only logged once
+                if self.last_error.as_ref() != Some(&e) {
+                    log::error!("Script {} failed: {}", self.path.display(), e);
+                    self.last_error = Some(e);
+                }
+                None
+            }
+        }
+    }
+
+    /// Re-sends the last outputs if they have gone a heartbeat without being published.
+    pub fn heartbeat(&mut self, now: Instant) -> Option<Value> {
+        self.scheduler
+            .heartbeat(now)
+            .into_iter()
+            .next()
+            .map(|(_, outputs)| outputs)
+    }
+
+    fn call(
+        &mut self,
+        ast: &AST,
+        name: &str,
+        args: impl rhai::FuncArgs,
+    ) -> Result<Dynamic, String> {
+        let options = CallFnOptions::new()
+            .eval_ast(false)
+            .bind_this_ptr(&mut self.state);
+
+        self.engine
+            .call_fn_with_options::<Dynamic>(options, &mut Scope::new(), ast, name, args)
+            .map_err(|e| e.to_string())
+    }
+
+    /// Sensors for the outputs declared by `entities()`
+    pub fn discovery_packet(&self, devices: &Devices) -> Vec<DiscoveryPrepPacket> {
+        self.entities
+            .iter()
+            .map(|(key, entity)| {
+                let mut sensor = Sensor::new(SCRIPT_STATE)
+                    .with_name(entity.name.clone().unwrap_or_else(|| key.clone()))
+                    .with_unique_id(format!("hairmqtt-script-{}", key))
+                    .with_object_id(format!("script_{}", key))
+                    .with_device(devices.get(entity.device))
+                    .with_value_template(format!("{{{{ value_json.{} }}}}", key));
+                sensor.icon = entity.icon.clone();
+                sensor.unit_of_measurement = entity.unit.clone();
+
+                let mut extra = vec![("expire_after", Value::from(self.interval.expire_after()))];
+                if let Some(device_class) = &entity.device_class {
+                    extra.push(("device_class", Value::from(device_class.as_str())));
+                }
+                if let Some(state_class) = &entity.state_class {
+                    extra.push(("state_class", Value::from(state_class.as_str())));
+                }
+                crate::prepare_payload_with(sensor, &extra)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const STINT_TIMER: &str = r#"
+fn entities() {
+    #{ stint: #{ name: "Stint Time", unit: "s", device_class: "duration" } }
+}
+
+fn tick(telemetry, session) {
+    if this.start == () || !telemetry.IsOnTrack {
+        this.start = telemetry.SessionTime;
+    }
+    #{ stint: telemetry.SessionTime - this.start, track: session.weekend_info.track_name }
+}
+"#;
+
+    fn host(name: &str, script: &str) -> ScriptHost {
+        let path =
+            std::env::temp_dir().join(format!("hairmqtt-{}-{}.rhai", name, std::process::id()));
+        std::fs::write(&path, script).unwrap();
+
+        let interval = PublishInterval::new(Duration::ZERO, Duration::from_secs(5));
+        let mut host = ScriptHost::new(path, interval);
+        assert!(host.reload());
+        host
+    }
+
+    fn telemetry(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn should_keep_state_between_ticks() {
+        let mut host = host("stint", STINT_TIMER);
+        host.session(&json!({ "weekend_info": { "track_name": "spa" } }));
+        let now = Instant::now();
+
+        let on_track = |time: f64| telemetry(json!({ "IsOnTrack": true, "SessionTime": time }));
+        assert_eq!(
+            host.tick(&on_track(100.), now),
+            Some(json!({ "stint": 0., "track": "spa" }))
+        );
+        assert_eq!(
+            host.tick(&on_track(130.), now),
+            Some(json!({ "stint": 30., "track": "spa" }))
+        );
+
+        let in_pits = telemetry(json!({ "IsOnTrack": false, "SessionTime": 140. }));
+        assert_eq!(host.tick(&in_pits, now).unwrap()["stint"], json!(0.));
+        assert_eq!(
+            host.tick(&on_track(150.), now).unwrap()["stint"],
+            json!(10.)
+        );
+    }
+
+    #[test]
+    fn should_read_declared_entities() {
+        let host = host("entities", STINT_TIMER);
+        assert_eq!(
+            host.entities.get("stint"),
+            Some(&ScriptEntity {
+                name: Some("Stint Time".to_string()),
+                unit: Some("s".to_string()),
+                device_class: Some("duration".to_string()),
+                ..ScriptEntity::default()
+            })
+        );
+    }
+
+    #[test]
+    fn should_keep_last_good_script() {
+        let mut host = host(
+            "broken",
+            "fn tick(telemetry, session) { #{ gear: telemetry.Gear } }",
+        );
+        std::fs::write(&host.path, "fn tick(telemetry, session) { #{ gear: ").unwrap();
+        assert!(!host.reload());
+
+        let outputs = host.tick(&telemetry(json!({ "Gear": 3 })), Instant::now());
+        assert_eq!(outputs, Some(json!({ "gear": 3 })));
+    }
+
+    #[test]
+    fn should_not_publish_failed_ticks() {
+        let mut host = host("fails", "fn tick(telemetry, session) { 42 }");
+        assert_eq!(host.tick(&Map::new(), Instant::now()), None);
+        assert!(host.last_error.is_some());
+    }
+}
@@ -0,0 +1,454 @@
+use serde::Deserialize;
+use serde_json::Value;
+use std::fmt;
+use std::str::FromStr;
+
+/// An array index in a session path.  Symbolic indices are resolved against the live session.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum PathIndex {
+    Position(usize),
+    /// The player's car, matched on `car_idx`
+    Player,
+    /// The session iRacing is currently running, matched on `session_num`
+    Current,
+    /// The car in P1 of the current session, matched on `car_idx`
+    Leader,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum PathSegment {
+    Key(String),
+    Index(PathIndex),
+}
+
+/// A path into the serialized session, eg `session_info.sessions[current].results_positions[player].lap`.
+///
+/// Keys are the snake case field names of the session.  Arrays take a number, or one of `player`, `current` and
+/// `leader`.  Lists with a `car_idx` (or `session_num` for `current`) are matched on that field, other lists use the
+/// symbolic index as a position.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) struct SessionPath {
+    segments: Vec<PathSegment>,
+}
+
+pub(crate) enum PathError {
+    Empty,
+    InvalidKey(String),
+    UnclosedIndex(String),
+    UnknownIndex(String),
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PathError::Empty => write!(f, "Session path is empty"),
+            PathError::InvalidKey(key) => write!(f, "Invalid key in session path: {}", key),
+            PathError::UnclosedIndex(part) => write!(f, "Unclosed index in session path: {}", part),
+            PathError::UnknownIndex(index) => write!(
+                f,
+                "Unknown index in session path: {}, expected a number, player, current or leader",
+                index
+            ),
+        }
+    }
+}
+
+impl fmt::Debug for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for PathError {}
+
+impl FromStr for PathIndex {
+    type Err = PathError;
+
+    fn from_str(index: &str) -> Result<Self, Self::Err> {
+        match index {
+            "player" => Ok(PathIndex::Player),
+            "current" => Ok(PathIndex::Current),
+            "leader" => Ok(PathIndex::Leader),
+            _ => index
+                .parse::<usize>()
+                .map(PathIndex::Position)
+                .map_err(|_| PathError::UnknownIndex(index.to_string())),
+        }
+    }
+}
+
+impl FromStr for SessionPath {
+    type Err = PathError;
+
+    fn from_str(path: &str) -> Result<Self, Self::Err> {
+        if path.trim().is_empty() {
+            return Err(PathError::Empty);
+        }
+
+        let mut segments = Vec::new();
+        for part in path.split('.') {
+            let (key, mut indices) = part.split_once('[').unwrap_or((part, ""));
+
+            if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(PathError::InvalidKey(part.to_string()));
+            }
+            segments.push(PathSegment::Key(key.to_string()));
+
+            // `indices` is everything after the first `[`, eg `0][player]`
+            while !indices.is_empty() {
+                let Some((index, rest)) = indices.split_once(']') else {
+                    return Err(PathError::UnclosedIndex(part.to_string()));
+                };
+                segments.push(PathSegment::Index(index.parse()?));
+
+                indices = match rest.strip_prefix('[') {
+                    Some(rest) => rest,
+                    None if rest.is_empty() => rest,
+                    None => return Err(PathError::InvalidKey(part.to_string())),
+                };
+            }
+        }
+
+        Ok(Self { segments })
+    }
+}
+
+impl TryFrom<String> for SessionPath {
+    type Error = PathError;
+
+    fn try_from(path: String) -> Result<Self, Self::Error> {
+        path.parse()
+    }
+}
+
+impl fmt::Display for PathIndex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PathIndex::Position(position) => write!(f, "{}", position),
+            PathIndex::Player => write!(f, "player"),
+            PathIndex::Current => write!(f, "current"),
+            PathIndex::Leader => write!(f, "leader"),
+        }
+    }
+}
+
+impl fmt::Display for SessionPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (idx, segment) in self.segments.iter().enumerate() {
+            match segment {
+                PathSegment::Key(key) if idx == 0 => write!(f, "{}", key)?,
+                PathSegment::Key(key) => write!(f, ".{}", key)?,
+                PathSegment::Index(index) => write!(f, "[{}]", index)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What the symbolic indices point to in the live session.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct PathContext {
+    pub player_car_idx: Option<i64>,
+    pub session_num: Option<i64>,
+    pub leader_car_idx: Option<i64>,
+}
+
+impl PathContext {
+    /// Builds the context from the serialized session.  `session_num` is the `SessionNum` telemetry var, if it has been
+    /// received.  Without it, the last session listed is used as the current one.
+    pub fn new(session: &Value, session_num: Option<i64>) -> Self {
+        let player_car_idx = session
+            .get("driver_info")
+            .and_then(|info| info.get("driver_car_idx"))
+            .and_then(Value::as_i64);
+
+        let session_num = session_num.or_else(|| {
+            session
+                .get("session_info")
+                .and_then(|info| info.get("sessions"))
+                .and_then(Value::as_array)
+                .and_then(|sessions| sessions.last())
+                .and_then(|last| last.get("session_num"))
+                .and_then(Value::as_i64)
+        });
+
+        let mut context = Self {
+            player_car_idx,
+            session_num,
+            leader_car_idx: None,
+        };
+
+        let results: SessionPath = "session_info.sessions[current].results_positions"
+            .parse()
+            .expect("Valid path");
+        context.leader_car_idx = results
+            .resolve(session, &context)
+            .and_then(Value::as_array)
+            .and_then(|positions| {
+                positions
+                    .iter()
+                    .find(|position| position.get("position").and_then(Value::as_i64) == Some(1))
+            })
+            .and_then(|leader| leader.get("car_idx"))
+            .and_then(Value::as_i64);
+
+        context
+    }
+}
+
+/// Why a path did not resolve, so `validate` can tell a typo from a session that does not have the value yet.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Unresolved {
+    /// The session has no such key, usually a typo
+    MissingKey(String),
+    /// The value under the key is empty, eg `results_positions` before the session has results
+    Empty(String),
+    /// Nothing in the list matches the index
+    NoMatch(PathIndex),
+    /// An index into something that is not a list, or a key into something that is not a map
+    WrongType(String),
+}
+
+impl Unresolved {
+    /// Whether the path can resolve in another session.  Symbolic indices and empty values depend on the session, a
+    /// missing key or a position past the end of a list does not.
+    pub fn can_resolve_later(&self) -> bool {
+        match self {
+            Unresolved::Empty(_) => true,
+            Unresolved::NoMatch(index) => !matches!(index, PathIndex::Position(_)),
+            Unresolved::MissingKey(_) | Unresolved::WrongType(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for Unresolved {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Unresolved::MissingKey(key) => write!(f, "the session has no {}", key),
+            Unresolved::Empty(key) => write!(f, "{} is empty in the session", key),
+            Unresolved::NoMatch(index) => write!(f, "nothing matches [{}] in the session", index),
+            Unresolved::WrongType(key) => write!(f, "{} is not the type the path expects", key),
+        }
+    }
+}
+
+impl SessionPath {
+    /// Follows the path through the session.  `None` if any part of it is missing.
+    pub fn resolve<'a>(&self, session: &'a Value, context: &PathContext) -> Option<&'a Value> {
+        self.try_resolve(session, context).ok()
+    }
+
+    /// Follows the path through the session, with the reason it stopped if it does not resolve.
+    pub fn try_resolve<'a>(
+        &self,
+        session: &'a Value,
+        context: &PathContext,
+    ) -> Result<&'a Value, Unresolved> {
+        let mut value = session;
+        // The key the value was found under, for the errors
+        let mut at = String::new();
+        for segment in self.segments.iter() {
+            value = match (segment, value) {
+                (_, Value::Null) => return Err(Unresolved::Empty(at)),
+                (PathSegment::Key(key), Value::Object(map)) => {
+                    at = key.clone();
+                    map.get(key)
+                        .ok_or_else(|| Unresolved::MissingKey(key.clone()))?
+                }
+                (PathSegment::Index(index), Value::Array(items)) => {
+                    select(items, index, context)
+                        .ok_or_else(|| Unresolved::NoMatch(index.clone()))?
+                }
+                _ => return Err(Unresolved::WrongType(at)),
+            };
+        }
+        Ok(value)
+    }
+}
+
+fn select<'a>(items: &'a [Value], index: &PathIndex, context: &PathContext) -> Option<&'a Value> {
+    match index {
+        PathIndex::Position(position) => items.get(*position),
+        PathIndex::Player => find_by(items, "car_idx", context.player_car_idx?),
+        PathIndex::Leader => find_by(items, "car_idx", context.leader_car_idx?),
+        PathIndex::Current => find_by(items, "session_num", context.session_num?),
+    }
+}
+
+/// Lists keyed by `field` are matched on it.  Other lists are indexed by position.
+fn find_by<'a>(items: &'a [Value], field: &str, id: i64) -> Option<&'a Value> {
+    if items.iter().any(|item| item.get(field).is_some()) {
+        items
+            .iter()
+            .find(|item| item.get(field).and_then(Value::as_i64) == Some(id))
+    } else {
+        usize::try_from(id)
+            .ok()
+            .and_then(|position| items.get(position))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn session() -> Value {
+        json!({
+            "weekend_info": { "track_name": "spa" },
+            "driver_info": {
+                "driver_car_idx": 2,
+                "drivers": [
+                    { "car_idx": 0, "user_name": "Pace Car" },
+                    { "car_idx": 2, "user_name": "Tim Reed" },
+                    { "car_idx": 5, "user_name": "Leader" },
+                ],
+            },
+            "session_info": { "sessions": [
+                { "session_num": 0, "session_type": "Practice", "results_positions": [] },
+                { "session_num": 1, "session_type": "Race", "results_positions": [
+                    { "position": 2, "car_idx": 2, "lap": 10 },
+                    { "position": 1, "car_idx": 5, "lap": 11 },
+                ]},
+            ]},
+        })
+    }
+
+    fn resolve(path: &str, session_num: Option<i64>) -> Option<Value> {
+        let session = session();
+        let context = PathContext::new(&session, session_num);
+        let path: SessionPath = path.parse().unwrap();
+        path.resolve(&session, &context).cloned()
+    }
+
+    #[test]
+    fn should_resolve_nested_keys() {
+        assert_eq!(resolve("weekend_info.track_name", None), Some(json!("spa")));
+    }
+
+    #[test]
+    fn should_resolve_position() {
+        assert_eq!(
+            resolve("driver_info.drivers[1].user_name", None),
+            Some(json!("Tim Reed"))
+        );
+    }
+
+    #[test]
+    fn should_resolve_player_by_car_idx() {
+        assert_eq!(
+            resolve("driver_info.drivers[player].user_name", None),
+            Some(json!("Tim Reed"))
+        );
+    }
+
+    #[test]
+    fn should_resolve_current_session_from_session_num() {
+        assert_eq!(
+            resolve("session_info.sessions[current].session_type", Some(0)),
+            Some(json!("Practice"))
+        );
+        // Falls back to the last session
+        assert_eq!(
+            resolve("session_info.sessions[current].session_type", None),
+            Some(json!("Race"))
+        );
+    }
+
+    #[test]
+    fn should_resolve_each_array_level_separately() {
+        assert_eq!(
+            resolve(
+                "session_info.sessions[current].results_positions[player].lap",
+                Some(1)
+            ),
+            Some(json!(10))
+        );
+        assert_eq!(
+            resolve(
+                "session_info.sessions[current].results_positions[leader].lap",
+                Some(1)
+            ),
+            Some(json!(11))
+        );
+        assert_eq!(
+            resolve("driver_info.drivers[leader].user_name", Some(1)),
+            Some(json!("Leader"))
+        );
+    }
+
+    #[test]
+    fn should_tell_why_a_path_does_not_resolve() {
+        let session = session();
+        let context = PathContext::new(&session, Some(0));
+        let unresolved = |path: &str| {
+            path.parse::<SessionPath>()
+                .unwrap()
+                .try_resolve(&session, &context)
+                .unwrap_err()
+        };
+
+        let typo = unresolved("weekend_info.trak_name");
+        assert_eq!(typo, Unresolved::MissingKey("trak_name".to_string()));
+        assert!(!typo.can_resolve_later());
+
+        // The practice session has no results yet
+        let no_results = unresolved("session_info.sessions[current].results_positions[player]");
+        assert_eq!(no_results, Unresolved::NoMatch(PathIndex::Player));
+        assert!(no_results.can_resolve_later());
+        assert!(!unresolved("driver_info.drivers[7]").can_resolve_later());
+        assert!(!unresolved("weekend_info.track_name.short").can_resolve_later());
+    }
+
+    #[test]
+    fn should_not_resolve_missing() {
+        assert_eq!(resolve("weekend_info.missing", None), None);
+        assert_eq!(
+            resolve(
+                "session_info.sessions[current].results_positions[player].lap",
+                Some(0)
+            ),
+            None
+        );
+        assert_eq!(resolve("driver_info.drivers[9]", None), None);
+    }
+
+    #[test]
+    fn should_index_positionally_without_key_field() {
+        let session = json!({ "list": [[1, 2], [3, 4]] });
+        let context = PathContext {
+            player_car_idx: Some(1),
+            ..Default::default()
+        };
+        let path: SessionPath = "list[player][0]".parse().unwrap();
+        assert_eq!(path.resolve(&session, &context), Some(&json!(3)));
+    }
+
+    #[test]
+    fn should_display_parsed_path() {
+        let path = "session_info.sessions[current].results_positions[0][player].lap";
+        assert_eq!(path.parse::<SessionPath>().unwrap().to_string(), path);
+    }
+
+    #[test]
+    fn should_reject_invalid_paths() {
+        assert!(matches!("".parse::<SessionPath>(), Err(PathError::Empty)));
+        assert!(matches!(
+            "driver_info..drivers".parse::<SessionPath>(),
+            Err(PathError::InvalidKey(_))
+        ));
+        assert!(matches!(
+            "drivers[player".parse::<SessionPath>(),
+            Err(PathError::UnclosedIndex(_))
+        ));
+        assert!(matches!(
+            "drivers[me]".parse::<SessionPath>(),
+            Err(PathError::UnknownIndex(_))
+        ));
+        assert!(matches!(
+            "drivers[0]name".parse::<SessionPath>(),
+            Err(PathError::InvalidKey(_))
+        ));
+    }
+}
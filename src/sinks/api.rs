@@ -0,0 +1,537 @@
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, TcpListener, TcpStream};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tungstenite::{Message, WebSocket};
+
+use super::sink::{parse_list, Sink};
+use crate::session_path::{PathContext, SessionPath};
+
+/// There is no authentication, so the api is only reachable from this machine unless `API_HOST` says otherwise.
+const DEFAULT_HOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+type Subscribers = Arc<Mutex<Vec<SyncSender<Arc<Map<String, Value>>>>>>;
+
+/// Telemetry updates a stream can fall behind by.  Newer updates are dropped for that client until it catches up.
+const STREAM_BACKLOG: usize = 4;
+
+/// Time a client has to send its request, and to take each write, before it is dropped.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest a stream goes without reading from the client, to answer pings and notice closes.
+const STREAM_POLL: Duration = Duration::from_millis(100);
+
+/// Read timeout of a stream once connected.  Short, so reading from the client never holds up the telemetry.
+const STREAM_READ_TIMEOUT: Duration = Duration::from_millis(5);
+
+/// The api only takes GET requests, so anything with a longer head is not for us.
+const MAX_REQUEST_HEAD: usize = 8192;
+
+/// Latest telemetry and session, served on the REST endpoints
+#[derive(Default)]
+struct ApiState {
+    telemetry: Arc<Map<String, Value>>,
+    session: Map<String, Value>,
+}
+
+/// Local http api for overlays and other tools that want the telemetry without going through a broker.
+///
+/// Enabled by setting `API_PORT`.  Listens on localhost, or on `API_HOST` (eg `0.0.0.0` for the whole network).
+/// - `GET /telemetry` latest telemetry
+/// - `GET /session` latest session
+/// - `GET /standings` results of the current session, with driver names
+/// - `GET /stream` WebSocket, sends each telemetry update as it arrives
+///
+/// `/telemetry` and `/stream` take `?vars=Speed,RPM` to only send a subset of the vars.
+pub(crate) struct ApiSink {
+    state: Arc<Mutex<ApiState>>,
+    subscribers: Subscribers,
+}
+
+/// The parts of a request the api looks at.
+#[derive(Debug, PartialEq)]
+struct ApiRequest {
+    path: String,
+    vars: Vec<String>,
+    /// Asks for a WebSocket upgrade
+    websocket: bool,
+    /// Length of the request head, up to and including the blank line
+    head_len: usize,
+}
+
+impl ApiSink {
+    /// Starts the http server if `API_PORT` is set.
+    pub fn from_env() -> Option<Self> {
+        let port = std::env::var("API_PORT").ok()?;
+        let port = match port.parse::<u16>() {
+            Ok(port) => port,
+            Err(_) => {
+                log::error!("Invalid API_PORT {}, api disabled", port);
+                return None;
+            }
+        };
+
+        let host = match std::env::var("API_HOST") {
+            Ok(host) => match host.parse::<IpAddr>() {
+                Ok(host) => host,
+                Err(_) => {
+                    log::error!("Invalid API_HOST {}, api disabled", host);
+                    return None;
+                }
+            },
+            Err(_) => DEFAULT_HOST,
+        };
+
+        let listener = match TcpListener::bind((host, port)) {
+            Ok(listener) => listener,
+            Err(e) => {
+                log::error!("Failed to start api on {}:{}: {:?}", host, port, e);
+                return None;
+            }
+        };
+
+        log::info!("Serving api on {}:{}", host, port);
+        Some(Self::serve(listener))
+    }
+
+    /// Handles each connection on its own thread, streams stay open for as long as the client does.
+    fn serve(listener: TcpListener) -> Self {
+        let state = Arc::new(Mutex::new(ApiState::default()));
+        let subscribers: Subscribers = Arc::new(Mutex::new(Vec::new()));
+
+        let server_state = state.clone();
+        let server_subscribers = subscribers.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let state = server_state.clone();
+                let subscribers = server_subscribers.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = handle_connection(stream, &state, &subscribers) {
+                        log::error!("Failed to respond to api request: {:?}", e);
+                    }
+                });
+            }
+        });
+
+        Self { state, subscribers }
+    }
+}
+
+impl Sink for ApiSink {
+    fn telemetry(&mut self, telemetry: &Map<String, Value>) {
+        let telemetry = Arc::new(telemetry.clone());
+        self.state.lock().unwrap().telemetry = telemetry.clone();
+
+        // A client that is behind misses this update.  Closed streams drop their receiver, which removes the subscriber.
+        self.subscribers.lock().unwrap().retain(|subscriber| {
+            match subscriber.try_send(telemetry.clone()) {
+                Ok(()) | Err(TrySendError::Full(_)) => true,
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+
+    fn session(&mut self, session: &Map<String, Value>) {
+        self.state.lock().unwrap().session = session.clone();
+    }
+
+    fn disconnected(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.telemetry = Arc::default();
+        state.session.clear();
+    }
+}
+
+fn handle_connection(
+    mut stream: TcpStream,
+    state: &Mutex<ApiState>,
+    subscribers: &Subscribers,
+) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+    let Some(request) = peek_request(&stream)? else {
+        return Ok(());
+    };
+
+    // The WebSocket handshake reads the request itself
+    if request.path == "/stream" && request.websocket {
+        subscribe(stream, request.vars, subscribers);
+        return Ok(());
+    }
+
+    // GET requests have no body, so the head is all there is to read
+    stream.read_exact(&mut vec![0; request.head_len])?;
+
+    match request.path.as_str() {
+        "/telemetry" => {
+            let telemetry = state.lock().unwrap().telemetry.clone();
+            respond_json(stream, &filter_vars(&telemetry, &request.vars))
+        }
+        "/session" => {
+            let session = state.lock().unwrap().session.clone();
+            respond_json(stream, &session)
+        }
+        "/standings" => {
+            let state = state.lock().unwrap();
+            let standings = standings(&state.telemetry, &state.session);
+            drop(state);
+            respond_json(stream, &standings)
+        }
+        "/stream" => respond(
+            stream,
+            "400 Bad Request",
+            "text/plain",
+            "Expected a WebSocket upgrade",
+        ),
+        _ => respond(stream, "404 Not Found", "text/plain", "Not Found"),
+    }
+}
+
+/// Waits for the whole request head without reading it, so a WebSocket handshake can still read it.  `None` if the
+/// client closes or does not send a complete head in time.
+fn peek_request(stream: &TcpStream) -> std::io::Result<Option<ApiRequest>> {
+    let started = Instant::now();
+    let mut head = vec![0; MAX_REQUEST_HEAD];
+
+    loop {
+        let read = stream.peek(&mut head)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        if let Some(request) = parse_request(&head[..read]) {
+            return Ok(Some(request));
+        }
+        if read == head.len() || started.elapsed() > CLIENT_TIMEOUT {
+            return Ok(None);
+        }
+        // Peeking returns straight away once part of the head is in
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Parses the request line and the `Upgrade` header.  `None` until the head is complete.
+fn parse_request(head: &[u8]) -> Option<ApiRequest> {
+    let head_len = head.windows(4).position(|end| end == b"\r\n\r\n")? + 4;
+    let head = String::from_utf8_lossy(&head[..head_len]);
+    let mut lines = head.lines();
+
+    let url = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .unwrap_or_default();
+    let (path, vars) = parse_url(url);
+
+    let websocket = lines.any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("upgrade")
+                && value.trim().eq_ignore_ascii_case("websocket")
+        })
+    });
+
+    Some(ApiRequest {
+        path,
+        vars,
+        websocket,
+        head_len,
+    })
+}
+
+fn respond(
+    mut stream: TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> std::io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+fn respond_json(stream: TcpStream, payload: &impl Serialize) -> std::io::Result<()> {
+    match serde_json::to_string(payload) {
+        Ok(body) => respond(stream, "200 OK", "application/json", &body),
+        Err(_) => respond(
+            stream,
+            "500 Internal Server Error",
+            "text/plain",
+            "Failed to serialize",
+        ),
+    }
+}
+
+/// Splits the url into the path and the vars from the query string.
+fn parse_url(url: &str) -> (String, Vec<String>) {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let vars = query
+        .split('&')
+        .find_map(|param| param.strip_prefix("vars="))
+        .map(|vars| parse_list(&vars.replace("%2C", ",")))
+        .unwrap_or_default();
+
+    (path.to_string(), vars)
+}
+
+/// No vars means every var.
+fn filter_vars(telemetry: &Map<String, Value>, vars: &[String]) -> Map<String, Value> {
+    if vars.is_empty() {
+        return telemetry.clone();
+    }
+
+    vars.iter()
+        .filter_map(|var| telemetry.get(var).map(|value| (var.clone(), value.clone())))
+        .collect()
+}
+
+/// Results of the current session joined with the driver names.  The current session is picked the same way as
+/// `[current]` in the session paths: `SessionNum` from the telemetry, or the last session listed.
+fn standings(telemetry: &Map<String, Value>, session: &Map<String, Value>) -> Vec<Value> {
+    let session = Value::Object(session.clone());
+    let session_num = telemetry.get("SessionNum").and_then(Value::as_i64);
+    let context = PathContext::new(&session, session_num);
+
+    let results: SessionPath = "session_info.sessions[current].results_positions"
+        .parse()
+        .expect("Valid path");
+    let positions = results
+        .resolve(&session, &context)
+        .and_then(Value::as_array);
+    let drivers = session
+        .get("driver_info")
+        .and_then(|info| info.get("drivers"))
+        .and_then(Value::as_array);
+
+    positions
+        .into_iter()
+        .flatten()
+        .map(|position| {
+            let mut position = position.clone();
+            let car_idx = position.get("car_idx").and_then(Value::as_i64);
+            let driver = drivers.into_iter().flatten().find(|driver| {
+                car_idx.is_some() && driver.get("car_idx").and_then(Value::as_i64) == car_idx
+            });
+
+            if let (Value::Object(entry), Some(driver)) = (&mut position, driver) {
+                for key in ["user_name", "car_number", "car_screen_name"] {
+                    if let Some(value) = driver.get(key) {
+                        entry.insert(key.to_string(), value.clone());
+                    }
+                }
+            }
+            position
+        })
+        .collect()
+}
+
+fn subscribe(stream: TcpStream, vars: Vec<String>, subscribers: &Subscribers) {
+    let socket = match tungstenite::accept(stream) {
+        Ok(socket) => socket,
+        Err(e) => {
+            log::debug!("Api stream handshake failed: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = socket.get_ref().set_read_timeout(Some(STREAM_READ_TIMEOUT)) {
+        log::error!("Failed to set up api stream: {:?}", e);
+        return;
+    }
+
+    let (sender, receiver) = sync_channel(STREAM_BACKLOG);
+    subscribers.lock().unwrap().push(sender);
+    stream_telemetry(socket, receiver, vars);
+}
+
+/// Sends every telemetry update to the socket, reading from the client in between, until either side closes.
+fn stream_telemetry(
+    mut socket: WebSocket<TcpStream>,
+    receiver: Receiver<Arc<Map<String, Value>>>,
+    vars: Vec<String>,
+) {
+    loop {
+        match receiver.recv_timeout(STREAM_POLL) {
+            Ok(telemetry) => {
+                let payload = match serde_json::to_string(&filter_vars(&telemetry, &vars)) {
+                    Ok(payload) => payload,
+                    Err(_) => {
+                        log::error!("Failed to serialize telemetry for api stream");
+                        continue;
+                    }
+                };
+
+                if let Err(e) = socket.send(Message::Text(payload)) {
+                    log::debug!("Api stream closed: {:?}", e);
+                    break;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => (),
+            // The sink is gone
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        if !read_client(&mut socket) {
+            break;
+        }
+    }
+}
+
+/// Reads everything the client has sent.  Pings are answered and a close is replied to.  `false` once the stream is
+/// closed.
+fn read_client(socket: &mut WebSocket<TcpStream>) -> bool {
+    loop {
+        match socket.read() {
+            Ok(Message::Close(_)) => {
+                let _ = socket.flush();
+                return false;
+            }
+            Ok(_) => (),
+            // Nothing more from the client, send the pongs the reads queued up
+            Err(tungstenite::Error::Io(e))
+                if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+            {
+                return socket.flush().is_ok();
+            }
+            Err(e) => {
+                log::debug!("Api stream closed: {:?}", e);
+                return false;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn serve() -> (ApiSink, u16) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        (ApiSink::serve(listener), port)
+    }
+
+    fn wait_for(mut condition: impl FnMut() -> bool) {
+        let started = Instant::now();
+        while !condition() {
+            assert!(started.elapsed() < Duration::from_secs(5), "Timed out");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn should_parse_vars_from_query() {
+        let (path, vars) = parse_url("/telemetry?vars=Speed,RPM%2CGear");
+        assert_eq!(path, "/telemetry");
+        assert_eq!(vars, vec!["Speed", "RPM", "Gear"]);
+    }
+
+    #[test]
+    fn should_parse_request_once_head_is_complete() {
+        let head =
+            b"GET /stream?vars=Speed HTTP/1.1\r\nHost: localhost\r\nupgrade: WebSocket\r\n\r\n";
+        assert_eq!(parse_request(&head[..20]), None);
+        assert_eq!(
+            parse_request(head),
+            Some(ApiRequest {
+                path: "/stream".to_string(),
+                vars: vec!["Speed".to_string()],
+                websocket: true,
+                head_len: head.len(),
+            })
+        );
+    }
+
+    #[test]
+    fn should_join_standings_with_drivers() {
+        let telemetry = json!({ "SessionNum": 1 });
+        let session = json!({
+            "session_info": { "sessions": [
+                { "session_num": 0, "results_positions": [] },
+                { "session_num": 1, "results_positions": [{ "position": 1, "car_idx": 4 }] },
+            ]},
+            "driver_info": { "drivers": [{ "car_idx": 4, "user_name": "Tim Reed", "car_number": "7" }] },
+        });
+
+        let standings = standings(telemetry.as_object().unwrap(), session.as_object().unwrap());
+        assert_eq!(
+            standings,
+            vec![
+                json!({ "position": 1, "car_idx": 4, "user_name": "Tim Reed", "car_number": "7" })
+            ]
+        );
+    }
+
+    #[test]
+    fn should_drop_updates_for_slow_subscribers() {
+        let mut sink = ApiSink {
+            state: Arc::default(),
+            subscribers: Arc::default(),
+        };
+        let (sender, receiver) = sync_channel(STREAM_BACKLOG);
+        sink.subscribers.lock().unwrap().push(sender);
+
+        let telemetry = json!({ "Speed": 41.2 });
+        for _ in 0..STREAM_BACKLOG * 3 {
+            sink.telemetry(telemetry.as_object().unwrap());
+        }
+        assert_eq!(receiver.try_iter().count(), STREAM_BACKLOG);
+        assert_eq!(sink.subscribers.lock().unwrap().len(), 1);
+
+        drop(receiver);
+        sink.telemetry(telemetry.as_object().unwrap());
+        assert!(sink.subscribers.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn should_serve_latest_telemetry() {
+        let (mut sink, port) = serve();
+        sink.telemetry(json!({ "Speed": 41.2, "RPM": 6500 }).as_object().unwrap());
+
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream
+            .write_all(b"GET /telemetry?vars=Speed HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(r#"{"Speed":41.2}"#));
+    }
+
+    #[test]
+    fn should_stream_and_answer_control_frames() {
+        let (mut sink, port) = serve();
+        let (mut client, _) =
+            tungstenite::connect(format!("ws://127.0.0.1:{}/stream?vars=Speed", port)).unwrap();
+        wait_for(|| !sink.subscribers.lock().unwrap().is_empty());
+
+        sink.telemetry(json!({ "Speed": 41.2, "RPM": 6500 }).as_object().unwrap());
+        assert_eq!(
+            client.read().unwrap(),
+            Message::Text(r#"{"Speed":41.2}"#.to_string())
+        );
+
+        client.send(Message::Ping(b"rig".to_vec())).unwrap();
+        assert_eq!(client.read().unwrap(), Message::Pong(b"rig".to_vec()));
+
+        // Closing ends the stream, which removes the subscriber
+        client.close(None).unwrap();
+        while client.read().is_ok() {}
+        let telemetry = json!({ "Speed": 42.0 });
+        wait_for(|| {
+            sink.telemetry(telemetry.as_object().unwrap());
+            sink.subscribers.lock().unwrap().is_empty()
+        });
+    }
+}
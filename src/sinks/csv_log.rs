@@ -0,0 +1,279 @@
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::sink::{parse_list, Sink};
+use crate::recording::VarInfo;
+
+/// Writes telemetry to CSV, one file per session.  Columns are the var headers, after a `Timestamp` column in unix ms.
+///
+/// Enabled by setting `CSV_DIR`.  `CSV_VARS` limits the columns to a comma separated list of vars.  A new file is started
+/// when the var headers change, or when `SessionNum` or the subsession changes.  Files are opened on the first row, once
+/// the vars, the subsession and `SessionNum` are all known, so a session load leaves a single file.
+pub(crate) struct CsvSink {
+    dir: PathBuf,
+    vars: Vec<String>,
+    columns: Vec<String>,
+    writer: Option<csv::Writer<File>>,
+    sub_session_id: Option<i64>,
+    session_num: Option<i64>,
+}
+
+impl CsvSink {
+    pub fn from_env() -> Option<Self> {
+        let dir = PathBuf::from(std::env::var("CSV_DIR").ok()?);
+        if let Err(e) = std::fs::create_dir_all(&dir) {
+            log::error!("Failed to create csv directory {}: {:?}", dir.display(), e);
+            return None;
+        }
+
+        let vars = std::env::var("CSV_VARS")
+            .map(|vars| parse_list(&vars))
+            .unwrap_or_default();
+
+        Some(Self {
+            dir,
+            vars,
+            columns: Vec::new(),
+            writer: None,
+            sub_session_id: None,
+            session_num: None,
+        })
+    }
+
+    /// Starts a new file and writes the header row.  Waits until the columns and the session are known.
+    fn open(&mut self) {
+        if self.columns.is_empty() || self.sub_session_id.is_none() || self.session_num.is_none() {
+            return;
+        }
+
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or_default();
+        let name = format!(
+            "{}_{}_{}.csv",
+            started,
+            self.sub_session_id.unwrap_or_default(),
+            self.session_num.unwrap_or_default()
+        );
+        let path = self.dir.join(name);
+
+        let mut writer = match csv::Writer::from_path(&path) {
+            Ok(writer) => writer,
+            Err(e) => {
+                log::error!("Failed to create csv file {}: {:?}", path.display(), e);
+                return;
+            }
+        };
+
+        let header = std::iter::once("Timestamp").chain(self.columns.iter().map(String::as_str));
+        if let Err(e) = writer.write_record(header) {
+            log::error!("Failed to write csv header: {:?}", e);
+        }
+
+        log::info!("Logging telemetry to {}", path.display());
+        self.writer = Some(writer);
+    }
+
+    fn close(&mut self) {
+        if let Some(mut writer) = self.writer.take() {
+            if let Err(e) = writer.flush() {
+                log::error!("Failed to flush csv file: {:?}", e);
+            }
+        }
+    }
+}
+
+impl Sink for CsvSink {
+    fn variables(&mut self, var_headers: &HashMap<String, VarInfo>) {
+        let mut columns: Vec<String> = if self.vars.is_empty() {
+            var_headers.keys().cloned().collect()
+        } else {
+            self.vars
+                .iter()
+                .filter(|var| var_headers.contains_key(*var))
+                .cloned()
+                .collect()
+        };
+        columns.sort();
+
+        self.columns = columns;
+        self.close();
+    }
+
+    fn telemetry(&mut self, telemetry: &Map<String, Value>) {
+        let session_num = telemetry.get("SessionNum").and_then(Value::as_i64);
+        if session_num.is_some() && session_num != self.session_num {
+            self.session_num = session_num;
+            self.close();
+        }
+
+        if self.writer.is_none() {
+            self.open();
+        }
+        let Some(writer) = self.writer.as_mut() else {
+            return;
+        };
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_millis())
+            .unwrap_or_default();
+
+        let row = std::iter::once(timestamp.to_string()).chain(
+            self.columns
+                .iter()
+                .map(|column| telemetry.get(column).map(cell).unwrap_or_default()),
+        );
+        if let Err(e) = writer.write_record(row) {
+            log::error!("Failed to write csv row: {:?}", e);
+        }
+    }
+
+    fn session(&mut self, session: &Map<String, Value>) {
+        let sub_session_id = session
+            .get("weekend_info")
+            .and_then(|info| info.get("sub_session_id"))
+            .and_then(Value::as_i64);
+
+        if sub_session_id != self.sub_session_id {
+            self.sub_session_id = sub_session_id;
+            self.close();
+        }
+    }
+
+    fn disconnected(&mut self) {
+        self.close();
+        self.columns.clear();
+        self.sub_session_id = None;
+        self.session_num = None;
+    }
+}
+
+/// Strings are written as is, everything else (including arrays) as JSON.
+fn cell(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.clone(),
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn var(name: &str) -> (String, VarInfo) {
+        let info = VarInfo {
+            name: name.to_string(),
+            var_type: "Float".to_string(),
+            units: String::new(),
+            count: 1,
+            description: String::new(),
+        };
+        (name.to_string(), info)
+    }
+
+    fn sink(name: &str, vars: &[&str]) -> CsvSink {
+        let dir =
+            std::env::temp_dir().join(format!("hairmqtt-csv-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        CsvSink {
+            dir,
+            vars: vars.iter().map(|var| var.to_string()).collect(),
+            columns: Vec::new(),
+            writer: None,
+            sub_session_id: None,
+            session_num: None,
+        }
+    }
+
+    /// Contents of each file written, oldest first
+    fn files(sink: &mut CsvSink) -> Vec<String> {
+        sink.close();
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(&sink.dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        paths.sort();
+        let files = paths
+            .iter()
+            .map(|path| std::fs::read_to_string(path).unwrap())
+            .collect();
+        std::fs::remove_dir_all(&sink.dir).unwrap();
+        files
+    }
+
+    fn session(sub_session_id: i64) -> Map<String, Value> {
+        json!({ "weekend_info": { "sub_session_id": sub_session_id } })
+            .as_object()
+            .unwrap()
+            .clone()
+    }
+
+    fn telemetry(session_num: i64, speed: f64) -> Map<String, Value> {
+        json!({ "SessionNum": session_num, "Speed": speed, "RPM": 6500.0 })
+            .as_object()
+            .unwrap()
+            .clone()
+    }
+
+    #[test]
+    fn should_write_one_file_per_session() {
+        let mut sink = sink("sessions", &[]);
+        sink.variables(&HashMap::from([
+            var("Speed"),
+            var("RPM"),
+            var("SessionNum"),
+        ]));
+        sink.session(&session(71234567));
+        sink.telemetry(&telemetry(0, 40.0));
+        sink.telemetry(&telemetry(0, 41.0));
+        // Session updates for the same subsession keep the file
+        sink.session(&session(71234567));
+        sink.telemetry(&telemetry(0, 42.0));
+
+        let files = files(&mut sink);
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].lines().count(), 4);
+    }
+
+    #[test]
+    fn should_start_a_file_when_the_session_changes() {
+        let mut sink = sink("rotate", &[]);
+        // The session arrives before the vars on reconnect
+        sink.session(&session(71234567));
+        sink.variables(&HashMap::from([var("Speed"), var("SessionNum")]));
+        sink.telemetry(&telemetry(0, 40.0));
+        sink.telemetry(&telemetry(1, 41.0));
+
+        sink.disconnected();
+        sink.variables(&HashMap::from([var("Speed"), var("SessionNum")]));
+        // Nothing is written until the session is known
+        sink.telemetry(&telemetry(0, 42.0));
+        sink.session(&session(71234568));
+        sink.telemetry(&telemetry(0, 43.0));
+
+        let files = files(&mut sink);
+        assert_eq!(files.len(), 3);
+        assert!(files.iter().all(|file| file.lines().count() == 2));
+    }
+
+    #[test]
+    fn should_take_header_from_var_headers() {
+        let mut sink = sink("header", &["Speed", "Missing", "RPM"]);
+        sink.variables(&HashMap::from([var("Speed"), var("RPM"), var("Gear")]));
+        sink.session(&session(71234567));
+        sink.telemetry(&telemetry(0, 40.5));
+
+        let files = files(&mut sink);
+        let lines: Vec<&str> = files[0].lines().collect();
+        assert_eq!(lines[0], "Timestamp,RPM,Speed");
+        assert!(lines[1].ends_with(",6500.0,40.5"));
+    }
+}
@@ -0,0 +1,300 @@
+use serde_json::{Map, Value};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::sink::{parse_list, Sink};
+use crate::devices::car_screen_name;
+
+const MEASUREMENT: &str = "iracing";
+
+/// Lines are batched before being written to the http endpoint.
+const BATCH_SIZE: usize = 500;
+const BATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Lines that can wait for the writer thread.  Past that, lines are dropped until it catches up.
+const BACKLOG: usize = BATCH_SIZE * 4;
+
+/// A database that is down or slow fails the batch instead of holding up the writer.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where the line protocol goes
+enum InfluxOutput {
+    File(File),
+    /// Lines are handed to a writer thread so a slow database does not hold up the telemetry loop.
+    Http(SyncSender<String>),
+}
+
+/// Writes telemetry as InfluxDB line protocol, to a file or to an http write endpoint.
+///
+/// Enabled by setting `INFLUX_FILE` to a file to append to, or `INFLUX_URL` to a write endpoint
+/// (eg `http://localhost:8086/api/v2/write?org=home&bucket=iracing&precision=ns`).  `INFLUX_TOKEN` is sent as the api
+/// token, and `INFLUX_VARS` limits the fields to a comma separated list of vars.
+pub(crate) struct InfluxSink {
+    output: InfluxOutput,
+    vars: Vec<String>,
+    rig: String,
+    car: String,
+    // Lines dropped since the writer last kept up
+    dropped: u64,
+}
+
+impl InfluxSink {
+    pub fn from_env() -> Option<Self> {
+        let output = if let Ok(path) = std::env::var("INFLUX_FILE") {
+            match OpenOptions::new().create(true).append(true).open(&path) {
+                Ok(file) => InfluxOutput::File(file),
+                Err(e) => {
+                    log::error!("Failed to open influx file {}: {:?}", path, e);
+                    return None;
+                }
+            }
+        } else if let Ok(url) = std::env::var("INFLUX_URL") {
+            let token = std::env::var("INFLUX_TOKEN").ok();
+            let (sender, receiver) = sync_channel(BACKLOG);
+            std::thread::spawn(move || write_http(&url, token.as_deref(), receiver));
+            InfluxOutput::Http(sender)
+        } else {
+            return None;
+        };
+
+        let vars = std::env::var("INFLUX_VARS")
+            .map(|vars| parse_list(&vars))
+            .unwrap_or_default();
+        let rig = std::env::var("RIG_ID").unwrap_or("default".to_string());
+
+        Some(Self {
+            output,
+            vars,
+            rig,
+            car: String::new(),
+            dropped: 0,
+        })
+    }
+}
+
+impl Sink for InfluxSink {
+    fn telemetry(&mut self, telemetry: &Map<String, Value>) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_nanos())
+            .unwrap_or_default();
+
+        let Some(line) = to_line(&self.rig, &self.car, telemetry, &self.vars, timestamp) else {
+            return;
+        };
+
+        match &mut self.output {
+            InfluxOutput::File(file) => {
+                if let Err(e) = writeln!(file, "{}", line) {
+                    log::error!("Failed to write influx line: {:?}", e);
+                }
+            }
+            InfluxOutput::Http(sender) => match sender.try_send(line) {
+                Ok(()) => {
+                    if self.dropped > 0 {
+                        log::info!("Influx writer caught up, {} lines dropped", self.dropped);
+                        self.dropped = 0;
+                    }
+                }
+                Err(TrySendError::Full(_)) => {
+                    if self.dropped == 0 {
+                        log::warn!("Influx writer is behind, dropping lines");
+                    }
+                    self.dropped += 1;
+                }
+                Err(TrySendError::Disconnected(_)) => log::error!("Influx writer has stopped"),
+            },
+        }
+    }
+
+    fn session(&mut self, session: &Map<String, Value>) {
+        if let Some(car) = car_screen_name(session) {
+            self.car = car.to_string();
+        }
+    }
+
+    fn disconnected(&mut self) {
+        self.car.clear();
+    }
+}
+
+/// Posts batches of lines until the sender is dropped.
+fn write_http(url: &str, token: Option<&str>, receiver: Receiver<String>) {
+    let agent = ureq::AgentBuilder::new()
+        .timeout_connect(CONNECT_TIMEOUT)
+        .timeout(WRITE_TIMEOUT)
+        .build();
+    let mut batch: Vec<String> = Vec::with_capacity(BATCH_SIZE);
+    let mut last_write = Instant::now();
+
+    loop {
+        let stopped = match receiver.recv_timeout(BATCH_INTERVAL) {
+            Ok(line) => {
+                batch.push(line);
+                false
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => true,
+        };
+
+        let due = batch.len() >= BATCH_SIZE || last_write.elapsed() >= BATCH_INTERVAL || stopped;
+        if due && !batch.is_empty() {
+            let mut request = agent
+                .post(url)
+                .set("Content-Type", "text/plain; charset=utf-8");
+            if let Some(token) = token {
+                request = request.set("Authorization", &format!("Token {}", token));
+            }
+
+            if let Err(e) = request.send_string(&batch.join("\n")) {
+                log::error!("Failed to write {} lines to influx: {:?}", batch.len(), e);
+            }
+            batch.clear();
+            last_write = Instant::now();
+        }
+
+        if stopped {
+            break;
+        }
+    }
+}
+
+/// Builds one line of line protocol from the telemetry.  Arrays become one field per index, eg `CarIdxLap_3`.
+/// Returns `None` if there are no fields, since that is not a valid line.
+fn to_line(
+    rig: &str,
+    car: &str,
+    telemetry: &Map<String, Value>,
+    vars: &[String],
+    timestamp: u128,
+) -> Option<String> {
+    let mut fields = Vec::new();
+    let mut push_field = |key: &str, value: &Value| {
+        if let Some(value) = field_value(value) {
+            fields.push(format!("{}={}", escape_key(key), value));
+        }
+    };
+
+    let mut names: Vec<&String> = if vars.is_empty() {
+        telemetry.keys().collect()
+    } else {
+        vars.iter()
+            .filter(|var| telemetry.contains_key(*var))
+            .collect()
+    };
+    names.sort();
+
+    for name in names {
+        match &telemetry[name] {
+            Value::Array(values) => {
+                for (idx, value) in values.iter().enumerate() {
+                    push_field(&format!("{}_{}", name, idx), value);
+                }
+            }
+            value => push_field(name, value),
+        }
+    }
+
+    if fields.is_empty() {
+        return None;
+    }
+
+    let mut line = format!("{},rig={}", MEASUREMENT, escape_key(rig));
+    if !car.is_empty() {
+        line.push_str(&format!(",car={}", escape_key(car)));
+    }
+
+    Some(format!("{} {} {}", line, fields.join(","), timestamp))
+}
+
+/// Integers get an `i` suffix so influx keeps them as integers.  Bitfields rendered as lists of flags become strings.
+fn field_value(value: &Value) -> Option<String> {
+    match value {
+        Value::Number(number) if number.is_f64() => Some(number.to_string()),
+        Value::Number(number) => Some(format!("{}i", number)),
+        Value::Bool(value) => Some(value.to_string()),
+        Value::String(value) => Some(format!("\"{}\"", escape_string(value))),
+        Value::Array(_) | Value::Object(_) => {
+            Some(format!("\"{}\"", escape_string(&value.to_string())))
+        }
+        Value::Null => None,
+    }
+}
+
+/// Tag keys, tag values and field keys escape commas, equals and spaces
+fn escape_key(key: &str) -> String {
+    key.replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+fn escape_string(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn should_build_line() {
+        let telemetry = json!({
+            "AirTemp": 21.5,
+            "Gear": 3,
+            "IsOnTrack": true,
+            "CarIdxLap": [1, 2],
+            "SessionFlags": ["Green Flag"],
+        });
+
+        let line = to_line(
+            "rig 1",
+            "Porsche 911",
+            telemetry.as_object().unwrap(),
+            &[],
+            1000,
+        );
+        assert_eq!(
+            line,
+            Some(
+                "iracing,rig=rig\\ 1,car=Porsche\\ 911 AirTemp=21.5,CarIdxLap_0=1i,CarIdxLap_1=2i,Gear=3i,IsOnTrack=true,SessionFlags_0=\"Green Flag\" 1000"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn should_drop_lines_while_writer_is_behind() {
+        let (sender, receiver) = sync_channel(2);
+        let mut sink = InfluxSink {
+            output: InfluxOutput::Http(sender),
+            vars: Vec::new(),
+            rig: "rig1".to_string(),
+            car: String::new(),
+            dropped: 0,
+        };
+        let telemetry = json!({ "Gear": 3 });
+
+        for _ in 0..5 {
+            sink.telemetry(telemetry.as_object().unwrap());
+        }
+        assert_eq!(sink.dropped, 3);
+        assert_eq!(receiver.try_iter().count(), 2);
+
+        sink.telemetry(telemetry.as_object().unwrap());
+        assert_eq!(sink.dropped, 0);
+    }
+
+    #[test]
+    fn should_only_use_selected_vars() {
+        let telemetry = json!({ "AirTemp": 21.5, "Gear": 3 });
+        let vars = vec!["Gear".to_string(), "Missing".to_string()];
+
+        let line = to_line("rig1", "", telemetry.as_object().unwrap(), &vars, 1);
+        assert_eq!(line, Some("iracing,rig=rig1 Gear=3i 1".to_string()));
+    }
+}
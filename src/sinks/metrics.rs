@@ -0,0 +1,260 @@
+use serde_json::{Map, Value};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use tiny_http::{Header, Response, Server};
+
+use super::sink::{parse_list, Sink};
+use crate::devices::car_screen_name;
+use crate::diagnostics::Diagnostics;
+
+/// Prometheus text exposition format
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Latest values served on `/metrics`
+#[derive(Default)]
+struct MetricsState {
+    car: String,
+    telemetry: Vec<(String, f64)>,
+}
+
+/// Serves the bridge counters and a selection of telemetry vars in Prometheus format on `/metrics`.
+///
+/// Enabled by setting `METRICS_PORT`.  `METRICS_VARS` is a comma separated list of telemetry vars to export as gauges, and
+/// `RIG_ID` labels every series so multiple rigs can share a Prometheus.
+pub(crate) struct MetricsSink {
+    vars: Vec<String>,
+    state: Arc<Mutex<MetricsState>>,
+}
+
+impl MetricsSink {
+    /// Starts the http server if `METRICS_PORT` is set.
+    pub fn from_env(diagnostics: Arc<Diagnostics>) -> Option<Self> {
+        let port = std::env::var("METRICS_PORT").ok()?;
+        let port = match port.parse::<u16>() {
+            Ok(port) => port,
+            Err(_) => {
+                log::error!("Invalid METRICS_PORT {}, metrics endpoint disabled", port);
+                return None;
+            }
+        };
+
+        let vars = std::env::var("METRICS_VARS")
+            .map(|vars| parse_list(&vars))
+            .unwrap_or_default();
+        let rig = std::env::var("RIG_ID").unwrap_or("default".to_string());
+
+        let server = match Server::http(("0.0.0.0", port)) {
+            Ok(server) => server,
+            Err(e) => {
+                log::error!("Failed to start metrics endpoint on port {}: {:?}", port, e);
+                return None;
+            }
+        };
+
+        let state = Arc::new(Mutex::new(MetricsState::default()));
+        let server_state = state.clone();
+        std::thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let response = if request.url() == "/metrics" {
+                    let state = server_state.lock().unwrap();
+                    let body = render(&rig, &state, &diagnostics);
+                    Response::from_string(body).with_header(
+                        Header::from_bytes(&b"Content-Type"[..], CONTENT_TYPE.as_bytes()).unwrap(),
+                    )
+                } else {
+                    Response::from_string("Not Found").with_status_code(404)
+                };
+
+                if let Err(e) = request.respond(response) {
+                    log::error!("Failed to respond to metrics request: {:?}", e);
+                }
+            }
+        });
+
+        log::info!("Serving metrics on port {}", port);
+        Some(Self { vars, state })
+    }
+}
+
+impl Sink for MetricsSink {
+    fn telemetry(&mut self, telemetry: &Map<String, Value>) {
+        let mut gauges = Vec::new();
+        for var in self.vars.iter() {
+            match telemetry.get(var) {
+                Some(Value::Array(values)) => {
+                    for (idx, value) in values.iter().enumerate() {
+                        if let Some(value) = as_gauge(value) {
+                            gauges.push((format!("{}[{}]", var, idx), value));
+                        }
+                    }
+                }
+                Some(value) => {
+                    if let Some(value) = as_gauge(value) {
+                        gauges.push((var.clone(), value));
+                    }
+                }
+                None => (),
+            }
+        }
+
+        self.state.lock().unwrap().telemetry = gauges;
+    }
+
+    fn session(&mut self, session: &Map<String, Value>) {
+        if let Some(car) = car_screen_name(session) {
+            self.state.lock().unwrap().car = car.to_string();
+        }
+    }
+
+    fn disconnected(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.car.clear();
+        state.telemetry.clear();
+    }
+}
+
+/// Numbers and bools can be gauges.  Everything else (strings, bitfields rendered as lists) is skipped.
+fn as_gauge(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(number) => number.as_f64(),
+        Value::Bool(true) => Some(1.),
+        Value::Bool(false) => Some(0.),
+        _ => None,
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn render(rig: &str, state: &MetricsState, diagnostics: &Diagnostics) -> String {
+    let rig = escape_label(rig);
+    let mut body = String::new();
+
+    let counters = [
+        (
+            "hairmqtt_publishes_total",
+            "Messages published",
+            diagnostics.publishes(),
+        ),
+        (
+            "hairmqtt_publish_errors_total",
+            "Failed publishes",
+            diagnostics.publish_errors(),
+        ),
+        (
+            "hairmqtt_dropped_messages_total",
+            "Messages dropped with a full request channel",
+            diagnostics.dropped_messages(),
+        ),
+        (
+            "hairmqtt_connection_errors_total",
+            "MQTT connection errors",
+            diagnostics.connection_errors(),
+        ),
+        (
+            "hairmqtt_reconnects_total",
+            "MQTT reconnects after the first connection",
+            diagnostics.reconnects(),
+        ),
+        (
+            "hairmqtt_telemetry_ticks_total",
+            "Telemetry updates received",
+            diagnostics.telemetry_ticks(),
+        ),
+        (
+            "hairmqtt_session_updates_total",
+            "Session updates received",
+            diagnostics.session_updates(),
+        ),
+    ];
+
+    for (name, help, value) in counters {
+        let _ = writeln!(body, "# HELP {} {}", name, help);
+        let _ = writeln!(body, "# TYPE {} counter", name);
+        let _ = writeln!(body, "{}{{rig=\"{}\"}} {}", name, rig, value);
+    }
+
+    let _ = writeln!(
+        body,
+        "# HELP hairmqtt_last_payload_bytes Size of the last published payload"
+    );
+    let _ = writeln!(body, "# TYPE hairmqtt_last_payload_bytes gauge");
+    let _ = writeln!(
+        body,
+        "hairmqtt_last_payload_bytes{{rig=\"{}\"}} {}",
+        rig,
+        diagnostics.last_payload_size()
+    );
+
+    if !state.telemetry.is_empty() {
+        let car = escape_label(&state.car);
+        let _ = writeln!(
+            body,
+            "# HELP iracing_telemetry Selected iRacing telemetry vars"
+        );
+        let _ = writeln!(body, "# TYPE iracing_telemetry gauge");
+        for (var, value) in state.telemetry.iter() {
+            let _ = writeln!(
+                body,
+                "iracing_telemetry{{rig=\"{}\",car=\"{}\",var=\"{}\"}} {}",
+                rig,
+                car,
+                escape_label(var),
+                value
+            );
+        }
+    }
+
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_render_counters_and_gauges() {
+        let diagnostics = Diagnostics::default();
+        diagnostics.record_publish(42);
+        let state = MetricsState {
+            car: "Porsche 911 GT3 R".to_string(),
+            telemetry: vec![("AirTemp".to_string(), 21.5)],
+        };
+
+        let body = render("rig1", &state, &diagnostics);
+        assert!(body.contains("hairmqtt_publishes_total{rig=\"rig1\"} 1\n"));
+        assert!(body.contains("hairmqtt_last_payload_bytes{rig=\"rig1\"} 42\n"));
+        assert!(body.contains(
+            "iracing_telemetry{rig=\"rig1\",car=\"Porsche 911 GT3 R\",var=\"AirTemp\"} 21.5\n"
+        ));
+    }
+
+    #[test]
+    fn should_escape_labels() {
+        assert_eq!(escape_label("a\"b\\c"), "a\\\"b\\\\c");
+    }
+
+    #[test]
+    fn should_export_arrays_by_index() {
+        let mut sink = MetricsSink {
+            vars: vec!["CarIdxLap".to_string(), "IsOnTrack".to_string()],
+            state: Arc::new(Mutex::new(MetricsState::default())),
+        };
+        let telemetry = serde_json::json!({ "CarIdxLap": [3, 4], "IsOnTrack": true });
+        sink.telemetry(telemetry.as_object().unwrap());
+
+        let state = sink.state.lock().unwrap();
+        assert_eq!(
+            state.telemetry,
+            vec![
+                ("CarIdxLap[0]".to_string(), 3.),
+                ("CarIdxLap[1]".to_string(), 4.),
+                ("IsOnTrack".to_string(), 1.)
+            ]
+        );
+    }
+}
@@ -0,0 +1,65 @@
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{LineWriter, Write};
+
+use super::sink::Sink;
+use crate::recording::{RecordEntry, VarInfo};
+
+/// Records what the bridge receives, one JSON entry per line, so it can be read back by `list-vars`, `dump-session`
+/// and `validate` without iRacing running.
+///
+/// Enabled by setting `RECORDING_FILE`.  New entries are appended to the file.
+pub(crate) struct RecordingSink {
+    writer: LineWriter<File>,
+}
+
+impl RecordingSink {
+    pub fn from_env() -> Option<Self> {
+        let path = std::env::var("RECORDING_FILE").ok()?;
+        match OpenOptions::new().create(true).append(true).open(&path) {
+            Ok(file) => {
+                log::info!("Recording to {}", path);
+                Some(Self {
+                    writer: LineWriter::new(file),
+                })
+            }
+            Err(e) => {
+                log::error!("Failed to open recording {}: {:?}", path, e);
+                None
+            }
+        }
+    }
+
+    fn record(&mut self, entry: RecordEntry) {
+        let result = serde_json::to_vec(&entry)
+            .map_err(std::io::Error::from)
+            .and_then(|mut line| {
+                line.push(b'\n');
+                self.writer.write_all(&line)
+            });
+        if let Err(e) = result {
+            log::error!("Failed to write recording: {:?}", e);
+        }
+    }
+}
+
+impl Sink for RecordingSink {
+    fn variables(&mut self, var_headers: &HashMap<String, VarInfo>) {
+        let mut vars: Vec<VarInfo> = var_headers.values().cloned().collect();
+        vars.sort_by(|a, b| a.name.cmp(&b.name));
+        self.record(RecordEntry::Variables(vars));
+    }
+
+    fn telemetry(&mut self, telemetry: &Map<String, Value>) {
+        self.record(RecordEntry::Telemetry(telemetry.clone()));
+    }
+
+    fn session(&mut self, session: &Map<String, Value>) {
+        self.record(RecordEntry::Session(session.clone()));
+    }
+
+    fn disconnected(&mut self) {
+        self.record(RecordEntry::Disconnected);
+    }
+}
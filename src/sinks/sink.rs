@@ -0,0 +1,73 @@
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::recording::VarInfo;
+use crate::scheduler::{interval_from_env, Throttle};
+
+/// Sinks get telemetry at 2 Hz unless their interval env var is set.
+const DEFAULT_SINK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// An output for the telemetry pipeline, alongside the mqtt client.  Each sink is handed the same maps that are published to
+/// the telemetry and session topics.
+pub(crate) trait Sink {
+    /// Called when the variable headers are received, which happens when a session loads.
+    fn variables(&mut self, _var_headers: &HashMap<String, VarInfo>) {}
+
+    /// Called with every telemetry update.
+    fn telemetry(&mut self, telemetry: &Map<String, Value>);
+
+    /// Called with every session update.
+    fn session(&mut self, _session: &Map<String, Value>) {}
+
+    /// Called when iRacing disconnects.  Session specific state should be cleared.
+    fn disconnected(&mut self) {}
+}
+
+/// Limits how often a sink gets telemetry.  Everything else is passed straight through.
+pub(crate) struct ThrottledSink<S> {
+    sink: S,
+    throttle: Throttle,
+}
+
+impl<S> ThrottledSink<S>
+where
+    S: Sink + Send + 'static,
+{
+    /// Reads the interval in seconds from `env`, eg `CSV_INTERVAL=0.05` to log at 20 Hz.
+    pub fn from_env(sink: S, env: &str) -> Box<dyn Sink + Send> {
+        Box::new(Self {
+            sink,
+            throttle: Throttle::new(interval_from_env(env, DEFAULT_SINK_INTERVAL)),
+        })
+    }
+}
+
+impl<S: Sink> Sink for ThrottledSink<S> {
+    fn variables(&mut self, var_headers: &HashMap<String, VarInfo>) {
+        self.sink.variables(var_headers);
+    }
+
+    fn telemetry(&mut self, telemetry: &Map<String, Value>) {
+        if self.throttle.ready(Instant::now()) {
+            self.sink.telemetry(telemetry);
+        }
+    }
+
+    fn session(&mut self, session: &Map<String, Value>) {
+        self.sink.session(session);
+    }
+
+    fn disconnected(&mut self) {
+        self.sink.disconnected();
+    }
+}
+
+/// Splits a comma separated list from an env var or query string, eg `AirTemp, Speed,RPM`.
+pub(crate) fn parse_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
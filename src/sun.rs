@@ -0,0 +1,338 @@
+use ha_mqtt::components::binary_sensor::BinarySensor;
+use ha_mqtt::components::sensor::Sensor;
+use ha_mqtt::device::Device;
+use serde_json::{Map, Value};
+use std::time::{Duration, Instant};
+
+use crate::irmqtt::client::DiscoveryPrepPacket;
+use crate::scheduler::Throttle;
+
+pub(crate) const SUN_STATE: &str = "hairmqtt/sun";
+
+/// Often enough for the sim clock to read right to the minute
+const SUN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Degrees.  Below the horizon down to civil twilight is `twilight`, under that is `night`.
+const TWILIGHT_ALTITUDE: f64 = -6.;
+
+/// Degrees.  The sun is treated as fully up from here for the lighting suggestions.
+const FULL_DAY_ALTITUDE: f64 = 30.;
+
+const MIN_BRIGHTNESS: f64 = 5.;
+const MAX_BRIGHTNESS: f64 = 100.;
+const WARM_KELVIN: f64 = 2200.;
+const DAYLIGHT_KELVIN: f64 = 5500.;
+
+const SECONDS_PER_DAY: f64 = 86400.;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Phase {
+    Day,
+    Twilight,
+    Night,
+}
+
+impl Phase {
+    fn from_altitude(altitude: f64) -> Self {
+        if altitude >= 0. {
+            Phase::Day
+        } else if altitude >= TWILIGHT_ALTITUDE {
+            Phase::Twilight
+        } else {
+            Phase::Night
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Phase::Day => "day",
+            Phase::Twilight => "twilight",
+            Phase::Night => "night",
+        }
+    }
+}
+
+/// Sun position and the in-sim time of day, with lighting suggestions that follow the sim sun.
+pub(crate) struct Sun {
+    throttle: Throttle,
+    /// Event date from the session, `(year, month, day)`
+    date: Option<(i64, u32, u32)>,
+}
+
+impl Sun {
+    pub fn new() -> Self {
+        Self {
+            throttle: Throttle::new(SUN_INTERVAL),
+            date: None,
+        }
+    }
+
+    pub fn session(&mut self, session: &Map<String, Value>) {
+        self.date = session
+            .get("weekend_info")
+            .and_then(|info| info.get("weekend_options"))
+            .and_then(|options| options.get("date"))
+            .and_then(Value::as_str)
+            .and_then(parse_date);
+    }
+
+    pub fn clear(&mut self) {
+        self.date = None;
+    }
+
+    /// Returns the sun state when it is due to be sent.
+    pub fn update(
+        &mut self,
+        telemetry: &Map<String, Value>,
+        now: Instant,
+    ) -> Option<Map<String, Value>> {
+        if !self.throttle.ready(now) {
+            return None;
+        }
+
+        let degrees = |var: &str| {
+            telemetry
+                .get(var)
+                .and_then(Value::as_f64)
+                .map(|radians| (radians.to_degrees() * 10.).round() / 10.)
+        };
+        let altitude = degrees("SolarAltitude");
+        let time_of_day = telemetry.get("SessionTimeOfDay").and_then(Value::as_f64);
+
+        let mut state = Map::new();
+        let mut insert = |key: &str, value: Option<Value>| {
+            state.insert(key.to_string(), value.unwrap_or(Value::Null));
+        };
+
+        insert("altitude", altitude.map(Value::from));
+        insert("azimuth", degrees("SolarAzimuth").map(Value::from));
+        insert(
+            "phase",
+            altitude.map(|a| Value::from(Phase::from_altitude(a).as_str())),
+        );
+        insert("brightness", altitude.map(|a| Value::from(brightness(a))));
+        insert(
+            "color_temp_kelvin",
+            altitude.map(|a| Value::from(color_temp(a))),
+        );
+        insert("time_of_day", time_of_day.map(|t| Value::from(clock(t))));
+        insert(
+            "local_time",
+            time_of_day
+                .zip(self.date)
+                .map(|(t, date)| Value::from(local_time(date, t))),
+        );
+
+        Some(state)
+    }
+}
+
+/// How far the sun is between night and full day, 0 to 1
+fn daylight(altitude: f64) -> f64 {
+    ((altitude - TWILIGHT_ALTITUDE) / (FULL_DAY_ALTITUDE - TWILIGHT_ALTITUDE)).clamp(0., 1.)
+}
+
+/// Suggested light brightness, in percent
+fn brightness(altitude: f64) -> u64 {
+    (MIN_BRIGHTNESS + (MAX_BRIGHTNESS - MIN_BRIGHTNESS) * daylight(altitude)).round() as u64
+}
+
+/// Suggested light color temperature.  Warm until the sun is up, then cooler as it climbs.
+fn color_temp(altitude: f64) -> u64 {
+    let warmth = (altitude / FULL_DAY_ALTITUDE).clamp(0., 1.);
+    ((WARM_KELVIN + (DAYLIGHT_KELVIN - WARM_KELVIN) * warmth) / 100.).round() as u64 * 100
+}
+
+/// `HH:MM:SS` from seconds since midnight.  Long sessions run past midnight, so it wraps.
+fn clock(seconds: f64) -> String {
+    let seconds = seconds.rem_euclid(SECONDS_PER_DAY) as u64;
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// ISO 8601 local time, moving the date on for sessions that run past midnight
+fn local_time(date: (i64, u32, u32), seconds: f64) -> String {
+    let (mut year, mut month, mut day) = date;
+    for _ in 0..(seconds / SECONDS_PER_DAY).floor().max(0.) as u64 {
+        day += 1;
+        if day > days_in_month(year, month) {
+            day = 1;
+            month += 1;
+            if month > 12 {
+                month = 1;
+                year += 1;
+            }
+        }
+    }
+
+    format!("{:04}-{:02}-{:02}T{}", year, month, day, clock(seconds))
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// The session date is `YYYY-MM-DD`
+fn parse_date(date: &str) -> Option<(i64, u32, u32)> {
+    let mut parts = date.trim().splitn(3, '-');
+    let year = parts.next()?.parse().ok()?;
+    let month = parts.next()?.parse().ok()?;
+    let day = parts.next()?.parse().ok()?;
+
+    ((1..=12).contains(&month) && day >= 1 && day <= days_in_month(year, month))
+        .then_some((year, month, day))
+}
+
+/// Sun and time of day sensors for the track device
+pub(crate) fn discovery_packet(device: &Device) -> Vec<DiscoveryPrepPacket> {
+    let expire_after = Value::from(SUN_INTERVAL.as_secs() * 3);
+    let sensors = [
+        ("altitude", "Sun Altitude", Some("°"), "mdi:sun-angle"),
+        ("azimuth", "Sun Azimuth", Some("°"), "mdi:sun-compass"),
+        (
+            "brightness",
+            "Suggested Brightness",
+            Some("%"),
+            "mdi:brightness-6",
+        ),
+        (
+            "color_temp_kelvin",
+            "Suggested Color Temperature",
+            Some("K"),
+            "mdi:thermometer",
+        ),
+        ("time_of_day", "Time of Day", None, "mdi:clock-outline"),
+        ("local_time", "Local Time", None, "mdi:calendar-clock"),
+    ];
+
+    let mut discoverables: Vec<DiscoveryPrepPacket> = sensors
+        .into_iter()
+        .map(|(key, name, unit, icon)| {
+            let mut sensor = sun_sensor(key, name, device);
+            sensor.icon = Some(icon.to_string());
+            sensor.unit_of_measurement = unit.map(|u| u.to_string());
+
+            let mut extra = vec![("expire_after", expire_after.clone())];
+            if unit.is_some() {
+                extra.push(("state_class", Value::from("measurement")));
+            }
+            crate::prepare_payload_with(sensor, &extra)
+        })
+        .collect();
+
+    let mut phase = sun_sensor("phase", "Sun Phase", device);
+    phase.icon = Some("mdi:theme-light-dark".to_string());
+    discoverables.push(crate::prepare_payload_with(
+        phase,
+        &[
+            ("expire_after", expire_after.clone()),
+            ("device_class", Value::from("enum")),
+            ("options", Value::from(vec!["day", "twilight", "night"])),
+        ],
+    ));
+
+    let daylight = BinarySensor::new(SUN_STATE)
+        .with_name("Daylight")
+        .with_device(device)
+        .with_icon("mdi:weather-sunny")
+        .with_payload_on("on")
+        .with_payload_off("off")
+        .with_value_template("{{ 'on' if value_json.phase == 'day' else 'off' }}")
+        .with_unique_id("hairmqtt-sun-daylight")
+        .with_object_id("sun_daylight");
+    discoverables.push(crate::prepare_payload_with(
+        daylight,
+        &[
+            ("expire_after", expire_after),
+            ("device_class", Value::from("light")),
+        ],
+    ));
+
+    discoverables
+}
+
+fn sun_sensor<'a>(key: &str, name: &str, device: &'a Device) -> Sensor<'a> {
+    Sensor::new(SUN_STATE)
+        .with_name(name)
+        .with_unique_id(format!("hairmqtt-sun-{}", key))
+        .with_object_id(format!("sun_{}", key))
+        .with_device(device)
+        .with_value_template(format!("{{{{ value_json.{} }}}}", key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn should_find_phase_from_altitude() {
+        assert_eq!(Phase::from_altitude(20.), Phase::Day);
+        assert_eq!(Phase::from_altitude(0.), Phase::Day);
+        assert_eq!(Phase::from_altitude(-3.), Phase::Twilight);
+        assert_eq!(Phase::from_altitude(-6.), Phase::Twilight);
+        assert_eq!(Phase::from_altitude(-20.), Phase::Night);
+    }
+
+    #[test]
+    fn should_suggest_lighting_from_altitude() {
+        assert_eq!(brightness(-20.), 5);
+        assert_eq!(brightness(12.), 53);
+        assert_eq!(brightness(45.), 100);
+
+        assert_eq!(color_temp(-3.), 2200);
+        assert_eq!(color_temp(15.), 3900);
+        assert_eq!(color_temp(60.), 5500);
+    }
+
+    #[test]
+    fn should_roll_local_time_past_midnight() {
+        assert_eq!(clock(13. * 3600. + 5. * 60. + 9.5), "13:05:09");
+        assert_eq!(local_time((2023, 6, 10), 3600.), "2023-06-10T01:00:00");
+        assert_eq!(
+            local_time((2023, 6, 30), 86400. + 7200.),
+            "2023-07-01T02:00:00"
+        );
+        assert_eq!(local_time((2023, 12, 31), 90000.), "2024-01-01T01:00:00");
+    }
+
+    #[test]
+    fn should_parse_session_date() {
+        assert_eq!(parse_date("2023-06-10"), Some((2023, 6, 10)));
+        assert_eq!(parse_date("2023-02-29"), None);
+        assert_eq!(parse_date("June"), None);
+    }
+
+    #[test]
+    fn should_build_sun_state() {
+        let mut sun = Sun::new();
+        sun.session(
+            json!({ "weekend_info": { "weekend_options": { "date": "2023-06-10" } } })
+                .as_object()
+                .unwrap(),
+        );
+
+        let telemetry = json!({
+            "SolarAltitude": -0.05,
+            "SolarAzimuth": 1.5,
+            "SessionTimeOfDay": 77400.,
+        });
+        let state = sun
+            .update(telemetry.as_object().unwrap(), Instant::now())
+            .unwrap();
+
+        assert_eq!(state["phase"], json!("twilight"));
+        assert_eq!(state["altitude"], json!(-2.9));
+        assert_eq!(state["time_of_day"], json!("21:30:00"));
+        assert_eq!(state["local_time"], json!("2023-06-10T21:30:00"));
+    }
+}
@@ -0,0 +1,458 @@
+use ha_mqtt::components::binary_sensor::BinarySensor;
+use ha_mqtt::components::sensor::Sensor;
+use ha_mqtt::device::Device;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::irmqtt::client::DiscoveryPrepPacket;
+use crate::recording::VarDescription;
+use crate::scheduler::Throttle;
+
+pub(crate) const WEATHER_STATE: &str = "hairmqtt/weather";
+
+/// Weather changes slowly, so it is sent on a fixed interval.  Entities expire after 3 missed updates.
+const WEATHER_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Wind above this (km/h) turns clear skies into `windy`
+const WINDY_KMH: f64 = 40.;
+
+/// `WeekendInfo` weather settings sent as attributes of the condition sensor
+const WEEKEND_WEATHER_KEYS: [&str; 11] = [
+    "track_weather_type",
+    "track_skies",
+    "track_surface_temp",
+    "track_air_temp",
+    "track_air_pressure",
+    "track_wind_vel",
+    "track_wind_dir",
+    "track_relative_humidity",
+    "track_fog_level",
+    "track_precipitation",
+    "track_dynamic_track",
+];
+
+/// Collects the weather telemetry into one state, with the HA weather condition worked out from the skies,
+/// precipitation and fog.  The sensors line up with what a HA template weather entity needs.
+pub(crate) struct Weather {
+    throttle: Throttle,
+    /// Units of `AirPressure`, since iRacing has used both Pa and inHg
+    pressure_units: String,
+    attributes: Map<String, Value>,
+}
+
+impl Weather {
+    pub fn new() -> Self {
+        Self {
+            throttle: Throttle::new(WEATHER_INTERVAL),
+            pressure_units: String::new(),
+            attributes: Map::new(),
+        }
+    }
+
+    pub fn variables<V: VarDescription>(&mut self, var_headers: &HashMap<String, V>) {
+        if let Some(var) = var_headers.get("AirPressure") {
+            self.pressure_units = var.units().to_string();
+        }
+    }
+
+    /// Keeps the weekend weather settings for the attributes.
+    pub fn session(&mut self, session: &Map<String, Value>) {
+        let Some(Value::Object(weekend_info)) = session.get("weekend_info") else {
+            return;
+        };
+
+        self.attributes = WEEKEND_WEATHER_KEYS
+            .iter()
+            .filter_map(|key| {
+                weekend_info
+                    .get(*key)
+                    .map(|value| (key.to_string(), value.clone()))
+            })
+            .collect();
+    }
+
+    pub fn clear(&mut self) {
+        self.attributes.clear();
+    }
+
+    /// Returns the weather state when it is due to be sent.
+    pub fn update(
+        &mut self,
+        telemetry: &Map<String, Value>,
+        now: Instant,
+    ) -> Option<Map<String, Value>> {
+        if !self.throttle.ready(now) {
+            return None;
+        }
+
+        let number = |var: &str| telemetry.get(var).and_then(Value::as_f64);
+        let round = |value: f64| (value * 10.).round() / 10.;
+
+        let wind_speed = number("WindVel").map(|speed| round(speed * 3.6));
+        let precipitation = number("Precipitation");
+        let fog_level = number("FogLevel");
+        let skies = telemetry.get("Skies").and_then(Value::as_i64);
+        let declared_wet = telemetry.get("WeatherDeclaredWet").and_then(Value::as_bool);
+        let daytime = number("SolarAltitude").map_or(true, |altitude| altitude > 0.);
+
+        let mut state = Map::new();
+        let mut insert = |key: &str, value: Option<Value>| {
+            state.insert(key.to_string(), value.unwrap_or(Value::Null));
+        };
+
+        insert(
+            "condition",
+            Some(Value::from(condition(
+                skies,
+                precipitation.unwrap_or_default(),
+                fog_level.unwrap_or_default(),
+                wind_speed.unwrap_or_default(),
+                declared_wet.unwrap_or_default(),
+                daytime,
+            ))),
+        );
+        insert("temperature", number("AirTemp").map(round).map(Value::from));
+        insert(
+            "track_temperature",
+            number("TrackTempCrew").map(round).map(Value::from),
+        );
+        // Humidity, fog and precipitation are fractions, even though the var headers say %
+        insert(
+            "humidity",
+            number("RelativeHumidity").map(|h| Value::from(round(h * 100.))),
+        );
+        insert(
+            "pressure",
+            number("AirPressure").map(|p| Value::from(round(self.to_hpa(p)))),
+        );
+        insert(
+            "wind_bearing",
+            number("WindDir").map(|dir| Value::from(round(dir.to_degrees()))),
+        );
+        insert("wind_speed", wind_speed.map(Value::from));
+        insert(
+            "fog_level",
+            fog_level.map(|fog| Value::from(round(fog * 100.))),
+        );
+        insert(
+            "precipitation",
+            precipitation.map(|p| Value::from(round(p * 100.))),
+        );
+        insert("skies", skies.map(|skies| Value::from(skies_name(skies))));
+        insert("declared_wet", declared_wet.map(Value::from));
+        insert(
+            "track_wetness",
+            telemetry
+                .get("TrackWetness")
+                .and_then(Value::as_i64)
+                .map(|wetness| Value::from(wetness_name(wetness))),
+        );
+        insert("attributes", Some(Value::Object(self.attributes.clone())));
+
+        Some(state)
+    }
+
+    fn to_hpa(&self, pressure: f64) -> f64 {
+        match self.pressure_units.as_str() {
+            "Hg" | "inHg" => pressure * 33.8639,
+            _ => pressure / 100.,
+        }
+    }
+}
+
+fn skies_name(skies: i64) -> &'static str {
+    match skies {
+        0 => "clear",
+        1 => "partly cloudy",
+        2 => "mostly cloudy",
+        3 => "overcast",
+        _ => "unknown",
+    }
+}
+
+/// `irsdk_TrackWetness`
+fn wetness_name(wetness: i64) -> &'static str {
+    match wetness {
+        1 => "dry",
+        2 => "mostly dry",
+        3 => "very lightly wet",
+        4 => "lightly wet",
+        5 => "moderately wet",
+        6 => "very wet",
+        7 => "extremely wet",
+        _ => "unknown",
+    }
+}
+
+/// Maps the iRacing weather to a HA weather condition.  Rain wins over fog, and fog over the skies.
+fn condition(
+    skies: Option<i64>,
+    precipitation: f64,
+    fog_level: f64,
+    wind_kmh: f64,
+    declared_wet: bool,
+    daytime: bool,
+) -> &'static str {
+    if precipitation > 0.5 {
+        return "pouring";
+    }
+    if precipitation > 0. || declared_wet {
+        return "rainy";
+    }
+    if fog_level > 0.5 {
+        return "fog";
+    }
+
+    match skies {
+        Some(0) if wind_kmh > WINDY_KMH => "windy",
+        Some(0) if !daytime => "clear-night",
+        Some(0) => "sunny",
+        Some(1) => "partlycloudy",
+        Some(2) | Some(3) => "cloudy",
+        _ => "exceptional",
+    }
+}
+
+/// Weather sensors for the track device.  Each one is only discovered if the var it is worked out from is in the
+/// variable headers, otherwise it would never get a state.
+pub(crate) fn discovery_packet<V: VarDescription>(
+    var_headers: &HashMap<String, V>,
+    device: &Device,
+) -> Vec<DiscoveryPrepPacket> {
+    let sensors = [
+        (
+            "AirTemp",
+            "temperature",
+            "Air Temperature",
+            Some("°C"),
+            Some("temperature"),
+            "mdi:thermometer",
+        ),
+        (
+            "TrackTempCrew",
+            "track_temperature",
+            "Track Temperature",
+            Some("°C"),
+            Some("temperature"),
+            "mdi:thermometer",
+        ),
+        (
+            "RelativeHumidity",
+            "humidity",
+            "Humidity",
+            Some("%"),
+            Some("humidity"),
+            "mdi:water-percent",
+        ),
+        (
+            "AirPressure",
+            "pressure",
+            "Air Pressure",
+            Some("hPa"),
+            Some("atmospheric_pressure"),
+            "mdi:gauge",
+        ),
+        (
+            "WindDir",
+            "wind_bearing",
+            "Wind Bearing",
+            Some("°"),
+            None,
+            "mdi:compass",
+        ),
+        (
+            "WindVel",
+            "wind_speed",
+            "Wind Speed",
+            Some("km/h"),
+            Some("wind_speed"),
+            "mdi:weather-windy",
+        ),
+        (
+            "FogLevel",
+            "fog_level",
+            "Fog Level",
+            Some("%"),
+            None,
+            "mdi:weather-fog",
+        ),
+        (
+            "Precipitation",
+            "precipitation",
+            "Precipitation",
+            Some("%"),
+            None,
+            "mdi:weather-pouring",
+        ),
+        ("Skies", "skies", "Skies", None, None, "mdi:weather-cloudy"),
+        (
+            "TrackWetness",
+            "track_wetness",
+            "Track Wetness",
+            None,
+            None,
+            "mdi:weather-rainy",
+        ),
+    ];
+
+    let mut discoverables: Vec<DiscoveryPrepPacket> = sensors
+        .into_iter()
+        .filter(|(var, ..)| var_headers.contains_key(*var))
+        .map(|(_, key, name, unit, device_class, icon)| {
+            let mut sensor = weather_sensor(key, name, device);
+            sensor.icon = Some(icon.to_string());
+            sensor.unit_of_measurement = unit.map(|u| u.to_string());
+
+            let mut extra = vec![("expire_after", Value::from(expire_after()))];
+            if let Some(device_class) = device_class {
+                extra.push(("device_class", Value::from(device_class)));
+                extra.push(("state_class", Value::from("measurement")));
+            }
+            crate::prepare_payload_with(sensor, &extra)
+        })
+        .collect();
+
+    // The condition carries the weekend weather settings as attributes.  Without the skies it is always exceptional.
+    if var_headers.contains_key("Skies") {
+        let mut condition = weather_sensor("condition", "Weather Condition", device);
+        condition.icon = Some("mdi:weather-partly-cloudy".to_string());
+        discoverables.push(crate::prepare_payload_with(
+            condition,
+            &[
+                ("expire_after", Value::from(expire_after())),
+                ("json_attributes_topic", Value::from(WEATHER_STATE)),
+                (
+                    "json_attributes_template",
+                    Value::from("{{ value_json.attributes | tojson }}"),
+                ),
+            ],
+        ));
+    }
+
+    if var_headers.contains_key("WeatherDeclaredWet") {
+        let declared_wet = BinarySensor::new(WEATHER_STATE)
+            .with_name("Declared Wet")
+            .with_device(device)
+            .with_icon("mdi:water")
+            .with_payload_on("on")
+            .with_payload_off("off")
+            .with_value_template("{{ 'on' if value_json.declared_wet == true else 'off' }}")
+            .with_unique_id("hairmqtt-weather-declared_wet")
+            .with_object_id("weather_declared_wet");
+        discoverables.push(crate::prepare_payload_with(
+            declared_wet,
+            &[
+                ("expire_after", Value::from(expire_after())),
+                ("device_class", Value::from("moisture")),
+            ],
+        ));
+    }
+
+    discoverables
+}
+
+fn weather_sensor<'a>(key: &str, name: &str, device: &'a Device) -> Sensor<'a> {
+    Sensor::new(WEATHER_STATE)
+        .with_name(name)
+        .with_unique_id(format!("hairmqtt-weather-{}", key))
+        .with_object_id(format!("weather_{}", key))
+        .with_device(device)
+        .with_value_template(format!("{{{{ value_json.{} }}}}", key))
+}
+
+fn expire_after() -> u64 {
+    WEATHER_INTERVAL.as_secs() * 3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recording::VarInfo;
+    use serde_json::json;
+
+    #[test]
+    fn should_map_skies_to_condition() {
+        assert_eq!(condition(Some(0), 0., 0., 10., false, true), "sunny");
+        assert_eq!(condition(Some(0), 0., 0., 10., false, false), "clear-night");
+        assert_eq!(condition(Some(0), 0., 0., 50., false, true), "windy");
+        assert_eq!(condition(Some(1), 0., 0., 10., false, true), "partlycloudy");
+        assert_eq!(condition(Some(3), 0., 0., 10., false, true), "cloudy");
+        assert_eq!(condition(None, 0., 0., 10., false, true), "exceptional");
+    }
+
+    #[test]
+    fn should_prefer_rain_and_fog_over_skies() {
+        assert_eq!(condition(Some(0), 0.8, 0., 0., false, true), "pouring");
+        assert_eq!(condition(Some(0), 0.1, 0.9, 0., false, true), "rainy");
+        assert_eq!(condition(Some(0), 0., 0., 0., true, true), "rainy");
+        assert_eq!(condition(Some(1), 0., 0.9, 0., false, true), "fog");
+    }
+
+    #[test]
+    fn should_build_weather_state() {
+        let mut weather = Weather::new();
+        weather.pressure_units = "Pa".to_string();
+        weather.session(
+            json!({ "weekend_info": { "track_skies": "Partly Cloudy", "track_name": "spa" } })
+                .as_object()
+                .unwrap(),
+        );
+
+        let telemetry = json!({
+            "AirTemp": 21.53,
+            "RelativeHumidity": 0.55,
+            "AirPressure": 101325.,
+            "WindVel": 10.,
+            "Skies": 1,
+            "Precipitation": 0.,
+            "FogLevel": 0.,
+            "WeatherDeclaredWet": false,
+            "TrackWetness": 1,
+        });
+        let state = weather
+            .update(telemetry.as_object().unwrap(), Instant::now())
+            .unwrap();
+
+        assert_eq!(state["condition"], json!("partlycloudy"));
+        assert_eq!(state["temperature"], json!(21.5));
+        assert_eq!(state["humidity"], json!(55.));
+        assert_eq!(state["pressure"], json!(1013.3));
+        assert_eq!(state["wind_speed"], json!(36.));
+        assert_eq!(state["skies"], json!("partly cloudy"));
+        assert_eq!(state["track_wetness"], json!("dry"));
+        assert_eq!(
+            state["attributes"],
+            json!({ "track_skies": "Partly Cloudy" })
+        );
+    }
+
+    #[test]
+    fn should_only_discover_sensors_with_vars() {
+        let device = Device::new();
+        let var_headers: HashMap<String, VarInfo> = ["AirTemp", "Skies"]
+            .iter()
+            .map(|name| {
+                let info = VarInfo {
+                    name: name.to_string(),
+                    var_type: "Float".to_string(),
+                    units: String::new(),
+                    count: 1,
+                    description: String::new(),
+                };
+                (name.to_string(), info)
+            })
+            .collect();
+
+        let topics: Vec<String> = discovery_packet(&var_headers, &device)
+            .into_iter()
+            .map(|(topic, _)| topic)
+            .collect();
+        assert_eq!(topics.len(), 3);
+        assert!(topics.iter().any(|topic| topic.contains("temperature")));
+        assert!(topics.iter().any(|topic| topic.contains("skies")));
+        assert!(topics.iter().any(|topic| topic.contains("condition")));
+
+        assert!(discovery_packet(&HashMap::<String, VarInfo>::new(), &device).is_empty());
+    }
+}